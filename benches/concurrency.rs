@@ -0,0 +1,172 @@
+//! Compares how many concurrent sessions the threaded and event-loop data
+//! paths hold, and what that costs in threads and time.
+//!
+//! Run with `cargo bench --bench concurrency`. The number of concurrent
+//! connections defaults to 2000 and can be changed with
+//! `LB_BENCH_CONNECTIONS`; each connection needs four file descriptors, plus
+//! four more on Linux for the zero-copy pipes.
+
+use std::{
+    env, fs,
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use load_balancer::{run_load_balancer_with, IoModel, LoadBalancer, ProxyConfig};
+
+fn main() {
+    let connections: usize = env::var("LB_BENCH_CONNECTIONS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(2000);
+    let backend = start_echo_backend();
+
+    println!(
+        "{:<12} {:>12} {:>14} {:>16} {:>14}",
+        "model", "connections", "extra threads", "connect all (ms)", "echo all (ms)"
+    );
+    for model in [IoModel::Threaded, IoModel::EventLoop] {
+        let result = run(model, backend, connections);
+        println!(
+            "{:<12} {:>12} {:>14} {:>16} {:>14}",
+            format!("{:?}", model),
+            result.connections,
+            result.extra_threads,
+            result.connect_time.as_millis(),
+            result.echo_time.as_millis()
+        );
+    }
+}
+
+struct BenchResult {
+    connections: usize,
+    extra_threads: usize,
+    connect_time: Duration,
+    echo_time: Duration,
+}
+
+fn run(model: IoModel, backend: u16, connections: usize) -> BenchResult {
+    let port = free_port();
+    let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![format!(
+        "127.0.0.1:{}",
+        backend
+    )])));
+    let config = ProxyConfig {
+        io_model: model,
+        // Threaded sessions each keep a worker for their whole lifetime.
+        workers: match model {
+            IoModel::Threaded => connections,
+            IoModel::EventLoop => ProxyConfig::default().workers,
+        },
+        ..ProxyConfig::default()
+    };
+    thread::spawn(move || run_load_balancer_with(port, balancer, config));
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    thread::sleep(Duration::from_millis(100));
+    let baseline = thread_count();
+
+    let started = Instant::now();
+    let mut clients = Vec::with_capacity(connections);
+    for i in 0..connections {
+        let mut client = match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(client) => client,
+            Err(e) => {
+                eprintln!("{:?}: connection {} failed: {}", model, i, e);
+                break;
+            }
+        };
+        if echo(&mut client, i).is_err() {
+            eprintln!("{:?}: connection {} did not echo", model, i);
+            break;
+        }
+        clients.push(client);
+    }
+    let connect_time = started.elapsed();
+    let extra_threads = thread_count().saturating_sub(baseline);
+
+    let started = Instant::now();
+    for (i, client) in clients.iter_mut().enumerate() {
+        echo(client, i).expect("established connection stopped echoing");
+    }
+    let echo_time = started.elapsed();
+
+    let result = BenchResult {
+        connections: clients.len(),
+        extra_threads,
+        connect_time,
+        echo_time,
+    };
+    drop(clients);
+    // Let the sessions wind down before the next model is measured.
+    thread::sleep(Duration::from_millis(500));
+    result
+}
+
+fn echo(client: &mut TcpStream, i: usize) -> std::io::Result<()> {
+    let message = format!("ping {:08}\n", i);
+    client.write_all(message.as_bytes())?;
+    let mut reply = vec![0; message.len()];
+    client.read_exact(&mut reply)?;
+    assert_eq!(reply, message.as_bytes());
+    Ok(())
+}
+
+/// An echo server on a single polling thread, so that the backend's own
+/// thread count does not depend on the number of connections.
+fn start_echo_backend() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let mut streams: Vec<TcpStream> = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            let mut busy = false;
+            while let Ok((stream, _)) = listener.accept() {
+                stream.set_nonblocking(true).unwrap();
+                streams.push(stream);
+                busy = true;
+            }
+            streams.retain_mut(|stream| match stream.read(&mut buffer) {
+                Ok(0) => false,
+                Ok(n) => {
+                    busy = true;
+                    stream.set_nonblocking(false).unwrap();
+                    let written = stream.write_all(&buffer[..n]).is_ok();
+                    stream.set_nonblocking(true).unwrap();
+                    written
+                }
+                Err(e) => e.kind() == ErrorKind::WouldBlock,
+            });
+            if !busy {
+                thread::sleep(Duration::from_micros(200));
+            }
+        }
+    });
+    port
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn thread_count() -> usize {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Threads:"))
+                .and_then(|n| n.trim().parse().ok())
+        })
+        .unwrap_or(0)
+}
@@ -0,0 +1,96 @@
+//! Compares bulk transfer throughput of buffered copying and `splice(2)`
+//! on both data paths.
+//!
+//! Run with `cargo bench --bench throughput`. Each run uploads
+//! `LB_BENCH_MEGABYTES` (default 512) through the proxy to a backend that
+//! discards it. The byte counters show which path actually moved the data.
+
+use std::{
+    env,
+    io::{self, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use load_balancer::{byte_counters, run_load_balancer_with, IoModel, LoadBalancer, ProxyConfig};
+
+fn main() {
+    let megabytes: usize = env::var("LB_BENCH_MEGABYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(512);
+    let backend = start_sink_backend();
+
+    println!(
+        "{:<12} {:>10} {:>10} {:>14} {:>14}",
+        "model", "zero copy", "MiB/s", "spliced (MiB)", "copied (MiB)"
+    );
+    for model in [IoModel::Threaded, IoModel::EventLoop] {
+        for zero_copy in [false, true] {
+            let before = byte_counters();
+            let elapsed = run(model, zero_copy, backend, megabytes);
+            let after = byte_counters();
+            println!(
+                "{:<12} {:>10} {:>10.0} {:>14} {:>14}",
+                format!("{:?}", model),
+                zero_copy,
+                megabytes as f64 / elapsed.as_secs_f64(),
+                (after.spliced - before.spliced) >> 20,
+                (after.copied - before.copied) >> 20
+            );
+        }
+    }
+}
+
+fn run(model: IoModel, zero_copy: bool, backend: u16, megabytes: usize) -> Duration {
+    let port = free_port();
+    let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![format!(
+        "127.0.0.1:{}",
+        backend
+    )])));
+    let config = ProxyConfig {
+        io_model: model,
+        zero_copy,
+        ..ProxyConfig::default()
+    };
+    thread::spawn(move || run_load_balancer_with(port, balancer, config));
+    let mut client = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(client) => break client,
+            Err(_) => thread::sleep(Duration::from_millis(10)),
+        }
+    };
+
+    let chunk = vec![0x5a; 1 << 20];
+    let started = Instant::now();
+    for _ in 0..megabytes {
+        client.write_all(&chunk).unwrap();
+    }
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    // The backend closes once it has read everything, which reaches us as
+    // end of stream.
+    io::copy(&mut client, &mut io::sink()).unwrap();
+    started.elapsed()
+}
+
+/// Reads and discards everything sent on each connection.
+fn start_sink_backend() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            thread::spawn(move || io::copy(&mut stream, &mut io::sink()));
+        }
+    });
+    port
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
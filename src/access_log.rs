@@ -0,0 +1,413 @@
+//! One line per client connection, for auditing and traffic analysis.
+//!
+//! Lines are built from an [`AccessLogFormat`], a template in which
+//! `%{field}` is replaced by a field of the connection and `%%` stands for
+//! a single `%`. The default, [`DEFAULT_FORMAT`], reads like this:
+//!
+//! ```text
+//! 10.1.2.3:50412 [2026-01-02T03:04:05.678Z] web 10.0.0.1:8081 17ms 78 1043 backend_close - "- -" -
+//! ```
+//!
+//! | Field         | Value                                                   |
+//! |---------------|---------------------------------------------------------|
+//! | `conn`        | connection id, as in the application log                |
+//! | `client`      | client `ip:port`                                        |
+//! | `client_ip`   | client address                                          |
+//! | `client_port` | client port                                             |
+//! | `frontend`    | name of the frontend                                    |
+//! | `backend`     | backend the session went to                             |
+//! | `start`       | when the connection was accepted, in UTC                |
+//! | `duration_ms` | milliseconds from acceptance until the session ended    |
+//! | `bytes_in`    | bytes received from the client and sent to the backend  |
+//! | `bytes_out`   | bytes received from the backend and sent to the client  |
+//! | `reason`      | `client_close`, `backend_close`, `timeout` or `error`   |
+//! | `error`       | kind of error that ended the session                    |
+//! | `method`      | HTTP request method                                     |
+//! | `path`        | HTTP request target                                     |
+//! | `status`      | HTTP response status                                    |
+//!
+//! The HTTP fields describe the last request of a connection to a
+//! frontend in [HTTP mode](crate::http). Fields without a value, such as
+//! the backend of a client no backend could be connected for, or the HTTP
+//! fields of a plain TCP session, are written as `-`.
+//!
+//! The log is appended to a file. [`AccessLog::reopen`] switches to a new
+//! file at the same path, so that the old one can be rotated away; the
+//! binary does so on `SIGUSR1`.
+
+use std::{
+    fmt::{self, Write as _},
+    fs::{File, OpenOptions},
+    io::{self, Write as _},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::{http::Exchange, logging, warn, ProxyError, Side, Transferred};
+
+pub const DEFAULT_FORMAT: &str = "%{client} [%{start}] %{frontend} %{backend} \
+     %{duration_ms}ms %{bytes_in} %{bytes_out} %{reason} %{error} \
+     \"%{method} %{path}\" %{status}";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Conn,
+    Client,
+    ClientIp,
+    ClientPort,
+    Frontend,
+    Backend,
+    Start,
+    DurationMs,
+    BytesIn,
+    BytesOut,
+    Reason,
+    Error,
+    Method,
+    Path,
+    Status,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        Some(match name {
+            "conn" => Field::Conn,
+            "client" => Field::Client,
+            "client_ip" => Field::ClientIp,
+            "client_port" => Field::ClientPort,
+            "frontend" => Field::Frontend,
+            "backend" => Field::Backend,
+            "start" => Field::Start,
+            "duration_ms" => Field::DurationMs,
+            "bytes_in" => Field::BytesIn,
+            "bytes_out" => Field::BytesOut,
+            "reason" => Field::Reason,
+            "error" => Field::Error,
+            "method" => Field::Method,
+            "path" => Field::Path,
+            "status" => Field::Status,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Field(Field),
+}
+
+/// A parsed access log template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogFormat {
+    template: String,
+    segments: Vec<Segment>,
+}
+
+impl Default for AccessLogFormat {
+    fn default() -> Self {
+        DEFAULT_FORMAT.parse().unwrap()
+    }
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(at) = rest.find('%') {
+            literal.push_str(&rest[..at]);
+            rest = &rest[at + 1..];
+            if let Some(after) = rest.strip_prefix('%') {
+                literal.push('%');
+                rest = after;
+                continue;
+            }
+            let Some((name, after)) = rest.strip_prefix('{').and_then(|rest| rest.split_once('}'))
+            else {
+                return Err(format!(
+                    "`%` at offset {} must start `%{{field}}` or `%%`",
+                    template.len() - rest.len() - 1
+                ));
+            };
+            let field =
+                Field::from_name(name).ok_or_else(|| format!("unknown field `{}`", name))?;
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Field(field));
+            rest = after;
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(AccessLogFormat {
+            template: template.to_string(),
+            segments,
+        })
+    }
+}
+
+impl fmt::Display for AccessLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+/// How a session came to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Termination {
+    /// The client finished sending first.
+    ClientClose,
+    /// The backend finished sending first.
+    BackendClose,
+    Timeout,
+    Error,
+}
+
+impl Termination {
+    /// How a connection that failed with `error`, if it failed, ended.
+    /// `first_finished` is the side that finished sending first.
+    pub(crate) fn of(error: Option<&ProxyError>, first_finished: Option<Side>) -> Self {
+        match error {
+            None if first_finished == Some(Side::Backend) => Termination::BackendClose,
+            None => Termination::ClientClose,
+            Some(ProxyError::Timeout { .. }) => Termination::Timeout,
+            Some(_) => Termination::Error,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Termination::ClientClose => "client_close",
+            Termination::BackendClose => "backend_close",
+            Termination::Timeout => "timeout",
+            Termination::Error => "error",
+        }
+    }
+}
+
+/// What is known about a connection once it is over.
+#[derive(Debug, Clone)]
+pub(crate) struct AccessRecord<'a> {
+    pub(crate) conn: u64,
+    pub(crate) frontend: &'a str,
+    pub(crate) client: Option<SocketAddr>,
+    pub(crate) backend: Option<&'a str>,
+    pub(crate) start: SystemTime,
+    pub(crate) duration: Duration,
+    pub(crate) transferred: Transferred,
+    pub(crate) termination: Termination,
+    /// Kind of the error that ended the connection, if any.
+    pub(crate) error: Option<String>,
+    /// The last request of an HTTP session.
+    pub(crate) request: Option<Exchange>,
+}
+
+impl AccessLogFormat {
+    fn render(&self, record: &AccessRecord<'_>) -> String {
+        let mut line = String::new();
+        let request = record.request.as_ref();
+        for segment in &self.segments {
+            let field = match segment {
+                Segment::Literal(text) => {
+                    line.push_str(text);
+                    continue;
+                }
+                Segment::Field(field) => field,
+            };
+            let _ = match field {
+                Field::Conn => write!(line, "{}", record.conn),
+                Field::Client => write!(line, "{}", Or(record.client)),
+                Field::ClientIp => write!(line, "{}", Or(record.client.map(|a| a.ip()))),
+                Field::ClientPort => write!(line, "{}", Or(record.client.map(|a| a.port()))),
+                Field::Frontend => write!(line, "{}", record.frontend),
+                Field::Backend => write!(line, "{}", Or(record.backend)),
+                Field::Start => write!(line, "{}", logging::timestamp(record.start)),
+                Field::DurationMs => write!(line, "{}", record.duration.as_millis()),
+                Field::BytesIn => write!(line, "{}", record.transferred.client_to_backend),
+                Field::BytesOut => write!(line, "{}", record.transferred.backend_to_client),
+                Field::Reason => write!(line, "{}", record.termination.name()),
+                Field::Error => write!(line, "{}", Or(record.error.as_deref())),
+                Field::Method => write!(
+                    line,
+                    "{}",
+                    Or(request.map(|r| &r.method).filter(|m| !m.is_empty()))
+                ),
+                Field::Path => write!(
+                    line,
+                    "{}",
+                    Or(request.map(|r| &r.target).filter(|t| !t.is_empty()))
+                ),
+                Field::Status => write!(line, "{}", Or(request.and_then(|r| r.status))),
+            };
+        }
+        line.push('\n');
+        line
+    }
+}
+
+/// Displays the value, or `-` if there is none.
+struct Or<T>(Option<T>);
+
+impl<T: fmt::Display> fmt::Display for Or<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(value) => value.fmt(f),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+/// The `[access_log]` section of a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogConfig {
+    pub path: PathBuf,
+    pub format: AccessLogFormat,
+}
+
+impl AccessLogConfig {
+    pub fn open(&self) -> io::Result<AccessLog> {
+        AccessLog::open(&self.path, self.format.clone())
+    }
+}
+
+/// Where access log lines go and what they look like. Shared by every
+/// frontend that logs to the same file.
+#[derive(Debug)]
+pub struct AccessLog {
+    path: PathBuf,
+    format: AccessLogFormat,
+    file: Mutex<File>,
+}
+
+impl AccessLog {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: impl Into<PathBuf>, format: AccessLogFormat) -> io::Result<Self> {
+        let path = path.into();
+        let file = Mutex::new(open_append(&path)?);
+        Ok(AccessLog { path, format, file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Closes the file and opens the path again, which creates a new file
+    /// if the old one has been moved away. On error the old file is kept.
+    pub fn reopen(&self) -> io::Result<()> {
+        let file = open_append(&self.path)?;
+        *self.file.lock().unwrap() = file;
+        Ok(())
+    }
+
+    pub(crate) fn write(&self, record: &AccessRecord<'_>) {
+        let line = self.format.render(record);
+        // One write per line keeps lines from different sessions whole.
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!(
+                "Error writing access log";
+                path = self.path.display(),
+                error = e,
+            );
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, time::UNIX_EPOCH};
+
+    fn record() -> AccessRecord<'static> {
+        AccessRecord {
+            conn: 17,
+            frontend: "web",
+            client: Some("10.1.2.3:50412".parse().unwrap()),
+            backend: Some("10.0.0.1:8081"),
+            start: UNIX_EPOCH + Duration::from_millis(1_767_323_045_678),
+            duration: Duration::from_micros(17_900),
+            transferred: Transferred {
+                client_to_backend: 78,
+                backend_to_client: 1043,
+            },
+            termination: Termination::BackendClose,
+            error: None,
+            request: None,
+        }
+    }
+
+    #[test]
+    fn test_default_format() {
+        assert_eq!(
+            AccessLogFormat::default().render(&record()),
+            "10.1.2.3:50412 [2026-01-02T03:04:05.678Z] web 10.0.0.1:8081 17ms 78 1043 \
+             backend_close - \"- -\" -\n"
+        );
+    }
+
+    #[test]
+    fn test_custom_format() {
+        let format: AccessLogFormat =
+            "%{conn} %{client_ip}:%{client_port} 100%% %{reason}/%{error}"
+                .parse()
+                .unwrap();
+        let record = AccessRecord {
+            backend: None,
+            termination: Termination::Error,
+            error: Some("connection_refused".to_string()),
+            ..record()
+        };
+        assert_eq!(
+            format.render(&record),
+            "17 10.1.2.3:50412 100% error/connection_refused\n"
+        );
+
+        for (template, message) in [
+            ("%{nope}", "unknown field `nope`"),
+            ("50% off", "`%` at offset 2"),
+            ("%{client", "`%` at offset 0"),
+        ] {
+            let err = template.parse::<AccessLogFormat>().unwrap_err();
+            assert!(err.contains(message), "{}: {}", template, err);
+        }
+    }
+
+    #[test]
+    fn test_reopen_starts_a_new_file() {
+        let dir = std::env::temp_dir().join(format!("access-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let format: AccessLogFormat = "%{conn}".parse().unwrap();
+        let log = AccessLog::open(&path, format).unwrap();
+
+        log.write(&record());
+        fs::rename(&path, dir.join("access.log.1")).unwrap();
+        log.write(&AccessRecord {
+            conn: 18,
+            ..record()
+        });
+        log.reopen().unwrap();
+        log.write(&AccessRecord {
+            conn: 19,
+            ..record()
+        });
+
+        assert_eq!(
+            fs::read_to_string(dir.join("access.log.1")).unwrap(),
+            "17\n18\n"
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "19\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,860 @@
+//! Runtime administration over HTTP.
+//!
+//! The admin API is served on its own listener, apart from the frontends,
+//! and speaks JSON. It works on the live load balancers of a [`Pools`], so
+//! changes take effect for the next connection:
+//!
+//! | Request                                  | Effect                        |
+//! |------------------------------------------|-------------------------------|
+//! | `GET /pools`                             | every pool and its backends   |
+//! | `GET /pools/{pool}`                      | one pool                      |
+//! | `POST /pools/{pool}/backends`            | add a backend                 |
+//! | `GET /pools/{pool}/backends/{address}`   | one backend                   |
+//! | `PATCH /pools/{pool}/backends/{address}` | change weight, tags or state  |
+//! | `DELETE /pools/{pool}/backends/{address}`| remove a backend              |
+//! | `POST /reload`                           | re-read the configuration     |
+//! | `GET /metrics`                           | Prometheus metrics            |
+//! | `GET /log`                               | log filter and format         |
+//! | `PATCH /log`                             | change the log filter, format |
+//!
+//! Backends are described as `{"address": "10.0.0.1:80", "weight": 2,
+//! "tags": ["zone-a"], "state": "enabled"}`, plus read-only health and
+//! connection fields in responses. Weights go up to
+//! [`Backend::MAX_WEIGHT`](crate::Backend::MAX_WEIGHT). Setting `state` to
+//! `draining` or `disabled` takes a backend out of rotation without
+//! touching its sessions. A drain may also be given a `drain_timeout`, such as `"30s"`,
+//! after which the sessions still open are closed; deploy tooling can
+//! poll the backend until `drain.remaining_connections` reaches zero
+//! before restarting it. Errors come back as `{"error": "..."}`.
+//!
+//! `GET /metrics` is the exception to JSON: it answers in the Prometheus
+//! text format, see [`metrics`](crate::metrics).
+//!
+//! The log settings look like `{"level": "info,proxy=debug", "format":
+//! "logfmt"}`, the same as the `[log]` section of the configuration file;
+//! see [`logging`](crate::logging) for what levels mean.
+//!
+//! Changes made here are not written back to the configuration file, and
+//! a reload resets the backends of each pool to what the file lists.
+
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    config::{self, Pools, ReloadSummary},
+    error, info,
+    logging::{self, Filter, Format},
+    metrics, Backend, BackendState, LoadBalancer, SharedLoadBalancer,
+};
+
+pub(crate) mod json;
+
+use json::Json;
+
+/// Limit for reading a request from an admin client.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+const MAX_REQUEST_BODY: usize = 64 * 1024;
+
+/// The admin API running on a background thread.
+pub struct AdminServer {
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AdminServer {
+    /// Serves the admin API for `pools` on `address`. `POST /reload`
+    /// re-reads `config_path`, and is refused without one.
+    pub fn start(
+        address: SocketAddr,
+        pools: Arc<Mutex<Pools>>,
+        config_path: Option<PathBuf>,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        info!("Admin API listening"; address = address);
+
+        let admin = Arc::new(Admin { pools, config_path });
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if thread_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let admin = Arc::clone(&admin);
+                        thread::spawn(move || {
+                            if let Err(e) = admin.serve(stream) {
+                                error!("Admin API error"; error = e);
+                            }
+                        });
+                    }
+                    Err(e) => error!("Admin API error accepting connection"; error = e),
+                }
+            }
+        });
+
+        Ok(AdminServer {
+            address,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Stops accepting requests and closes the listening socket.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it notices the stop flag.
+        let _ = TcpStream::connect(self.address);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for AdminServer {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.shutdown();
+        }
+    }
+}
+
+struct Admin {
+    pools: Arc<Mutex<Pools>>,
+    config_path: Option<PathBuf>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, body: Json) -> Response {
+        Response {
+            status,
+            content_type: "application/json",
+            body: format!("{}\n", body),
+        }
+    }
+
+    fn ok(body: Json) -> Response {
+        Response::json(200, body)
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Response {
+        Response::json(
+            status,
+            Json::object([("error", Json::String(message.into()))]),
+        )
+    }
+}
+
+impl Admin {
+    fn serve(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let response = match read_request(&mut stream) {
+            Ok(request) => self.handle(&request),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => Response::error(400, e.to_string()),
+            Err(e) => return Err(e),
+        };
+        write_response(&mut stream, &response)
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        let segments: Vec<String> = request
+            .path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .map(percent_decode)
+            .collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        let method = request.method.as_str();
+
+        match segments.as_slice() {
+            ["pools"] => match method {
+                "GET" => self.list_pools(),
+                _ => not_allowed(),
+            },
+            ["pools", pool] => match method {
+                "GET" => self.with_pool(pool, |name, lb| Response::ok(pool_json(name, lb))),
+                _ => not_allowed(),
+            },
+            ["pools", pool, "backends"] => match method {
+                "GET" => self.with_pool(pool, |_, lb| {
+                    Response::ok(Json::Array(
+                        lb.backends().iter().map(|b| backend_json(b)).collect(),
+                    ))
+                }),
+                "POST" => self.add_backend(pool, &request.body),
+                _ => not_allowed(),
+            },
+            ["pools", pool, "backends", address] => match method {
+                "GET" => self.with_pool(pool, |_, lb| match lb.backend(address) {
+                    Some(backend) => Response::ok(backend_json(backend)),
+                    None => unknown_backend(address),
+                }),
+                "PATCH" => self.update_backend(pool, address, &request.body),
+                "DELETE" => self.with_pool(pool, |_, lb| match lb.remove_backend(address) {
+                    Some(backend) => {
+                        info!("Admin API removed backend"; pool = pool, backend = address);
+                        Response::ok(backend_json(&backend))
+                    }
+                    None => unknown_backend(address),
+                }),
+                _ => not_allowed(),
+            },
+            ["metrics"] => match method {
+                "GET" => Response {
+                    status: 200,
+                    content_type: "text/plain; version=0.0.4",
+                    body: metrics::render(),
+                },
+                _ => not_allowed(),
+            },
+            ["log"] => match method {
+                "GET" => Response::ok(log_json()),
+                "PATCH" => update_log(&request.body),
+                _ => not_allowed(),
+            },
+            ["reload"] => match method {
+                "POST" => self.reload(),
+                _ => not_allowed(),
+            },
+            _ => Response::error(404, format!("no such resource `{}`", request.path)),
+        }
+    }
+
+    fn load_balancer(&self, pool: &str) -> Option<SharedLoadBalancer> {
+        self.pools.lock().unwrap().load_balancer(pool)
+    }
+
+    fn with_pool(
+        &self,
+        pool: &str,
+        f: impl FnOnce(&str, &mut LoadBalancer) -> Response,
+    ) -> Response {
+        match self.load_balancer(pool) {
+            Some(lb) => f(pool, &mut lb.lock().unwrap()),
+            None => Response::error(404, format!("unknown pool `{}`", pool)),
+        }
+    }
+
+    fn list_pools(&self) -> Response {
+        let pools = self.pools.lock().unwrap();
+        let pools = pools
+            .names()
+            .map(|name| {
+                let lb = pools.load_balancer(name).unwrap();
+                let lb = lb.lock().unwrap();
+                pool_json(name, &lb)
+            })
+            .collect();
+        Response::ok(Json::object([("pools", Json::Array(pools))]))
+    }
+
+    fn add_backend(&self, pool: &str, body: &[u8]) -> Response {
+        let update = match BackendUpdate::parse(body, true) {
+            Ok(update) => update,
+            Err(message) => return Response::error(400, message),
+        };
+        let address = update.address.clone().unwrap();
+        self.with_pool(pool, |_, lb| {
+            let backend = Backend::with_weight(address.clone(), update.weight.unwrap_or(1))
+                .with_tags(update.tags.unwrap_or_default());
+            if let Some(state) = update.state {
+                backend.set_state(state);
+            }
+            if !lb.add_backend(backend) {
+                return Response::error(409, format!("backend `{}` already exists", address));
+            }
+            info!("Admin API added backend"; pool = pool, backend = address);
+            Response::json(201, backend_json(lb.backend(&address).unwrap()))
+        })
+    }
+
+    fn update_backend(&self, pool: &str, address: &str, body: &[u8]) -> Response {
+        let update = match BackendUpdate::parse(body, false) {
+            Ok(update) => update,
+            Err(message) => return Response::error(400, message),
+        };
+        self.with_pool(pool, |_, lb| {
+            let Some(backend) = lb.backend(address).cloned() else {
+                return unknown_backend(address);
+            };
+            if let Some(weight) = update.weight {
+                lb.set_weight(address, weight);
+            }
+            if let Some(tags) = update.tags {
+                backend.set_tags(tags);
+            }
+            if let Some(state) = update.state {
+                match state {
+                    BackendState::Draining => backend.drain(update.drain_timeout),
+                    _ => backend.set_state(state),
+                }
+                info!(
+                    "Admin API set backend state";
+                    pool = pool,
+                    backend = address,
+                    state = state.name(),
+                );
+            }
+            Response::ok(backend_json(&backend))
+        })
+    }
+
+    fn reload(&self) -> Response {
+        let Some(path) = &self.config_path else {
+            return Response::error(409, "there is no configuration file to reload");
+        };
+        match self.pools.lock().unwrap().reload_from(path) {
+            Ok(summary) => {
+                info!("Admin API reloaded configuration"; summary = format!("{:?}", summary));
+                Response::ok(summary_json(&summary))
+            }
+            Err(e) => Response::error(400, e.to_string()),
+        }
+    }
+}
+
+/// Fields of a backend that requests may set.
+struct BackendUpdate {
+    address: Option<String>,
+    weight: Option<u32>,
+    tags: Option<Vec<String>>,
+    state: Option<BackendState>,
+    drain_timeout: Option<Duration>,
+}
+
+impl BackendUpdate {
+    /// Decodes a request body. The address may only be given, and then
+    /// must be, when adding a backend.
+    fn parse(body: &[u8], adding: bool) -> Result<BackendUpdate, String> {
+        let fields = parse_object(body)?;
+
+        let mut update = BackendUpdate {
+            address: None,
+            weight: None,
+            tags: None,
+            state: None,
+            drain_timeout: None,
+        };
+        for (key, value) in &fields {
+            let mismatch = |expected: &str| {
+                format!(
+                    "`{}`: expected {}, found {}",
+                    key,
+                    expected,
+                    value.type_name()
+                )
+            };
+            match (key.as_str(), value) {
+                ("address", Json::String(address)) if adding => {
+                    if !config::is_host_port(address) {
+                        return Err(format!("`{}` is not a host:port address", address));
+                    }
+                    update.address = Some(address.clone());
+                }
+                ("address", Json::String(_)) => {
+                    return Err("the address of a backend cannot be changed".to_string())
+                }
+                ("address", _) => return Err(mismatch("a string")),
+                ("weight", Json::Number(weight)) => {
+                    let weight = u32::try_from(*weight)
+                        .ok()
+                        .filter(|&weight| weight <= Backend::MAX_WEIGHT)
+                        .ok_or_else(|| {
+                            format!(
+                                "`weight`: {} is out of range; expected 0 to {}",
+                                weight,
+                                Backend::MAX_WEIGHT
+                            )
+                        })?;
+                    update.weight = Some(weight);
+                }
+                ("weight", _) => return Err(mismatch("a number")),
+                ("tags", Json::Array(items)) => {
+                    let tags = items
+                        .iter()
+                        .map(|item| match item {
+                            Json::String(tag) => Ok(tag.clone()),
+                            _ => Err(mismatch("an array of strings")),
+                        })
+                        .collect::<Result<_, _>>()?;
+                    update.tags = Some(tags);
+                }
+                ("tags", _) => return Err(mismatch("an array of strings")),
+                ("state", Json::String(state)) => {
+                    update.state = Some(BackendState::from_name(state).ok_or_else(|| {
+                        format!(
+                            "unknown state `{}`; expected `enabled`, `draining` or `disabled`",
+                            state
+                        )
+                    })?);
+                }
+                ("state", _) => return Err(mismatch("a string")),
+                ("drain_timeout", Json::String(timeout)) => {
+                    update.drain_timeout =
+                        Some(config::parse_duration(timeout).ok_or_else(|| {
+                            format!("`drain_timeout`: `{}` is not a duration", timeout)
+                        })?);
+                }
+                ("drain_timeout", _) => return Err(mismatch("a string")),
+                (other, _) => return Err(format!("unknown field `{}`", other)),
+            }
+        }
+        if adding && update.address.is_none() {
+            return Err("`address` is required".to_string());
+        }
+        if update.drain_timeout.is_some()
+            && (adding || update.state != Some(BackendState::Draining))
+        {
+            return Err("`drain_timeout` only applies when draining a backend".to_string());
+        }
+        Ok(update)
+    }
+}
+
+/// Parses a request body that has to be a JSON object into its fields.
+fn parse_object(body: &[u8]) -> Result<Vec<(String, Json)>, String> {
+    let body = std::str::from_utf8(body).map_err(|_| "body is not UTF-8".to_string())?;
+    match json::parse(body).map_err(|e| format!("invalid JSON: {}", e))? {
+        Json::Object(fields) => Ok(fields),
+        body => Err(format!("expected an object, found {}", body.type_name())),
+    }
+}
+
+fn log_json() -> Json {
+    Json::object([
+        ("level", Json::String(logging::filter().to_string())),
+        ("format", Json::from(logging::format().name())),
+    ])
+}
+
+/// Applies `{"level": ..., "format": ...}`, either of which may be left
+/// out. Nothing changes unless both are valid.
+fn update_log(body: &[u8]) -> Response {
+    let fields = match parse_object(body) {
+        Ok(fields) => fields,
+        Err(message) => return Response::error(400, message),
+    };
+    let (mut filter, mut format) = (None, None);
+    for (key, value) in fields {
+        let Json::String(value) = value else {
+            return Response::error(
+                400,
+                format!("`{}`: expected a string, found {}", key, value.type_name()),
+            );
+        };
+        match key.as_str() {
+            "level" => match value.parse::<Filter>() {
+                Ok(parsed) => filter = Some(parsed),
+                Err(message) => return Response::error(400, message),
+            },
+            "format" => match Format::from_name(&value) {
+                Some(parsed) => format = Some(parsed),
+                None => {
+                    return Response::error(
+                        400,
+                        format!("unknown format `{}`; expected logfmt or json", value),
+                    )
+                }
+            },
+            _ => return Response::error(400, format!("unknown field `{}`", key)),
+        }
+    }
+    if let Some(filter) = filter {
+        logging::set_filter(filter);
+    }
+    if let Some(format) = format {
+        logging::set_format(format);
+    }
+    info!("Admin API changed log settings"; settings = log_json());
+    Response::ok(log_json())
+}
+
+fn not_allowed() -> Response {
+    Response::error(405, "method not allowed")
+}
+
+fn unknown_backend(address: &str) -> Response {
+    Response::error(404, format!("unknown backend `{}`", address))
+}
+
+fn pool_json(name: &str, lb: &LoadBalancer) -> Json {
+    Json::object([
+        ("name", name.into()),
+        ("strategy", lb.strategy_name().into()),
+        (
+            "backends",
+            Json::Array(lb.backends().iter().map(|b| backend_json(b)).collect()),
+        ),
+    ])
+}
+
+fn backend_json(backend: &Backend) -> Json {
+    Json::object([
+        ("address", backend.address().into()),
+        ("weight", backend.weight().into()),
+        ("tags", Json::strings(&backend.tags())),
+        ("state", backend.state().name().into()),
+        ("healthy", backend.is_healthy().into()),
+        ("ejected", backend.is_ejected().into()),
+        ("available", backend.is_available().into()),
+        ("active_connections", backend.active_connections().into()),
+        (
+            "drain",
+            match backend.drain_progress() {
+                Some(progress) => Json::object([
+                    (
+                        "remaining_connections",
+                        progress.remaining_connections.into(),
+                    ),
+                    (
+                        "time_left_ms",
+                        match progress.time_left {
+                            Some(left) => Json::Number(left.as_millis() as i64),
+                            None => Json::Null,
+                        },
+                    ),
+                    ("complete", progress.is_complete().into()),
+                ]),
+                None => Json::Null,
+            },
+        ),
+    ])
+}
+
+fn summary_json(summary: &ReloadSummary) -> Json {
+    Json::object([
+        ("added_pools", Json::strings(&summary.added_pools)),
+        ("removed_pools", Json::strings(&summary.removed_pools)),
+        (
+            "backends",
+            Json::Object(
+                summary
+                    .backends
+                    .iter()
+                    .map(|(pool, changes)| {
+                        let changes = Json::object([
+                            ("added", Json::strings(&changes.added)),
+                            ("removed", Json::strings(&changes.removed)),
+                            ("updated", Json::strings(&changes.updated)),
+                        ]);
+                        (pool.clone(), changes)
+                    })
+                    .collect(),
+            ),
+        ),
+        ("needs_restart", Json::strings(&summary.needs_restart)),
+    ])
+}
+
+fn read_request(stream: &mut TcpStream) -> io::Result<Request> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    let mut data = Vec::new();
+    let mut buffer = [0; 4096];
+    let head_end = loop {
+        if let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > MAX_REQUEST_HEAD {
+            return Err(invalid("request head is too large"));
+        }
+        match stream.read(&mut buffer)? {
+            0 => return Err(invalid("incomplete request")),
+            n => data.extend_from_slice(&buffer[..n]),
+        }
+    };
+
+    let head =
+        std::str::from_utf8(&data[..head_end]).map_err(|_| invalid("request head is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(path), Some(_version)) = (
+        request_line.next(),
+        request_line.next(),
+        request_line.next(),
+    ) else {
+        return Err(invalid("malformed request line"));
+    };
+    let method = method.to_string();
+    let path = path.split('?').next().unwrap_or_default().to_string();
+    let mut content_length = 0;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(invalid("malformed header"));
+        };
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .trim()
+                .parse()
+                .map_err(|_| invalid("invalid Content-Length"))?;
+        }
+    }
+    if content_length > MAX_REQUEST_BODY {
+        return Err(invalid("request body is too large"));
+    }
+
+    let mut body = data.split_off(head_end + 4);
+    body.truncate(content_length);
+    let mut rest = vec![0; content_length - body.len()];
+    stream.read_exact(&mut rest)?;
+    body.extend_from_slice(&rest);
+
+    Ok(Request { method, path, body })
+}
+
+fn write_response(stream: &mut TcpStream, response: &Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        reason,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+/// Decodes `%XX` escapes, so that addresses may be given as `host%3Aport`.
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn request(address: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Json) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: admin\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, json::parse(body).unwrap())
+    }
+
+    fn start() -> (AdminServer, SharedLoadBalancer) {
+        let config = Config::parse(
+            "[[frontend]]\nport = 8080\npool = \"web\"\n\n\
+             [[pool.web.backend]]\naddress = \"10.0.0.1:80\"\n",
+        )
+        .unwrap();
+        let pools = Pools::start(&config);
+        let load_balancer = pools.load_balancer("web").unwrap();
+        let server = AdminServer::start(
+            "127.0.0.1:0".parse().unwrap(),
+            Arc::new(Mutex::new(pools)),
+            None,
+        )
+        .unwrap();
+        (server, load_balancer)
+    }
+
+    #[test]
+    fn test_admin_api_manages_backends() {
+        let (server, load_balancer) = start();
+        let address = server.local_addr();
+
+        let (status, body) = request(address, "GET", "/pools", "");
+        assert_eq!(status, 200);
+        let pools = body.get("pools").unwrap();
+        assert_eq!(
+            pools.to_string(),
+            r#"[{"name":"web","strategy":"round_robin","backends":[{"address":"10.0.0.1:80","weight":1,"tags":[],"state":"enabled","healthy":true,"ejected":false,"available":true,"active_connections":0,"drain":null}]}]"#
+        );
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("\nlb_backend_weight{pool=\"web\",backend=\"10.0.0.1:80\"} 1\n"));
+
+        let (status, body) = request(
+            address,
+            "POST",
+            "/pools/web/backends",
+            r#"{"address": "10.0.0.2:80", "weight": 3, "tags": ["canary"]}"#,
+        );
+        assert_eq!(status, 201);
+        assert_eq!(body.get("weight"), Some(&Json::Number(3)));
+        let (status, _) = request(
+            address,
+            "POST",
+            "/pools/web/backends",
+            r#"{"address": "10.0.0.2:80"}"#,
+        );
+        assert_eq!(status, 409);
+
+        let (status, body) = request(
+            address,
+            "PATCH",
+            "/pools/web/backends/10.0.0.1%3A80",
+            r#"{"state": "draining", "drain_timeout": "1m", "weight": 5}"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body.get("available"), Some(&Json::Bool(false)));
+        let drain = body.get("drain").unwrap();
+        assert_eq!(drain.get("complete"), Some(&Json::Bool(true)));
+        assert!(matches!(drain.get("time_left_ms"), Some(Json::Number(ms)) if *ms > 50_000));
+        {
+            let mut lb = load_balancer.lock().unwrap();
+            let drained = lb.backend("10.0.0.1:80").unwrap();
+            assert_eq!(drained.state(), BackendState::Draining);
+            assert_eq!(drained.weight(), 5);
+            for _ in 0..4 {
+                assert_eq!(lb.next_backend(), Some("10.0.0.2:80"));
+            }
+        }
+
+        let (status, _) = request(address, "DELETE", "/pools/web/backends/10.0.0.1:80", "");
+        assert_eq!(status, 200);
+        assert!(load_balancer
+            .lock()
+            .unwrap()
+            .backend("10.0.0.1:80")
+            .is_none());
+        server.stop();
+    }
+
+    #[test]
+    fn test_admin_api_rejects_bad_requests() {
+        let (server, _) = start();
+        let address = server.local_addr();
+        let cases = [
+            ("GET", "/pools/api", "", 404, "unknown pool `api`"),
+            (
+                "GET",
+                "/pools/web/backends/a:1",
+                "",
+                404,
+                "unknown backend `a:1`",
+            ),
+            ("PUT", "/pools", "", 405, "method not allowed"),
+            (
+                "PATCH",
+                "/log",
+                r#"{"level": "info", "format": "xml"}"#,
+                400,
+                "unknown format `xml`",
+            ),
+            (
+                "POST",
+                "/pools/web/backends",
+                "{}",
+                400,
+                "`address` is required",
+            ),
+            (
+                "PATCH",
+                "/pools/web/backends/10.0.0.1:80",
+                r#"{"state": "off"}"#,
+                400,
+                "unknown state `off`",
+            ),
+            (
+                "PATCH",
+                "/pools/web/backends/10.0.0.1:80",
+                r#"{"weight": -1}"#,
+                400,
+                "out of range",
+            ),
+            (
+                "POST",
+                "/pools/web/backends",
+                r#"{"address": "10.0.0.3:80", "weight": 10001}"#,
+                400,
+                "expected 0 to 10000",
+            ),
+            ("POST", "/pools/web/backends", "{", 400, "invalid JSON"),
+            (
+                "PATCH",
+                "/pools/web/backends/10.0.0.1:80",
+                r#"{"drain_timeout": "5s"}"#,
+                400,
+                "only applies when draining",
+            ),
+            ("POST", "/reload", "", 409, "no configuration file"),
+        ];
+        for (method, path, body, status, message) in cases {
+            let (actual, response) = request(address, method, path, body);
+            assert_eq!(actual, status, "{} {}: {}", method, path, response);
+            let Some(Json::String(error)) = response.get("error") else {
+                panic!("{} {}: no error in {}", method, path, response);
+            };
+            assert!(error.contains(message), "{} {}: {}", method, path, error);
+        }
+    }
+}
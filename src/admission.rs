@@ -0,0 +1,272 @@
+//! Admission control for accepted connections.
+//!
+//! Every client connection holds a [`Permit`] from acceptance until its
+//! session closes, which caps the number of connections handled at once.
+//! Connections accepted while the cap is reached either wait for a permit
+//! in a bounded queue or are rejected straight away, depending on the
+//! [`OverloadPolicy`]. Rejected connections are closed, after a
+//! `503 Service Unavailable` response in HTTP mode.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// What happens to connections accepted while the connection limit is
+/// reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverloadPolicy {
+    /// Close them immediately.
+    Reject,
+    /// Hold up to `capacity` of them until a connection closes, and close
+    /// those still waiting after `timeout`.
+    Queue { capacity: usize, timeout: Duration },
+}
+
+impl OverloadPolicy {
+    pub(crate) const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+    pub(crate) const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+}
+
+impl Default for OverloadPolicy {
+    fn default() -> Self {
+        OverloadPolicy::Queue {
+            capacity: Self::DEFAULT_QUEUE_CAPACITY,
+            timeout: Self::DEFAULT_QUEUE_TIMEOUT,
+        }
+    }
+}
+
+/// Live counters of the connections seen by a load balancer.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    accepted: AtomicU64,
+    rejected: AtomicU64,
+    active: AtomicUsize,
+    queued: AtomicUsize,
+}
+
+impl ConnectionStats {
+    /// Connections accepted from clients, including rejected ones.
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    /// Connections closed because of the connection limit, either at once
+    /// or after timing out in the queue.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Connections currently holding a permit.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Connections currently waiting for a permit.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) enum Admit {
+    Now(Permit),
+    /// Call [`Admission::wait`] for a permit.
+    Queued,
+    Rejected,
+}
+
+pub(crate) struct Admission {
+    max_connections: usize,
+    policy: OverloadPolicy,
+    stats: Arc<ConnectionStats>,
+    // Permits handed out; mirrored in `stats.active`.
+    active: Mutex<usize>,
+    released: Condvar,
+    closed: AtomicBool,
+}
+
+impl Admission {
+    pub(crate) fn new(
+        max_connections: usize,
+        policy: OverloadPolicy,
+        stats: Arc<ConnectionStats>,
+    ) -> Self {
+        Admission {
+            max_connections,
+            policy,
+            stats,
+            active: Mutex::new(0),
+            released: Condvar::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Decides what to do with a newly accepted connection.
+    pub(crate) fn admit(self: &Arc<Self>) -> Admit {
+        self.stats.accepted.fetch_add(1, Ordering::Relaxed);
+        let mut active = self.active.lock().unwrap();
+        if *active < self.max_connections {
+            return Admit::Now(self.grant(&mut active));
+        }
+        match self.policy {
+            OverloadPolicy::Queue { capacity, .. } if self.stats.queued() < capacity => {
+                self.stats.queued.fetch_add(1, Ordering::Relaxed);
+                Admit::Queued
+            }
+            _ => {
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                Admit::Rejected
+            }
+        }
+    }
+
+    /// Waits for a permit for a connection that was queued at `accepted`,
+    /// giving up once the queue timeout has passed since then.
+    pub(crate) fn wait(self: &Arc<Self>, accepted: Instant) -> Option<Permit> {
+        let timeout = match self.policy {
+            OverloadPolicy::Queue { timeout, .. } => timeout,
+            OverloadPolicy::Reject => Duration::ZERO,
+        };
+        let deadline = accepted + timeout;
+        let mut active = self.active.lock().unwrap();
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            if *active < self.max_connections {
+                self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                return Some(self.grant(&mut active));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+            active = self.released.wait_timeout(active, remaining).unwrap().0;
+        }
+    }
+
+    /// Turns away connections still waiting in the queue, for shutting
+    /// down.
+    pub(crate) fn close(&self) {
+        // Taking the lock makes sure no waiter misses the notification
+        // between checking the flag and waiting.
+        let _active = self.active.lock().unwrap();
+        self.closed.store(true, Ordering::SeqCst);
+        self.released.notify_all();
+    }
+
+    /// Waits until every permit has been released or `timeout` passes,
+    /// returning whether they all were. Only used after [`Self::close`],
+    /// when releases wake every waiter.
+    pub(crate) fn wait_idle(&self, timeout: Duration) -> bool {
+        let active = self.active.lock().unwrap();
+        let (active, _) = self
+            .released
+            .wait_timeout_while(active, timeout, |active| *active > 0)
+            .unwrap();
+        *active == 0
+    }
+
+    fn grant(self: &Arc<Self>, active: &mut usize) -> Permit {
+        *active += 1;
+        self.stats.active.fetch_add(1, Ordering::Relaxed);
+        Permit(Arc::clone(self))
+    }
+}
+
+/// A slot under the connection limit, released when dropped.
+pub(crate) struct Permit(Arc<Admission>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let admission = &self.0;
+        *admission.active.lock().unwrap() -= 1;
+        admission.stats.active.fetch_sub(1, Ordering::Relaxed);
+        // Once closed, nobody waits for a permit, but someone may be
+        // waiting for all of them.
+        if admission.closed.load(Ordering::SeqCst) {
+            admission.released.notify_all();
+        } else {
+            admission.released.notify_one();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn admission(max: usize, policy: OverloadPolicy) -> Arc<Admission> {
+        Arc::new(Admission::new(
+            max,
+            policy,
+            Arc::new(ConnectionStats::default()),
+        ))
+    }
+
+    #[test]
+    fn test_reject_policy_rejects_over_limit() {
+        let admission = admission(1, OverloadPolicy::Reject);
+        let permit = match admission.admit() {
+            Admit::Now(permit) => permit,
+            _ => panic!("first connection should be admitted"),
+        };
+        assert!(matches!(admission.admit(), Admit::Rejected));
+        assert_eq!(admission.stats.rejected(), 1);
+        assert_eq!(admission.stats.active(), 1);
+
+        drop(permit);
+        assert!(matches!(admission.admit(), Admit::Now(_)));
+        assert_eq!(admission.stats.accepted(), 3);
+        assert_eq!(admission.stats.active(), 0);
+    }
+
+    #[test]
+    fn test_queue_policy_waits_for_permit_or_times_out() {
+        let admission = admission(
+            1,
+            OverloadPolicy::Queue {
+                capacity: 1,
+                timeout: Duration::from_millis(200),
+            },
+        );
+        let permit = match admission.admit() {
+            Admit::Now(permit) => permit,
+            _ => panic!("first connection should be admitted"),
+        };
+        assert!(matches!(admission.admit(), Admit::Queued));
+        assert!(matches!(admission.admit(), Admit::Rejected));
+        assert_eq!(admission.stats.queued(), 1);
+
+        // The queued connection gets the permit once it is released.
+        let waiter = {
+            let admission = Arc::clone(&admission);
+            let accepted = Instant::now();
+            thread::spawn(move || admission.wait(accepted).is_some())
+        };
+        thread::sleep(Duration::from_millis(50));
+        drop(permit);
+        assert!(waiter.join().unwrap());
+
+        // With the permit still held, the next queued connection times out.
+        let _held = match admission.admit() {
+            Admit::Now(permit) => permit,
+            _ => panic!("permit should be free again"),
+        };
+        assert!(matches!(admission.admit(), Admit::Queued));
+        let accepted = Instant::now();
+        assert!(admission.wait(accepted).is_none());
+        assert!(accepted.elapsed() >= Duration::from_millis(200));
+        assert_eq!(admission.stats.rejected(), 2);
+        assert_eq!(admission.stats.queued(), 0);
+    }
+}
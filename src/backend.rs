@@ -0,0 +1,339 @@
+use std::{
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    metrics::BackendMetrics,
+    outlier::OutlierStats,
+    registry::{SessionRegistry, TrackedSession},
+    warn,
+};
+
+/// A single upstream server together with the live state that balancing
+/// strategies look at when choosing where to send a connection.
+#[derive(Debug)]
+pub struct Backend {
+    address: String,
+    tags: Mutex<Vec<String>>,
+    weight: AtomicU32,
+    active_connections: AtomicUsize,
+    healthy: AtomicBool,
+    state: AtomicU8,
+    // Milliseconds since `epoch()` at which a drain closes the sessions
+    // still open, or 0 if there is no such deadline.
+    drain_deadline: AtomicU64,
+    // Milliseconds since `epoch()` until which the backend is ejected.
+    ejected_until: AtomicU64,
+    outlier: Mutex<OutlierStats>,
+    // For closing the sessions left when a drain times out.
+    sessions: Arc<SessionRegistry>,
+    metrics: BackendMetrics,
+}
+
+/// How often a drain with a timeout checks its deadline.
+const DRAIN_CHECK: Duration = Duration::from_millis(100);
+
+impl Backend {
+    /// Largest weight accepted from the configuration file and the admin
+    /// API. Strategies do work in proportion to weights, so this keeps one
+    /// backend from making rebuilds slow.
+    pub const MAX_WEIGHT: u32 = 10_000;
+
+    pub fn new(address: impl Into<String>) -> Self {
+        Self::with_weight(address, 1)
+    }
+
+    pub fn with_weight(address: impl Into<String>, weight: u32) -> Self {
+        Backend {
+            address: address.into(),
+            tags: Mutex::new(Vec::new()),
+            weight: AtomicU32::new(weight),
+            active_connections: AtomicUsize::new(0),
+            healthy: AtomicBool::new(true),
+            state: AtomicU8::new(BackendState::Enabled as u8),
+            drain_deadline: AtomicU64::new(0),
+            ejected_until: AtomicU64::new(0),
+            outlier: Mutex::new(OutlierStats::default()),
+            sessions: Arc::default(),
+            metrics: BackendMetrics::default(),
+        }
+    }
+
+    /// Labels the backend with free-form tags, such as a zone or a
+    /// release, for operators and tooling.
+    pub fn with_tags(self, tags: Vec<String>) -> Self {
+        self.set_tags(tags);
+        self
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn tags(&self) -> Vec<String> {
+        self.tags.lock().unwrap().clone()
+    }
+
+    pub fn set_tags(&self, tags: Vec<String>) {
+        *self.tags.lock().unwrap() = tags;
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::Relaxed)
+    }
+
+    pub fn set_weight(&self, weight: u32) {
+        self.weight.store(weight, Ordering::Relaxed);
+    }
+
+    pub fn metrics(&self) -> &BackendMetrics {
+        &self.metrics
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Counts a new proxied connection against this backend until the
+    /// returned guard is dropped.
+    pub fn acquire(self: &Arc<Self>) -> ConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            backend: Arc::clone(self),
+            session: None,
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    /// Whether an operator has taken the backend out of rotation.
+    pub fn state(&self) -> BackendState {
+        BackendState::from_u8(self.state.load(Ordering::Relaxed))
+    }
+
+    /// Changes the administrative state, cancelling the timeout of a
+    /// drain in progress.
+    pub fn set_state(&self, state: BackendState) {
+        self.drain_deadline.store(0, Ordering::Relaxed);
+        self.state.store(state as u8, Ordering::Relaxed);
+    }
+
+    /// Takes the backend out of rotation while its sessions finish. With a
+    /// `timeout`, the sessions still open once it elapses are closed;
+    /// without one they may run for as long as they like.
+    pub fn drain(self: &Arc<Self>, timeout: Option<Duration>) {
+        self.set_state(BackendState::Draining);
+        let Some(timeout) = timeout else {
+            return;
+        };
+        let deadline = millis_since_epoch() + (timeout.as_millis() as u64).max(1);
+        self.drain_deadline.store(deadline, Ordering::Relaxed);
+
+        let backend = Arc::downgrade(self);
+        thread::spawn(move || enforce_drain_deadline(backend, deadline));
+    }
+
+    /// How far a drain has got, or `None` if the backend is not draining.
+    pub fn drain_progress(&self) -> Option<DrainProgress> {
+        if self.state() != BackendState::Draining {
+            return None;
+        }
+        let deadline = self.drain_deadline.load(Ordering::Relaxed);
+        Some(DrainProgress {
+            remaining_connections: self.active_connections(),
+            time_left: (deadline != 0)
+                .then(|| Duration::from_millis(deadline.saturating_sub(millis_since_epoch()))),
+        })
+    }
+
+    /// Closes every tracked session on this backend, returning how many
+    /// there were.
+    pub fn close_sessions(&self) -> usize {
+        self.sessions.close_all()
+    }
+
+    /// Whether outlier detection has temporarily taken the backend out of
+    /// rotation.
+    pub fn is_ejected(&self) -> bool {
+        self.ejection_remaining().is_some()
+    }
+
+    pub fn ejection_remaining(&self) -> Option<Duration> {
+        let until = self.ejected_until.load(Ordering::Relaxed);
+        let now = millis_since_epoch();
+        (until > now).then(|| Duration::from_millis(until - now))
+    }
+
+    pub(crate) fn eject_for(&self, duration: Duration) {
+        let until = millis_since_epoch() + duration.as_millis() as u64;
+        self.ejected_until.store(until, Ordering::Relaxed);
+    }
+
+    pub(crate) fn outlier_stats(&self) -> MutexGuard<'_, OutlierStats> {
+        self.outlier.lock().unwrap()
+    }
+
+    /// Whether the backend may be handed new connections.
+    pub fn is_available(&self) -> bool {
+        self.state() == BackendState::Enabled && self.is_healthy() && !self.is_ejected()
+    }
+}
+
+/// Administrative state of a backend, set by operators independently of
+/// its health. Only enabled backends are handed new connections; sessions
+/// already on a draining or disabled backend carry on until they close.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BackendState {
+    #[default]
+    Enabled,
+    /// Being taken out of rotation, typically so it can be restarted once
+    /// its sessions have finished. See [`Backend::drain`].
+    Draining,
+    /// Out of service until enabled again.
+    Disabled,
+}
+
+impl BackendState {
+    pub fn name(self) -> &'static str {
+        match self {
+            BackendState::Enabled => "enabled",
+            BackendState::Draining => "draining",
+            BackendState::Disabled => "disabled",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BackendState> {
+        match name {
+            "enabled" => Some(BackendState::Enabled),
+            "draining" => Some(BackendState::Draining),
+            "disabled" => Some(BackendState::Disabled),
+            _ => None,
+        }
+    }
+
+    fn from_u8(value: u8) -> BackendState {
+        match value {
+            1 => BackendState::Draining,
+            2 => BackendState::Disabled,
+            _ => BackendState::Enabled,
+        }
+    }
+}
+
+/// Closes the sessions left on `backend` once `deadline` passes, unless the
+/// drain is cancelled or replaced by another one first.
+fn enforce_drain_deadline(backend: Weak<Backend>, deadline: u64) {
+    loop {
+        let Some(backend) = backend.upgrade() else {
+            return;
+        };
+        if backend.drain_deadline.load(Ordering::Relaxed) != deadline {
+            return;
+        }
+        let left = deadline.saturating_sub(millis_since_epoch());
+        if left == 0 {
+            let closed = backend.close_sessions();
+            if closed > 0 {
+                warn!(
+                    "Drain timed out, closed {} sessions", closed;
+                    backend = backend.address(),
+                );
+            }
+            return;
+        }
+        drop(backend);
+        thread::sleep(DRAIN_CHECK.min(Duration::from_millis(left)));
+    }
+}
+
+fn millis_since_epoch() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    EPOCH.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Progress of a backend drain, for automation waiting on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrainProgress {
+    pub remaining_connections: usize,
+    /// Time until the remaining sessions are closed, if the drain has a
+    /// timeout.
+    pub time_left: Option<Duration>,
+}
+
+impl DrainProgress {
+    pub fn is_complete(&self) -> bool {
+        self.remaining_connections == 0
+    }
+}
+
+/// Keeps a backend's active connection count raised while a connection to it
+/// is being proxied.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    backend: Arc<Backend>,
+    session: Option<TrackedSession>,
+}
+
+impl ConnectionGuard {
+    pub fn backend(&self) -> &Arc<Backend> {
+        &self.backend
+    }
+
+    /// Registers the sockets of the session so that a timed-out drain can
+    /// close it. Sockets that cannot be duplicated are not tracked.
+    pub fn track(&mut self, client: &TcpStream, server: &TcpStream) {
+        self.session = self.backend.sessions.track(client, server);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.backend
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_guard_tracks_active_connections() {
+        let backend = Arc::new(Backend::new("127.0.0.1:9000"));
+        let first = backend.acquire();
+        let second = backend.acquire();
+        assert_eq!(backend.active_connections(), 2);
+
+        drop(first);
+        assert_eq!(backend.active_connections(), 1);
+        assert_eq!(second.backend().address(), "127.0.0.1:9000");
+        drop(second);
+        assert_eq!(backend.active_connections(), 0);
+    }
+
+    #[test]
+    fn test_only_enabled_backends_are_available() {
+        let backend = Backend::new("127.0.0.1:9000");
+        for state in [BackendState::Draining, BackendState::Disabled] {
+            backend.set_state(state);
+            assert_eq!(backend.state(), state);
+            assert!(!backend.is_available());
+            assert_eq!(BackendState::from_name(state.name()), Some(state));
+        }
+        backend.set_state(BackendState::Enabled);
+        assert!(backend.is_available());
+    }
+}
@@ -0,0 +1,1226 @@
+//! Configuration files.
+//!
+//! A configuration file is TOML describing the frontends to listen on, the
+//! backend pools they forward to, the timeouts that apply to every session
+//! and, optionally, where to serve the [admin API](crate::admin), how to
+//! [log](crate::logging) and where to write the
+//! [access log](crate::access_log):
+//!
+//! ```toml
+//! [[frontend]]
+//! name = "web"
+//! bind = "0.0.0.0"
+//! port = 8080
+//! pool = "web"
+//! mode = "http"
+//! max_connections = 5000
+//! overload = "queue"
+//! queue_timeout = "2s"
+//!
+//! [pool.web]
+//! strategy = "consistent_hash"
+//! hash_key = "header:X-User"
+//!
+//! [[pool.web.backend]]
+//! address = "10.0.0.1:8081"
+//! weight = 2
+//! tags = ["zone-a"]
+//!
+//! [pool.web.health_check]
+//! type = "http"
+//! path = "/healthz"
+//! interval = "2s"
+//!
+//! [pool.web.outlier_detection]
+//! consecutive_failures = 3
+//!
+//! [timeouts]
+//! connect = "3s"
+//! client_idle = "60s"
+//!
+//! [admin]
+//! address = "127.0.0.1:9000"
+//!
+//! [log]
+//! level = "info,health=debug"
+//! format = "json"
+//!
+//! [access_log]
+//! path = "/var/log/lb/access.log"
+//! format = "%{client} %{backend} %{duration_ms} %{reason}"
+//! ```
+//!
+//! Files are parsed and validated in one go into a [`Config`]. Errors name
+//! the offending key, such as `pool.web.backend[1].weight`, and the line it
+//! is on. [`Pools`] runs the configured pools and applies later versions of
+//! the file to them without a restart.
+
+use std::{
+    cell::RefCell,
+    error::Error,
+    fmt, fs,
+    net::{IpAddr, SocketAddr},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    access_log::{AccessLogConfig, AccessLogFormat},
+    http::HttpConfig,
+    logging::{Filter, Format, LogConfig},
+    strategy, Backend, BalancingStrategy, ConsistentHash, HashKey, HealthCheckConfig,
+    HealthCheckKind, HttpCheck, LeastConnections, LoadBalancer, Maglev, OutlierConfig,
+    OverloadPolicy, PowerOfTwoChoices, ProxyConfig, RetryPolicy, RoundRobin, Timeouts,
+    WeightedRoundRobin,
+};
+
+mod pools;
+mod toml;
+
+pub use pools::{Pools, ReloadSummary};
+use toml::{Table, Value, ValueKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Config {
+    pub frontends: Vec<FrontendConfig>,
+    pub pools: Vec<PoolConfig>,
+    pub timeouts: Timeouts,
+    /// Address of the admin API, which is not served unless set.
+    pub admin: Option<SocketAddr>,
+    /// Log settings, applied when the pools are started or reloaded.
+    /// Without them the current settings are kept.
+    pub log: Option<LogConfig>,
+    /// Where to write the access log, which is not written unless set.
+    pub access_log: Option<AccessLogConfig>,
+}
+
+/// A listener and the pool it forwards to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrontendConfig {
+    pub name: String,
+    pub address: SocketAddr,
+    pub pool: String,
+    /// Set by `mode = "http"`; frontends proxy plain TCP by default.
+    pub http: Option<HttpConfig>,
+    /// From `retry_attempts`.
+    pub retry: RetryPolicy,
+    pub workers: usize,
+    pub max_connections: usize,
+    /// From `overload`, which is `queue` or `reject`, and for queueing
+    /// `queue_capacity` and `queue_timeout`.
+    pub overload: OverloadPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolConfig {
+    pub name: String,
+    pub strategy: StrategyConfig,
+    pub backends: Vec<BackendConfig>,
+    pub health_check: Option<HealthCheckConfig>,
+    pub outlier_detection: Option<OutlierConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendConfig {
+    pub address: String,
+    /// At most [`Backend::MAX_WEIGHT`].
+    pub weight: u32,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StrategyConfig {
+    #[default]
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastConnections,
+    PowerOfTwoChoices,
+    ConsistentHash(HashKey),
+    /// The hash key and the size of the lookup table.
+    Maglev(HashKey, usize),
+}
+
+impl StrategyConfig {
+    pub fn build(&self) -> Box<dyn BalancingStrategy> {
+        match self {
+            StrategyConfig::RoundRobin => Box::new(RoundRobin::new()),
+            StrategyConfig::WeightedRoundRobin => Box::new(WeightedRoundRobin::new()),
+            StrategyConfig::LeastConnections => Box::new(LeastConnections::new()),
+            StrategyConfig::PowerOfTwoChoices => Box::new(PowerOfTwoChoices::new()),
+            StrategyConfig::ConsistentHash(key) => Box::new(ConsistentHash::new(key.clone())),
+            StrategyConfig::Maglev(key, table_size) => {
+                Box::new(Maglev::with_table_size(key.clone(), *table_size))
+            }
+        }
+    }
+}
+
+impl Config {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let input = fs::read_to_string(path).map_err(|e| ConfigError {
+            key: None,
+            line: None,
+            message: format!("cannot read {}: {}", path.display(), e),
+        })?;
+        Config::parse(&input)
+    }
+
+    pub fn parse(input: &str) -> Result<Config, ConfigError> {
+        let root = toml::parse(input).map_err(|e| ConfigError {
+            key: None,
+            line: Some(e.line),
+            message: e.message,
+        })?;
+        let root = Section {
+            path: String::new(),
+            line: None,
+            table: &root,
+            seen: RefCell::new(Vec::new()),
+        };
+
+        let pools = match root.section("pool")? {
+            Some(pools) => pools
+                .named_sections()?
+                .into_iter()
+                .map(|(name, pool)| decode_pool(name, &pool))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+        let frontends = decode_frontends(&root, &pools)?;
+        let timeouts = match root.section("timeouts")? {
+            Some(section) => decode_timeouts(&section)?,
+            None => Timeouts::default(),
+        };
+        let admin = match root.section("admin")? {
+            Some(section) => Some(decode_admin(&section)?),
+            None => None,
+        };
+        let log = match root.section("log")? {
+            Some(section) => Some(decode_log(&section)?),
+            None => None,
+        };
+        let access_log = match root.section("access_log")? {
+            Some(section) => Some(decode_access_log(&section)?),
+            None => None,
+        };
+        root.finish()?;
+
+        Ok(Config {
+            frontends,
+            pools,
+            timeouts,
+            admin,
+            log,
+            access_log,
+        })
+    }
+
+    pub fn pool(&self, name: &str) -> Option<&PoolConfig> {
+        self.pools.iter().find(|p| p.name == name)
+    }
+
+    /// Options for running `frontend`. Health checks and outlier detection
+    /// are left out because they belong to pools, which frontends may
+    /// share.
+    pub fn proxy_config(&self, frontend: &FrontendConfig) -> ProxyConfig {
+        ProxyConfig {
+            name: Some(frontend.name.clone()),
+            http: frontend.http.clone(),
+            retry: frontend.retry.clone(),
+            timeouts: self.timeouts.clone(),
+            workers: frontend.workers,
+            max_connections: frontend.max_connections,
+            overload: frontend.overload,
+            ..ProxyConfig::default()
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn load_balancer(&self) -> LoadBalancer {
+        let mut load_balancer = LoadBalancer::from_backends(
+            self.backends.iter().map(BackendConfig::backend).collect(),
+            self.strategy.build(),
+        );
+        load_balancer.set_outlier_detection(self.outlier_detection.clone());
+        load_balancer
+    }
+}
+
+impl BackendConfig {
+    pub fn backend(&self) -> Backend {
+        Backend::with_weight(self.address.clone(), self.weight).with_tags(self.tags.clone())
+    }
+}
+
+/// A configuration file that could not be read, parsed or validated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    key: Option<String>,
+    line: Option<usize>,
+    message: String,
+}
+
+impl ConfigError {
+    /// Dotted path of the offending key, such as `frontend[0].port`.
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        self.line
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.key, self.line) {
+            (Some(key), Some(line)) => write!(f, "{} (line {}): {}", key, line, self.message),
+            (Some(key), None) => write!(f, "{}: {}", key, self.message),
+            (None, Some(line)) => write!(f, "line {}: {}", line, self.message),
+            (None, None) => f.write_str(&self.message),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+fn decode_frontends(
+    root: &Section<'_>,
+    pools: &[PoolConfig],
+) -> Result<Vec<FrontendConfig>, ConfigError> {
+    let sections = root.sections("frontend")?;
+    if sections.is_empty() {
+        return Err(root.missing("frontend"));
+    }
+
+    let mut frontends: Vec<FrontendConfig> = Vec::new();
+    for section in &sections {
+        let bind = match section.get("bind") {
+            Some(value) => {
+                let bind = section.as_str("bind", value)?;
+                bind.parse::<IpAddr>().map_err(|_| {
+                    section.invalid("bind", value, format!("`{}` is not an IP address", bind))
+                })?
+            }
+            None => IpAddr::from([127, 0, 0, 1]),
+        };
+        let port = section.required_integer("port", 1..=65535)? as u16;
+        let address = SocketAddr::new(bind, port);
+        if let Some(other) = frontends.iter().find(|f| f.address == address) {
+            let value = section.get("port").unwrap();
+            return Err(section.invalid(
+                "port",
+                value,
+                format!("{} is already used by frontend `{}`", address, other.name),
+            ));
+        }
+
+        let pool = section.required_string("pool")?;
+        if !pools.iter().any(|p| p.name == pool) {
+            let value = section.get("pool").unwrap();
+            return Err(section.invalid("pool", value, format!("unknown pool `{}`", pool)));
+        }
+
+        let name = match section.string("name")? {
+            Some(name) => name.to_string(),
+            None => address.to_string(),
+        };
+        if frontends.iter().any(|f| f.name == name) {
+            let value = section.get("name").unwrap();
+            return Err(section.invalid(
+                "name",
+                value,
+                format!("frontend `{}` is defined twice", name),
+            ));
+        }
+        let http = match section.string("mode")?.unwrap_or("tcp") {
+            "tcp" => None,
+            "http" => Some(HttpConfig::default()),
+            other => {
+                let value = section.get("mode").unwrap();
+                return Err(section.invalid(
+                    "mode",
+                    value,
+                    format!("unknown mode `{}`; expected `tcp` or `http`", other),
+                ));
+            }
+        };
+        let defaults = ProxyConfig::default();
+        let retry = RetryPolicy {
+            attempts: section
+                .integer("retry_attempts", 1..=100)?
+                .unwrap_or(defaults.retry.attempts.into()) as u32,
+        };
+        let workers = section
+            .integer("workers", 1..=65_536)?
+            .unwrap_or(defaults.workers as i64) as usize;
+        let max_connections = section
+            .integer("max_connections", 1..=1_000_000)?
+            .unwrap_or(defaults.max_connections as i64) as usize;
+        let overload = decode_overload(section)?;
+        section.finish()?;
+
+        frontends.push(FrontendConfig {
+            name,
+            address,
+            pool: pool.to_string(),
+            http,
+            retry,
+            workers,
+            max_connections,
+            overload,
+        });
+    }
+    Ok(frontends)
+}
+
+/// Accepts `overload = "queue"`, the default, with optional
+/// `queue_capacity` and `queue_timeout`, or `overload = "reject"`.
+fn decode_overload(section: &Section<'_>) -> Result<OverloadPolicy, ConfigError> {
+    const QUEUE_KEYS: [&str; 2] = ["queue_capacity", "queue_timeout"];
+
+    match section.string("overload")?.unwrap_or("queue") {
+        "queue" => Ok(OverloadPolicy::Queue {
+            capacity: section
+                .integer("queue_capacity", 0..=1_000_000)?
+                .unwrap_or(OverloadPolicy::DEFAULT_QUEUE_CAPACITY as i64)
+                as usize,
+            timeout: section
+                .nonzero_duration("queue_timeout")?
+                .unwrap_or(OverloadPolicy::DEFAULT_QUEUE_TIMEOUT),
+        }),
+        "reject" => {
+            if let Some(key) = QUEUE_KEYS
+                .iter()
+                .find(|key| section.table.contains_key(**key))
+            {
+                let value = section.get(key).unwrap();
+                return Err(section.invalid(key, value, "only used when overload is `queue`"));
+            }
+            Ok(OverloadPolicy::Reject)
+        }
+        other => {
+            let value = section.get("overload").unwrap();
+            Err(section.invalid(
+                "overload",
+                value,
+                format!(
+                    "unknown overload policy `{}`; expected `queue` or `reject`",
+                    other
+                ),
+            ))
+        }
+    }
+}
+
+fn decode_pool(name: &str, section: &Section<'_>) -> Result<PoolConfig, ConfigError> {
+    let strategy = match section.string("strategy")?.unwrap_or("round_robin") {
+        "round_robin" => StrategyConfig::RoundRobin,
+        "weighted_round_robin" => StrategyConfig::WeightedRoundRobin,
+        "least_connections" => StrategyConfig::LeastConnections,
+        "power_of_two_choices" => StrategyConfig::PowerOfTwoChoices,
+        "consistent_hash" => StrategyConfig::ConsistentHash(decode_hash_key(section)?),
+        "maglev" => StrategyConfig::Maglev(
+            decode_hash_key(section)?,
+            section
+                .integer("maglev_table_size", 2..=16_777_216)?
+                .unwrap_or(strategy::DEFAULT_TABLE_SIZE as i64) as usize,
+        ),
+        other => {
+            let value = section.get("strategy").unwrap();
+            return Err(section.invalid(
+                "strategy",
+                value,
+                format!("unknown strategy `{}`", other),
+            ));
+        }
+    };
+    if !matches!(
+        strategy,
+        StrategyConfig::ConsistentHash(_) | StrategyConfig::Maglev(..)
+    ) {
+        if let Some(value) = section.get("hash_key") {
+            return Err(section.invalid(
+                "hash_key",
+                value,
+                "only used by the consistent_hash and maglev strategies",
+            ));
+        }
+    }
+    if !matches!(strategy, StrategyConfig::Maglev(..)) {
+        if let Some(value) = section.get("maglev_table_size") {
+            return Err(section.invalid(
+                "maglev_table_size",
+                value,
+                "only used by the maglev strategy",
+            ));
+        }
+    }
+
+    let mut backends: Vec<BackendConfig> = Vec::new();
+    for backend in section.sections("backend")? {
+        let address = backend.required_string("address")?;
+        if !is_host_port(address) {
+            let value = backend.get("address").unwrap();
+            return Err(backend.invalid(
+                "address",
+                value,
+                format!("`{}` is not a host:port address", address),
+            ));
+        }
+        if backends.iter().any(|b| b.address == address) {
+            let value = backend.get("address").unwrap();
+            return Err(backend.invalid(
+                "address",
+                value,
+                format!("backend `{}` is listed twice", address),
+            ));
+        }
+        let weight = backend
+            .integer("weight", 0..=i64::from(Backend::MAX_WEIGHT))?
+            .unwrap_or(1) as u32;
+        let tags = backend.strings("tags")?.unwrap_or_default();
+        backend.finish()?;
+        backends.push(BackendConfig {
+            address: address.to_string(),
+            weight,
+            tags,
+        });
+    }
+    if backends.is_empty() {
+        return Err(section.missing("backend"));
+    }
+
+    let health_check = match section.section("health_check")? {
+        Some(health_check) => Some(decode_health_check(&health_check)?),
+        None => None,
+    };
+    let outlier_detection = match section.section("outlier_detection")? {
+        Some(outlier_detection) => Some(decode_outlier_detection(&outlier_detection)?),
+        None => None,
+    };
+    section.finish()?;
+
+    Ok(PoolConfig {
+        name: name.to_string(),
+        strategy,
+        backends,
+        health_check,
+        outlier_detection,
+    })
+}
+
+/// Accepts `client_ip`, `header:<name>` or `cookie:<name>`.
+fn decode_hash_key(section: &Section<'_>) -> Result<HashKey, ConfigError> {
+    let Some(value) = section.get("hash_key") else {
+        return Ok(HashKey::default());
+    };
+    let key = section.as_str("hash_key", value)?;
+    match key.split_once(':') {
+        None if key == "client_ip" => Ok(HashKey::ClientIp),
+        Some(("header", name)) if !name.is_empty() => Ok(HashKey::Header(name.to_string())),
+        Some(("cookie", name)) if !name.is_empty() => Ok(HashKey::Cookie(name.to_string())),
+        _ => Err(section.invalid(
+            "hash_key",
+            value,
+            format!(
+                "unknown hash key `{}`; expected `client_ip`, `header:<name>` or `cookie:<name>`",
+                key
+            ),
+        )),
+    }
+}
+
+fn decode_health_check(section: &Section<'_>) -> Result<HealthCheckConfig, ConfigError> {
+    const HTTP_KEYS: [&str; 5] = ["method", "path", "host", "expected_status", "body_contains"];
+
+    let defaults = HealthCheckConfig::default();
+    let kind = match section.string("type")?.unwrap_or("tcp") {
+        "tcp" => {
+            if let Some(key) = HTTP_KEYS
+                .iter()
+                .find(|key| section.table.contains_key(**key))
+            {
+                let value = section.get(key).unwrap();
+                return Err(section.invalid(key, value, "only used by http health checks"));
+            }
+            HealthCheckKind::Tcp
+        }
+        "http" => {
+            let http = HttpCheck::default();
+            HealthCheckKind::Http(HttpCheck {
+                method: section
+                    .string("method")?
+                    .unwrap_or(&http.method)
+                    .to_string(),
+                path: section.string("path")?.unwrap_or(&http.path).to_string(),
+                host: section.string("host")?.map(str::to_string),
+                expected_status: match section.get("expected_status") {
+                    Some(value) => decode_status_range(section, value)?,
+                    None => http.expected_status,
+                },
+                body_contains: section.string("body_contains")?.map(str::to_string),
+            })
+        }
+        other => {
+            let value = section.get("type").unwrap();
+            return Err(section.invalid(
+                "type",
+                value,
+                format!(
+                    "unknown health check type `{}`; expected `tcp` or `http`",
+                    other
+                ),
+            ));
+        }
+    };
+    let config = HealthCheckConfig {
+        kind,
+        interval: section
+            .nonzero_duration("interval")?
+            .unwrap_or(defaults.interval),
+        timeout: section
+            .nonzero_duration("timeout")?
+            .unwrap_or(defaults.timeout),
+        rise: section
+            .integer("rise", 1..=1000)?
+            .unwrap_or(defaults.rise.into()) as u32,
+        fall: section
+            .integer("fall", 1..=1000)?
+            .unwrap_or(defaults.fall.into()) as u32,
+    };
+    section.finish()?;
+    Ok(config)
+}
+
+fn decode_outlier_detection(section: &Section<'_>) -> Result<OutlierConfig, ConfigError> {
+    let defaults = OutlierConfig::default();
+    let config = OutlierConfig {
+        consecutive_failures: section
+            .integer("consecutive_failures", 0..=1_000_000)?
+            .unwrap_or(defaults.consecutive_failures.into()) as u32,
+        failure_rate_percent: section
+            .integer("failure_rate_percent", 1..=100)?
+            .map(|percent| percent as u32)
+            .or(defaults.failure_rate_percent),
+        window: section
+            .nonzero_duration("window")?
+            .unwrap_or(defaults.window),
+        min_requests: section
+            .integer("min_requests", 1..=1_000_000)?
+            .unwrap_or(defaults.min_requests.into()) as u32,
+        base_ejection: section
+            .nonzero_duration("base_ejection")?
+            .unwrap_or(defaults.base_ejection),
+        max_ejection: section
+            .nonzero_duration("max_ejection")?
+            .unwrap_or(defaults.max_ejection),
+        max_ejection_percent: section
+            .integer("max_ejection_percent", 0..=100)?
+            .unwrap_or(defaults.max_ejection_percent.into()) as u32,
+    };
+    if config.max_ejection < config.base_ejection {
+        let key = match section.table.contains_key("max_ejection") {
+            true => "max_ejection",
+            false => "base_ejection",
+        };
+        let value = section.get(key).unwrap();
+        return Err(section.invalid(
+            key,
+            value,
+            format!(
+                "max_ejection ({:?}) is shorter than base_ejection ({:?})",
+                config.max_ejection, config.base_ejection
+            ),
+        ));
+    }
+    section.finish()?;
+    Ok(config)
+}
+
+/// Accepts a single status such as `200` or a range such as `"200-399"`.
+fn decode_status_range(
+    section: &Section<'_>,
+    value: &Value,
+) -> Result<RangeInclusive<u16>, ConfigError> {
+    let invalid = || {
+        section.invalid(
+            "expected_status",
+            value,
+            "expected a status code such as 200 or a range such as \"200-399\"",
+        )
+    };
+    let (low, high) = match &value.kind {
+        ValueKind::Integer(status) => (*status, *status),
+        ValueKind::String(range) => {
+            let (low, high) = range.split_once('-').ok_or_else(invalid)?;
+            (
+                low.trim().parse().map_err(|_| invalid())?,
+                high.trim().parse().map_err(|_| invalid())?,
+            )
+        }
+        _ => return Err(invalid()),
+    };
+    if !(100..=599).contains(&low) || !(100..=599).contains(&high) || low > high {
+        return Err(invalid());
+    }
+    Ok(low as u16..=high as u16)
+}
+
+fn decode_timeouts(section: &Section<'_>) -> Result<Timeouts, ConfigError> {
+    let defaults = Timeouts::default();
+    let timeouts = Timeouts {
+        connect: section
+            .nonzero_duration("connect")?
+            .unwrap_or(defaults.connect),
+        client_idle: section
+            .nonzero_duration("client_idle")?
+            .unwrap_or(defaults.client_idle),
+        backend_idle: section
+            .nonzero_duration("backend_idle")?
+            .unwrap_or(defaults.backend_idle),
+        max_session: section
+            .nonzero_duration("max_session")?
+            .or(defaults.max_session),
+        shutdown_grace: section
+            .duration("shutdown_grace")?
+            .unwrap_or(defaults.shutdown_grace),
+    };
+    section.finish()?;
+    Ok(timeouts)
+}
+
+fn decode_admin(section: &Section<'_>) -> Result<SocketAddr, ConfigError> {
+    let address = section.required_string("address")?;
+    let address = address.parse().map_err(|_| {
+        let value = section.get("address").unwrap();
+        section.invalid(
+            "address",
+            value,
+            format!("`{}` is not an ip:port address", address),
+        )
+    })?;
+    section.finish()?;
+    Ok(address)
+}
+
+fn decode_log(section: &Section<'_>) -> Result<LogConfig, ConfigError> {
+    let mut log = LogConfig::default();
+    if let Some(level) = section.string("level")? {
+        log.filter = level
+            .parse::<Filter>()
+            .map_err(|message| section.invalid("level", section.get("level").unwrap(), message))?;
+    }
+    if let Some(format) = section.string("format")? {
+        log.format = Format::from_name(format).ok_or_else(|| {
+            section.invalid(
+                "format",
+                section.get("format").unwrap(),
+                format!("unknown format `{}`; expected logfmt or json", format),
+            )
+        })?;
+    }
+    section.finish()?;
+    Ok(log)
+}
+
+fn decode_access_log(section: &Section<'_>) -> Result<AccessLogConfig, ConfigError> {
+    let path = PathBuf::from(section.required_string("path")?);
+    let format = match section.string("format")? {
+        Some(template) => template.parse().map_err(|message| {
+            section.invalid("format", section.get("format").unwrap(), message)
+        })?,
+        None => AccessLogFormat::default(),
+    };
+    section.finish()?;
+    Ok(AccessLogConfig { path, format })
+}
+
+pub(crate) fn is_host_port(address: &str) -> bool {
+    match address.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok_and(|p| p != 0),
+        None => false,
+    }
+}
+
+/// Parses durations such as `250ms`, `5s`, `2m` or `1h`.
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit())?;
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    match unit {
+        "ms" => Some(Duration::from_millis(number)),
+        "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number.checked_mul(60)?)),
+        "h" => Some(Duration::from_secs(number.checked_mul(3600)?)),
+        _ => None,
+    }
+}
+
+/// A table being decoded. Remembers which keys were looked at so that
+/// [`Section::finish`] can reject the rest as unknown.
+struct Section<'a> {
+    path: String,
+    line: Option<usize>,
+    table: &'a Table,
+    seen: RefCell<Vec<&'a str>>,
+}
+
+impl<'a> Section<'a> {
+    fn key_path(&self, key: &str) -> String {
+        if self.path.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.path, key)
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Value> {
+        let (key, value) = self.table.get_key_value(key)?;
+        self.seen.borrow_mut().push(key);
+        Some(value)
+    }
+
+    fn invalid(&self, key: &str, value: &Value, message: impl Into<String>) -> ConfigError {
+        ConfigError {
+            key: Some(self.key_path(key)),
+            line: Some(value.line),
+            message: message.into(),
+        }
+    }
+
+    fn missing(&self, key: &str) -> ConfigError {
+        ConfigError {
+            key: Some(self.key_path(key)),
+            line: self.line,
+            message: "missing required key".to_string(),
+        }
+    }
+
+    fn mismatch(&self, key: &str, value: &Value, expected: &str) -> ConfigError {
+        self.invalid(
+            key,
+            value,
+            format!("expected {}, found {}", expected, value.kind.type_name()),
+        )
+    }
+
+    fn as_str(&self, key: &str, value: &'a Value) -> Result<&'a str, ConfigError> {
+        match &value.kind {
+            ValueKind::String(s) => Ok(s),
+            _ => Err(self.mismatch(key, value, "a string")),
+        }
+    }
+
+    fn string(&self, key: &str) -> Result<Option<&'a str>, ConfigError> {
+        self.get(key)
+            .map(|value| self.as_str(key, value))
+            .transpose()
+    }
+
+    fn required_string(&self, key: &str) -> Result<&'a str, ConfigError> {
+        self.string(key)?.ok_or_else(|| self.missing(key))
+    }
+
+    fn integer(&self, key: &str, range: RangeInclusive<i64>) -> Result<Option<i64>, ConfigError> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        match value.kind {
+            ValueKind::Integer(n) if range.contains(&n) => Ok(Some(n)),
+            ValueKind::Integer(n) => Err(self.invalid(
+                key,
+                value,
+                format!(
+                    "{} is out of range; expected {} to {}",
+                    n,
+                    range.start(),
+                    range.end()
+                ),
+            )),
+            _ => Err(self.mismatch(key, value, "an integer")),
+        }
+    }
+
+    fn required_integer(&self, key: &str, range: RangeInclusive<i64>) -> Result<i64, ConfigError> {
+        self.integer(key, range)?.ok_or_else(|| self.missing(key))
+    }
+
+    fn duration(&self, key: &str) -> Result<Option<Duration>, ConfigError> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        let expected = "expected a duration such as \"250ms\", \"5s\", \"2m\" or \"1h\"";
+        match &value.kind {
+            ValueKind::String(s) => parse_duration(s)
+                .map(Some)
+                .ok_or_else(|| self.invalid(key, value, expected)),
+            _ => Err(self.invalid(key, value, expected)),
+        }
+    }
+
+    /// A duration that has to be longer than zero, such as a timeout.
+    fn nonzero_duration(&self, key: &str) -> Result<Option<Duration>, ConfigError> {
+        match self.duration(key)? {
+            Some(duration) if duration.is_zero() => {
+                let value = self.get(key).unwrap();
+                Err(self.invalid(key, value, "must be longer than zero"))
+            }
+            duration => Ok(duration),
+        }
+    }
+
+    fn strings(&self, key: &str) -> Result<Option<Vec<String>>, ConfigError> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        let ValueKind::Array(items) = &value.kind else {
+            return Err(self.mismatch(key, value, "an array of strings"));
+        };
+        items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| match &item.kind {
+                ValueKind::String(s) => Ok(s.clone()),
+                _ => Err(self.mismatch(&format!("{}[{}]", key, i), item, "a string")),
+            })
+            .collect::<Result<_, _>>()
+            .map(Some)
+    }
+
+    fn section(&self, key: &str) -> Result<Option<Section<'a>>, ConfigError> {
+        let Some(value) = self.get(key) else {
+            return Ok(None);
+        };
+        match &value.kind {
+            ValueKind::Table(table) => Ok(Some(Section {
+                path: self.key_path(key),
+                line: Some(value.line),
+                table,
+                seen: RefCell::new(Vec::new()),
+            })),
+            _ => Err(self.mismatch(key, value, "a table")),
+        }
+    }
+
+    /// An array of tables, such as one written with `[[key]]` headers.
+    fn sections(&self, key: &str) -> Result<Vec<Section<'a>>, ConfigError> {
+        let Some(value) = self.get(key) else {
+            return Ok(Vec::new());
+        };
+        let ValueKind::Array(items) = &value.kind else {
+            return Err(self.mismatch(key, value, "an array of tables"));
+        };
+        items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| match &item.kind {
+                ValueKind::Table(table) => Ok(Section {
+                    path: self.key_path(&format!("{}[{}]", key, i)),
+                    line: Some(item.line),
+                    table,
+                    seen: RefCell::new(Vec::new()),
+                }),
+                _ => Err(self.mismatch(&format!("{}[{}]", key, i), item, "a table")),
+            })
+            .collect()
+    }
+
+    /// Every entry of this table as a named sub-table.
+    fn named_sections(&self) -> Result<Vec<(&'a str, Section<'a>)>, ConfigError> {
+        self.table
+            .keys()
+            .map(|name| Ok((name.as_str(), self.section(name)?.unwrap())))
+            .collect()
+    }
+
+    fn finish(&self) -> Result<(), ConfigError> {
+        let seen = self.seen.borrow();
+        match self
+            .table
+            .iter()
+            .find(|(key, _)| !seen.contains(&key.as_str()))
+        {
+            Some((key, value)) => Err(self.invalid(key, value, "unknown key")),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = r#"
+[[frontend]]
+name = "web"
+bind = "0.0.0.0"
+port = 8080
+pool = "web"
+mode = "http"
+retry_attempts = 2
+workers = 64
+max_connections = 500
+overload = "reject"
+
+[[frontend]]
+port = 9090
+pool = "web"
+
+[pool.web]
+strategy = "maglev"
+hash_key = "cookie:session"
+maglev_table_size = 1000
+
+[[pool.web.backend]]
+address = "10.0.0.1:8081"
+weight = 3
+tags = ["zone-a", "canary"]
+
+[[pool.web.backend]]
+address = "backend.internal:8082"
+
+[pool.web.health_check]
+type = "http"
+path = "/status"
+expected_status = "200-399"
+interval = "500ms"
+fall = 2
+
+[pool.web.outlier_detection]
+failure_rate_percent = 50
+base_ejection = "10s"
+
+[timeouts]
+connect = "2s"
+max_session = "1h"
+shutdown_grace = "10s"
+
+[admin]
+address = "127.0.0.1:9000"
+
+[log]
+level = "warn,proxy=trace"
+format = "json"
+
+[access_log]
+path = "/var/log/lb/access.log"
+"#;
+
+    #[test]
+    fn test_parse_full_config() {
+        let config = Config::parse(EXAMPLE).unwrap();
+
+        assert_eq!(config.frontends.len(), 2);
+        assert_eq!(config.frontends[0].name, "web");
+        assert_eq!(config.frontends[0].address, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.frontends[0].http, Some(HttpConfig::default()));
+        assert_eq!(config.frontends[1].name, "127.0.0.1:9090");
+        assert_eq!(config.frontends[1].http, None);
+        let proxy_config = config.proxy_config(&config.frontends[0]);
+        assert_eq!(proxy_config.retry.attempts, 2);
+        assert_eq!(proxy_config.workers, 64);
+        assert_eq!(proxy_config.max_connections, 500);
+        assert_eq!(proxy_config.overload, OverloadPolicy::Reject);
+        assert_eq!(config.frontends[1].overload, OverloadPolicy::default(),);
+
+        let pool = config.pool("web").unwrap();
+        assert_eq!(
+            pool.strategy,
+            StrategyConfig::Maglev(HashKey::Cookie("session".to_string()), 1000)
+        );
+        assert_eq!(
+            pool.backends[0],
+            BackendConfig {
+                address: "10.0.0.1:8081".to_string(),
+                weight: 3,
+                tags: vec!["zone-a".to_string(), "canary".to_string()],
+            }
+        );
+        assert_eq!(pool.backends[1].weight, 1);
+
+        let health_check = pool.health_check.as_ref().unwrap();
+        assert_eq!(health_check.interval, Duration::from_millis(500));
+        assert_eq!(health_check.fall, 2);
+        assert_eq!(health_check.rise, HealthCheckConfig::default().rise);
+        let outlier_detection = pool.outlier_detection.as_ref().unwrap();
+        assert_eq!(outlier_detection.failure_rate_percent, Some(50));
+        assert_eq!(outlier_detection.base_ejection, Duration::from_secs(10));
+        assert_eq!(
+            outlier_detection.consecutive_failures,
+            OutlierConfig::default().consecutive_failures
+        );
+        match &health_check.kind {
+            HealthCheckKind::Http(http) => {
+                assert_eq!(http.path, "/status");
+                assert_eq!(http.expected_status, 200..=399);
+            }
+            other => panic!("unexpected kind {:?}", other),
+        }
+
+        assert_eq!(config.timeouts.connect, Duration::from_secs(2));
+        assert_eq!(config.timeouts.max_session, Some(Duration::from_secs(3600)));
+        assert_eq!(config.timeouts.client_idle, Timeouts::default().client_idle);
+        assert_eq!(config.timeouts.shutdown_grace, Duration::from_secs(10));
+        assert_eq!(config.admin, Some("127.0.0.1:9000".parse().unwrap()));
+        let log = config.log.as_ref().unwrap();
+        assert_eq!(log.filter.to_string(), "warn,proxy=trace");
+        assert_eq!(log.format, Format::Json);
+        let access_log = config.access_log.as_ref().unwrap();
+        assert_eq!(access_log.path, Path::new("/var/log/lb/access.log"));
+        assert_eq!(access_log.format, AccessLogFormat::default());
+
+        let lb = pool.load_balancer();
+        assert_eq!(lb.strategy_name(), "maglev");
+        assert_eq!(lb.backends()[0].tags(), ["zone-a", "canary"]);
+    }
+
+    #[test]
+    fn test_errors_point_to_offending_key() {
+        let base =
+            "[[frontend]]\nport = 80\npool = \"p\"\n\n[[pool.p.backend]]\naddress = \"a:1\"\n";
+        let cases = [
+            (
+                "[[frontend]]\nport = 80\n",
+                "frontend[0].pool",
+                Some(1),
+                "missing required key",
+            ),
+            (
+                &format!("{}weight = \"heavy\"\n", base),
+                "pool.p.backend[0].weight",
+                Some(7),
+                "expected an integer, found a string",
+            ),
+            (
+                &format!("{}wieght = 2\n", base),
+                "pool.p.backend[0].wieght",
+                Some(7),
+                "unknown key",
+            ),
+            (
+                &format!("{}weight = 10001\n", base),
+                "pool.p.backend[0].weight",
+                Some(7),
+                "out of range",
+            ),
+            (
+                &base.replace("port = 80", "port = 70000"),
+                "frontend[0].port",
+                Some(2),
+                "out of range",
+            ),
+            (
+                &base.replace("pool = \"p\"", "pool = \"q\""),
+                "frontend[0].pool",
+                Some(3),
+                "unknown pool `q`",
+            ),
+            (
+                &base.replace("pool = \"p\"", "pool = \"p\"\nmode = \"udp\""),
+                "frontend[0].mode",
+                Some(4),
+                "unknown mode `udp`",
+            ),
+            (
+                &format!("{}\n[pool.p.health_check]\npath = \"/\"\n", base),
+                "pool.p.health_check.path",
+                Some(9),
+                "only used by http health checks",
+            ),
+            (
+                &format!("{}\n[timeouts]\nconnect = 5\n", base),
+                "timeouts.connect",
+                Some(9),
+                "expected a duration",
+            ),
+            (
+                &format!("{}\n[timeouts]\nconnect = \"0s\"\n", base),
+                "timeouts.connect",
+                Some(9),
+                "must be longer than zero",
+            ),
+            (
+                &format!("{}\n[pool.p.health_check]\ninterval = \"0ms\"\n", base),
+                "pool.p.health_check.interval",
+                Some(9),
+                "must be longer than zero",
+            ),
+            (
+                &base.replace(
+                    "pool = \"p\"",
+                    "pool = \"p\"\noverload = \"reject\"\nqueue_capacity = 5",
+                ),
+                "frontend[0].queue_capacity",
+                Some(5),
+                "only used when overload is `queue`",
+            ),
+            (
+                &format!("{}\n[pool.p]\nmaglev_table_size = 1000\n", base),
+                "pool.p.maglev_table_size",
+                Some(9),
+                "only used by the maglev strategy",
+            ),
+            (
+                &format!(
+                    "{}\n[pool.p.outlier_detection]\nmax_ejection = \"10s\"\n",
+                    base
+                ),
+                "pool.p.outlier_detection.max_ejection",
+                Some(9),
+                "shorter than base_ejection",
+            ),
+            (
+                &format!("{}\n[pool.p]\nhash_key = \"client_ip\"\n", base),
+                "pool.p.hash_key",
+                Some(9),
+                "only used by the consistent_hash and maglev strategies",
+            ),
+            (
+                &format!("{}\n[admin]\naddress = \"localhost:9000\"\n", base),
+                "admin.address",
+                Some(9),
+                "not an ip:port address",
+            ),
+            (
+                &format!(
+                    "{}
+[log]
+level = \"info,proxy=loud\"\n",
+                    base
+                ),
+                "log.level",
+                Some(9),
+                "unknown level `loud`",
+            ),
+            (
+                &format!(
+                    "{}\n[access_log]\npath = \"a.log\"\nformat = \"%{{who}}\"\n",
+                    base
+                ),
+                "access_log.format",
+                Some(10),
+                "unknown field `who`",
+            ),
+        ];
+        for (input, key, line, message) in cases {
+            let err = Config::parse(input).unwrap_err();
+            assert_eq!(err.key(), Some(key), "{}", err);
+            assert_eq!(err.line(), line, "{}", err);
+            assert!(err.message().contains(message), "{}", err);
+        }
+
+        let err = Config::parse("port = \n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: expected a value, found the end of the line"
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use super::{Config, ConfigError, PoolConfig};
+use crate::{metrics, BackendChanges, HealthChecker, SharedLoadBalancer};
+
+/// The running load balancers built from a configuration, each with its
+/// health checker. A newer configuration can be applied in place with
+/// [`Pools::reload`], so frontends holding a pool's load balancer pick up
+/// the change without being restarted. Each pool's backends are reported
+/// in [`metrics`](crate::metrics) under the pool's name.
+pub struct Pools {
+    config: Config,
+    pools: BTreeMap<String, RunningPool>,
+}
+
+struct RunningPool {
+    config: PoolConfig,
+    load_balancer: SharedLoadBalancer,
+    health_checker: Option<HealthChecker>,
+}
+
+impl RunningPool {
+    fn start(config: &PoolConfig) -> Self {
+        let load_balancer = Arc::new(Mutex::new(config.load_balancer()));
+        metrics::register_pool(&config.name, &load_balancer);
+        RunningPool {
+            config: config.clone(),
+            health_checker: config
+                .health_check
+                .clone()
+                .map(|health_check| HealthChecker::spawn(Arc::clone(&load_balancer), health_check)),
+            load_balancer,
+        }
+    }
+}
+
+/// What a reload changed, per pool.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReloadSummary {
+    pub added_pools: Vec<String>,
+    pub removed_pools: Vec<String>,
+    /// Backend changes of the pools that existed before and after.
+    pub backends: BTreeMap<String, BackendChanges>,
+    /// Settings that differ but only take effect after a restart.
+    pub needs_restart: Vec<String>,
+}
+
+impl Pools {
+    pub fn start(config: &Config) -> Self {
+        if let Some(log) = &config.log {
+            log.apply();
+        }
+        Pools {
+            pools: config
+                .pools
+                .iter()
+                .map(|pool| (pool.name.clone(), RunningPool::start(pool)))
+                .collect(),
+            config: config.clone(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.pools.keys().map(String::as_str)
+    }
+
+    pub fn load_balancer(&self, pool: &str) -> Option<SharedLoadBalancer> {
+        self.pools
+            .get(pool)
+            .map(|running| Arc::clone(&running.load_balancer))
+    }
+
+    /// Re-reads the configuration file at `path` and applies it.
+    pub fn reload_from(&mut self, path: impl AsRef<Path>) -> Result<ReloadSummary, ConfigError> {
+        self.reload(Config::from_file(path)?)
+    }
+
+    /// Applies the pools of `config` to the running load balancers.
+    /// Backends that remain keep their state, and sessions on removed ones
+    /// are left to finish. Frontends cannot be changed without a restart,
+    /// so a configuration that changes them is rejected as a whole.
+    pub fn reload(&mut self, config: Config) -> Result<ReloadSummary, ConfigError> {
+        if config.frontends != self.config.frontends {
+            return Err(ConfigError {
+                key: Some("frontend".to_string()),
+                line: None,
+                message: "frontends cannot be changed without a restart".to_string(),
+            });
+        }
+
+        let mut summary = ReloadSummary::default();
+        if config.timeouts != self.config.timeouts {
+            summary.needs_restart.push("timeouts".to_string());
+        }
+        if config.admin != self.config.admin {
+            summary.needs_restart.push("admin".to_string());
+        }
+        if config.access_log != self.config.access_log {
+            summary.needs_restart.push("access_log".to_string());
+        }
+        self.pools.retain(|name, _| {
+            let keep = config.pool(name).is_some();
+            if !keep {
+                metrics::unregister_pool(name);
+                summary.removed_pools.push(name.clone());
+            }
+            keep
+        });
+        for pool in &config.pools {
+            let Some(running) = self.pools.get_mut(&pool.name) else {
+                summary.added_pools.push(pool.name.clone());
+                self.pools
+                    .insert(pool.name.clone(), RunningPool::start(pool));
+                continue;
+            };
+
+            let changes = {
+                let mut load_balancer = running.load_balancer.lock().unwrap();
+                if pool.strategy != running.config.strategy {
+                    load_balancer.set_strategy(pool.strategy.build());
+                }
+                if pool.outlier_detection != running.config.outlier_detection {
+                    load_balancer.set_outlier_detection(pool.outlier_detection.clone());
+                }
+                load_balancer.replace_backends(pool.backends.iter().map(|b| b.backend()).collect())
+            };
+            if pool.health_check != running.config.health_check {
+                if let Some(old) = running.health_checker.take() {
+                    old.stop();
+                }
+                running.health_checker = pool.health_check.clone().map(|health_check| {
+                    HealthChecker::spawn(Arc::clone(&running.load_balancer), health_check)
+                });
+            }
+            running.config = pool.clone();
+            if !changes.is_empty() {
+                summary.backends.insert(pool.name.clone(), changes);
+            }
+        }
+
+        if let Some(log) = &config.log {
+            log.apply();
+        }
+        self.config = config;
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(backends: &str) -> Config {
+        Config::parse(&format!(
+            "[[frontend]]\nport = 8080\npool = \"web\"\n\n[pool.web]\n{}",
+            backends
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_reload_swaps_backends_in_place() {
+        let mut pools = Pools::start(&config(
+            "[[pool.web.backend]]\naddress = \"a:1\"\n[[pool.web.backend]]\naddress = \"b:1\"\n",
+        ));
+        let load_balancer = pools.load_balancer("web").unwrap();
+        let a = Arc::clone(&load_balancer.lock().unwrap().backends()[0]);
+        a.set_healthy(false);
+
+        let summary = pools
+            .reload(config(
+                "strategy = \"least_connections\"\n\
+                 [[pool.web.backend]]\naddress = \"a:1\"\nweight = 2\n\
+                 [[pool.web.backend]]\naddress = \"c:1\"\n",
+            ))
+            .unwrap();
+
+        let changes = &summary.backends["web"];
+        assert_eq!(changes.added, ["c:1"]);
+        assert_eq!(changes.removed, ["b:1"]);
+        assert_eq!(changes.updated, ["a:1"]);
+        let load_balancer = load_balancer.lock().unwrap();
+        assert_eq!(load_balancer.strategy_name(), "least_connections");
+        assert!(Arc::ptr_eq(&load_balancer.backends()[0], &a));
+        assert!(!load_balancer.backends()[0].is_healthy());
+    }
+
+    #[test]
+    fn test_reload_rejects_frontend_changes() {
+        let backends = "[[pool.web.backend]]\naddress = \"a:1\"\n";
+        let mut pools = Pools::start(&config(backends));
+        let changed = Config::parse(&format!(
+            "[[frontend]]\nport = 9090\npool = \"web\"\n\n[pool.web]\n{}",
+            backends
+        ))
+        .unwrap();
+
+        let err = pools.reload(changed).unwrap_err();
+        assert_eq!(err.key(), Some("frontend"));
+        assert_eq!(pools.config().frontends[0].address.port(), 8080);
+    }
+}
@@ -0,0 +1,513 @@
+//! A parser for the subset of TOML that configuration files use.
+//!
+//! Supported are comments, `[table]` and `[[array.of.tables]]` headers with
+//! dotted names, bare and quoted keys, basic and literal strings, integers,
+//! floats, booleans, arrays (which may span lines) and inline tables.
+//! Multi-line strings, dates and dotted keys on the left of `=` are not.
+//! Every value remembers the line it started on so that later validation
+//! errors can point at it.
+
+use std::{collections::BTreeMap, fmt};
+
+pub(crate) type Table = BTreeMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Value {
+    pub(crate) kind: ValueKind,
+    pub(crate) line: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum ValueKind {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl ValueKind {
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            ValueKind::String(_) => "a string",
+            ValueKind::Integer(_) => "an integer",
+            ValueKind::Float(_) => "a float",
+            ValueKind::Boolean(_) => "a boolean",
+            ValueKind::Array(_) => "an array",
+            ValueKind::Table(_) => "a table",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    pub(crate) line: usize,
+    pub(crate) message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+pub(crate) fn parse(input: &str) -> Result<Table, ParseError> {
+    Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+        line: 1,
+    }
+    .document()
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn document(&mut self) -> Result<Table, ParseError> {
+        let mut root = Table::new();
+        let mut current: Vec<String> = Vec::new();
+        // Headers of `[table]`s seen so far, which may not be repeated.
+        let mut defined: Vec<Vec<String>> = Vec::new();
+
+        loop {
+            self.skip_blank();
+            let Some(c) = self.peek() else {
+                return Ok(root);
+            };
+            let line = self.line;
+            if c == '[' {
+                self.bump();
+                let array = self.eat('[');
+                let path = self.header_path()?;
+                self.expect(']')?;
+                if array {
+                    self.expect(']')?;
+                }
+                self.end_of_line()?;
+
+                let (parent, last) = path.split_at(path.len() - 1);
+                let parent = table_at(&mut root, parent, line)?;
+                let last = &last[0];
+                if array {
+                    let entry = parent.entry(last.clone()).or_insert(Value {
+                        kind: ValueKind::Array(Vec::new()),
+                        line,
+                    });
+                    match &mut entry.kind {
+                        ValueKind::Array(items)
+                            if items
+                                .iter()
+                                .all(|item| matches!(item.kind, ValueKind::Table(_))) =>
+                        {
+                            items.push(Value {
+                                kind: ValueKind::Table(Table::new()),
+                                line,
+                            });
+                        }
+                        _ => {
+                            return Err(error(
+                                line,
+                                format!("`{}` is not an array of tables", last),
+                            ))
+                        }
+                    }
+                } else {
+                    if defined.contains(&path) {
+                        return Err(error(
+                            line,
+                            format!("table `{}` is defined twice", path.join(".")),
+                        ));
+                    }
+                    defined.push(path.clone());
+                    let entry = parent.entry(last.clone()).or_insert(Value {
+                        kind: ValueKind::Table(Table::new()),
+                        line,
+                    });
+                    match &mut entry.kind {
+                        ValueKind::Table(_) => entry.line = line,
+                        _ => return Err(error(line, format!("`{}` is not a table", last))),
+                    }
+                }
+                current = path;
+            } else {
+                let key = self.key()?;
+                self.skip_spaces();
+                if self.peek() == Some('.') {
+                    return Err(error(line, "dotted keys are not supported"));
+                }
+                self.expect('=')?;
+                self.skip_spaces();
+                let value = self.value()?;
+                self.end_of_line()?;
+                insert(table_at(&mut root, &current, line)?, key, value)?;
+            }
+        }
+    }
+
+    fn header_path(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut path = Vec::new();
+        loop {
+            self.skip_spaces();
+            path.push(self.key()?);
+            self.skip_spaces();
+            if !self.eat('.') {
+                return Ok(path);
+            }
+        }
+    }
+
+    fn key(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some('"') => self.basic_string(),
+            Some('\'') => self.literal_string(),
+            _ => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    self.bump();
+                }
+                if self.pos == start {
+                    return Err(self.unexpected("a key"));
+                }
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        let line = self.line;
+        let kind = match self.peek() {
+            Some('"') => ValueKind::String(self.basic_string()?),
+            Some('\'') => ValueKind::String(self.literal_string()?),
+            Some('[') => ValueKind::Array(self.array()?),
+            Some('{') => ValueKind::Table(self.inline_table()?),
+            Some(c) if c.is_ascii_alphanumeric() || c == '+' || c == '-' => self.scalar()?,
+            _ => return Err(self.unexpected("a value")),
+        };
+        Ok(Value { kind, line })
+    }
+
+    fn scalar(&mut self) -> Result<ValueKind, ParseError> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || "+-._".contains(c)) {
+            self.bump();
+        }
+        let token: String = self.chars[start..self.pos].iter().collect();
+        match token.as_str() {
+            "true" => return Ok(ValueKind::Boolean(true)),
+            "false" => return Ok(ValueKind::Boolean(false)),
+            _ => {}
+        }
+        let digits = token.replace('_', "");
+        if let Ok(n) = digits.parse::<i64>() {
+            return Ok(ValueKind::Integer(n));
+        }
+        if token.contains(['.', 'e', 'E']) {
+            if let Ok(f) = digits.parse::<f64>() {
+                return Ok(ValueKind::Float(f));
+            }
+        }
+        Err(error(
+            self.line,
+            format!("invalid value `{}`; strings must be quoted", token),
+        ))
+    }
+
+    fn basic_string(&mut self) -> Result<String, ParseError> {
+        // Strings cannot span lines, so errors belong to the opening line.
+        let line = self.line;
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(s),
+                Some('\\') => {
+                    let escaped = match self.bump() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('u') => {
+                            let hex: String = (0..4).filter_map(|_| self.bump()).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or_else(|| error(line, "invalid unicode escape"))?
+                        }
+                        _ => return Err(error(line, "invalid escape sequence")),
+                    };
+                    s.push(escaped);
+                }
+                Some('\n') | None => return Err(error(line, "unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, ParseError> {
+        let line = self.line;
+        self.expect('\'')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('\'') => return Ok(s),
+                Some('\n') | None => return Err(error(line, "unterminated string")),
+                Some(c) => s.push(c),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Vec<Value>, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.eat(']') {
+                return Ok(items);
+            }
+            items.push(self.value()?);
+            self.skip_blank();
+            if self.eat(']') {
+                return Ok(items);
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn inline_table(&mut self) -> Result<Table, ParseError> {
+        self.expect('{')?;
+        let mut table = Table::new();
+        self.skip_spaces();
+        if self.eat('}') {
+            return Ok(table);
+        }
+        loop {
+            self.skip_spaces();
+            let key = self.key()?;
+            self.skip_spaces();
+            self.expect('=')?;
+            self.skip_spaces();
+            let value = self.value()?;
+            insert(&mut table, key, value)?;
+            self.skip_spaces();
+            if self.eat('}') {
+                return Ok(table);
+            }
+            self.expect(',')?;
+        }
+    }
+
+    /// Skips trailing spaces and a comment, then requires a newline or the
+    /// end of the input.
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip_spaces();
+        self.skip_comment();
+        match self.peek() {
+            None => Ok(()),
+            Some('\n') => {
+                self.bump();
+                Ok(())
+            }
+            Some('\r') if self.chars.get(self.pos + 1) == Some(&'\n') => {
+                self.pos += 1;
+                self.bump();
+                Ok(())
+            }
+            _ => Err(self.unexpected("the end of the line")),
+        }
+    }
+
+    /// Skips whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.peek() {
+                Some('\n' | '\r') => {
+                    self.bump();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_spaces(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t')) {
+            self.bump();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), Some('\n') | None) {
+                self.bump();
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        if self.peek() == Some(expected) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            Err(self.unexpected(&format!("`{}`", expected)))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> ParseError {
+        let found = match self.peek() {
+            None => "the end of the file".to_string(),
+            Some('\n' | '\r') => "the end of the line".to_string(),
+            Some(c) => format!("`{}`", c),
+        };
+        error(self.line, format!("expected {}, found {}", expected, found))
+    }
+}
+
+/// Follows `path` from `root`, descending into the last element of arrays
+/// of tables and creating missing tables on the way.
+fn table_at<'a>(
+    root: &'a mut Table,
+    path: &[String],
+    line: usize,
+) -> Result<&'a mut Table, ParseError> {
+    let mut table = root;
+    for key in path {
+        let entry = table.entry(key.clone()).or_insert(Value {
+            kind: ValueKind::Table(Table::new()),
+            line,
+        });
+        let kind = match &mut entry.kind {
+            ValueKind::Array(items) => match items.last_mut() {
+                Some(last) => &mut last.kind,
+                None => return Err(error(line, format!("`{}` is not a table", key))),
+            },
+            kind => kind,
+        };
+        table = match kind {
+            ValueKind::Table(inner) => inner,
+            _ => return Err(error(line, format!("`{}` is not a table", key))),
+        };
+    }
+    Ok(table)
+}
+
+fn insert(table: &mut Table, key: String, value: Value) -> Result<(), ParseError> {
+    if table.contains_key(&key) {
+        return Err(error(value.line, format!("key `{}` is defined twice", key)));
+    }
+    table.insert(key, value);
+    Ok(())
+}
+
+fn error(line: usize, message: impl Into<String>) -> ParseError {
+    ParseError {
+        line,
+        message: message.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get<'a>(table: &'a Table, key: &str) -> &'a ValueKind {
+        &table[key].kind
+    }
+
+    #[test]
+    fn test_parse_tables_arrays_and_scalars() {
+        let input = r#"
+# Comment
+title = "lb" # trailing comment
+count = 1_000
+ratio = 0.5
+enabled = true
+tags = [
+    "a",  # first
+    'b',
+]
+
+[pool."web-1"]
+strategy = "maglev"
+
+[[pool."web-1".backend]]
+address = "10.0.0.1:80"
+
+[[pool."web-1".backend]]
+address = "10.0.0.2:80"
+meta = { weight = 2, zone = "b" }
+"#;
+        let root = parse(input).unwrap();
+        assert_eq!(get(&root, "title"), &ValueKind::String("lb".to_string()));
+        assert_eq!(get(&root, "count"), &ValueKind::Integer(1000));
+        assert_eq!(get(&root, "ratio"), &ValueKind::Float(0.5));
+        assert_eq!(get(&root, "enabled"), &ValueKind::Boolean(true));
+        match get(&root, "tags") {
+            ValueKind::Array(items) => assert_eq!(items.len(), 2),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let ValueKind::Table(pools) = get(&root, "pool") else {
+            panic!("pool is not a table");
+        };
+        let ValueKind::Table(web) = get(pools, "web-1") else {
+            panic!("web-1 is not a table");
+        };
+        assert_eq!(web["strategy"].line, 13);
+        let ValueKind::Array(backends) = get(web, "backend") else {
+            panic!("backend is not an array");
+        };
+        assert_eq!(backends.len(), 2);
+        assert_eq!(backends[1].line, 18);
+        let ValueKind::Table(second) = &backends[1].kind else {
+            panic!("backend is not a table");
+        };
+        assert_eq!(second["meta"].line, 20);
+    }
+
+    #[test]
+    fn test_parse_errors_carry_line_numbers() {
+        let cases = [
+            ("a = 1\nb = unquoted\n", 2, "strings must be quoted"),
+            ("a = 1\na = 2\n", 2, "defined twice"),
+            ("[t]\n[t]\n", 2, "defined twice"),
+            ("a = \"open\n", 1, "unterminated string"),
+            ("a = [1, 2\n", 2, "expected `,`"),
+            ("a.b = 1\n", 1, "dotted keys"),
+            ("a = 1 b = 2\n", 1, "end of the line"),
+        ];
+        for (input, line, message) in cases {
+            let err = parse(input).unwrap_err();
+            assert_eq!(err.line, line, "{:?}: {}", input, err);
+            assert!(err.message.contains(message), "{:?}: {}", input, err);
+        }
+    }
+}
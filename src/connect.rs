@@ -0,0 +1,221 @@
+//! Choosing a backend for a new client and connecting to it.
+//!
+//! When a connect fails, the failure is reported to outlier detection and
+//! another eligible backend is tried, up to the configured number of
+//! attempts. Nothing has been sent to the backend at that point, so the
+//! client never notices.
+
+use std::{
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    logging, warn, Backend, ConnectionGuard, Outcome, ProxyError, SelectContext,
+    SharedLoadBalancer, TimeoutKind,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of backends to try, including the first. `1` disables
+    /// retries.
+    pub attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { attempts: 3 }
+    }
+}
+
+/// An open connection to a backend, counted against its active connections
+/// until dropped.
+#[derive(Debug)]
+pub struct BackendConnection {
+    pub stream: TcpStream,
+    pub guard: ConnectionGuard,
+    /// Addresses of every backend that was attempted, in order, ending with
+    /// the one connected to.
+    pub tried: Vec<String>,
+}
+
+impl BackendConnection {
+    pub fn backend(&self) -> &Arc<Backend> {
+        self.guard.backend()
+    }
+}
+
+/// Picks a backend for the connection described by `ctx` and connects to
+/// it, moving on to other backends when connecting fails. Each attempt may
+/// take up to `connect_timeout`. Fails with the error of the last attempt,
+/// or [`ProxyError::NoBackend`] if there was none.
+pub fn connect_with_retry(
+    load_balancer: &SharedLoadBalancer,
+    ctx: &SelectContext,
+    policy: &RetryPolicy,
+    connect_timeout: Duration,
+) -> Result<BackendConnection, ProxyError> {
+    let mut ctx = ctx.clone();
+    let mut tried = Vec::new();
+    let mut last_error = None;
+
+    for _ in 0..policy.attempts.max(1) {
+        let selected = load_balancer.lock().unwrap().select(&ctx);
+        let backend = match selected {
+            Some(backend) => backend,
+            None => break,
+        };
+        let guard = backend.acquire();
+        tried.push(backend.address().to_string());
+
+        let started = Instant::now();
+        match self::connect_timeout(backend.address(), connect_timeout) {
+            Ok(stream) => {
+                backend.metrics().record_connect(started.elapsed());
+                return Ok(BackendConnection {
+                    stream,
+                    guard,
+                    tried,
+                });
+            }
+            Err(e) => {
+                backend.metrics().record_connect_failure();
+                warn!(
+                    "Error connecting to backend";
+                    backend = backend.address(),
+                    error_kind = logging::error_kind(&e),
+                    error = e,
+                );
+                load_balancer
+                    .lock()
+                    .unwrap()
+                    .report(&backend, Outcome::Failure);
+                ctx.excluded.push(backend.address().to_string());
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(match last_error {
+        None => ProxyError::NoBackend,
+        Some(e) => ProxyError::connect(tried, e),
+    })
+}
+
+/// Connects to the first address `address` resolves to that accepts within
+/// `timeout`. Timing out fails with [`TimeoutKind::Connect`].
+pub(crate) fn connect_timeout(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                last_error = Some(TimeoutKind::Connect.into())
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} did not resolve to any address", address),
+        )
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackendServer, LoadBalancer, Side};
+    use std::{net::TcpListener, sync::Mutex};
+
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+    // A port nothing listens on: bind it, note it and let it go again.
+    fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    fn balancer(addresses: Vec<String>) -> SharedLoadBalancer {
+        Arc::new(Mutex::new(LoadBalancer::new(addresses)))
+    }
+
+    #[test]
+    fn test_connect_with_retry_skips_refused_backends() {
+        let server = BackendServer::start(0).unwrap();
+        let dead = [
+            format!("127.0.0.1:{}", closed_port()),
+            format!("127.0.0.1:{}", closed_port()),
+        ];
+        let live = format!("127.0.0.1:{}", server.port());
+        let lb = balancer(vec![dead[0].clone(), dead[1].clone(), live.clone()]);
+
+        let connection = connect_with_retry(
+            &lb,
+            &SelectContext::default(),
+            &RetryPolicy::default(),
+            CONNECT_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(connection.backend().address(), live);
+        assert_eq!(
+            connection.tried,
+            vec![dead[0].clone(), dead[1].clone(), live]
+        );
+        assert_eq!(connection.backend().active_connections(), 1);
+        assert_eq!(lb.lock().unwrap().backends()[0].active_connections(), 0);
+    }
+
+    #[test]
+    fn test_connect_with_retry_gives_up_after_attempts() {
+        let server = BackendServer::start(0).unwrap();
+        let dead = [
+            format!("127.0.0.1:{}", closed_port()),
+            format!("127.0.0.1:{}", closed_port()),
+        ];
+        let lb = balancer(vec![
+            dead[0].clone(),
+            dead[1].clone(),
+            format!("127.0.0.1:{}", server.port()),
+        ]);
+        let policy = RetryPolicy { attempts: 2 };
+
+        let err = connect_with_retry(&lb, &SelectContext::default(), &policy, CONNECT_TIMEOUT)
+            .unwrap_err();
+        match &err {
+            ProxyError::Connect {
+                backend,
+                tried,
+                source,
+            } => {
+                assert_eq!(backend, &dead[1]);
+                assert_eq!(tried, &dead);
+                assert_eq!(source.kind(), io::ErrorKind::ConnectionRefused);
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+        assert_eq!(err.side(), Some(Side::Backend));
+        assert_eq!(err.label(), "connection_refused");
+    }
+
+    #[test]
+    fn test_connect_with_retry_no_backend_available() {
+        let lb = balancer(vec![format!("127.0.0.1:{}", closed_port())]);
+        lb.lock().unwrap().backends()[0].set_healthy(false);
+
+        let err = connect_with_retry(
+            &lb,
+            &SelectContext::default(),
+            &RetryPolicy::default(),
+            CONNECT_TIMEOUT,
+        )
+        .unwrap_err();
+        assert!(matches!(err, ProxyError::NoBackend), "{:?}", err);
+    }
+}
@@ -0,0 +1,217 @@
+//! Why proxying failed.
+//!
+//! Sessions and connect attempts fail with plain [`io::Error`]s deep down.
+//! [`ProxyError`] adds what callers need to act on them: which end of the
+//! session failed, the backend involved, and whether a timeout was the
+//! cause.
+
+use std::{error::Error, fmt, io, net::SocketAddr};
+
+use crate::{logging, Side, TimeoutKind};
+
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The frontend could not listen on `address` or start its workers.
+    Start {
+        address: SocketAddr,
+        source: io::Error,
+    },
+    /// No backend was eligible for the connection: all of them are
+    /// unhealthy, ejected or draining, or the pool is empty.
+    NoBackend,
+    /// Connecting to a backend failed. `backend` is the last backend tried
+    /// and `source` its error; `tried` lists every attempt in order.
+    Connect {
+        backend: String,
+        tried: Vec<String>,
+        source: io::Error,
+    },
+    /// A connect or session timeout expired. For connect timeouts
+    /// `tried` lists every attempt in order, like for `Connect`; for
+    /// session timeouts it is empty.
+    Timeout {
+        kind: TimeoutKind,
+        side: Side,
+        backend: String,
+        tried: Vec<String>,
+    },
+    /// An established session failed on one side, for example because
+    /// the client reset its connection.
+    Session {
+        side: Side,
+        backend: String,
+        source: io::Error,
+    },
+}
+
+impl ProxyError {
+    /// The error of the last of the `tried` connect attempts.
+    pub(crate) fn connect(tried: Vec<String>, source: io::Error) -> Self {
+        let backend = tried.last().cloned().unwrap_or_default();
+        match TimeoutKind::of(&source) {
+            Some(kind) => ProxyError::Timeout {
+                kind,
+                side: Side::Backend,
+                backend,
+                tried,
+            },
+            None => ProxyError::Connect {
+                backend,
+                tried,
+                source,
+            },
+        }
+    }
+
+    /// The error that ended a session with `backend`.
+    pub(crate) fn session(side: Side, backend: &str, source: io::Error) -> Self {
+        let backend = backend.to_string();
+        match TimeoutKind::of(&source) {
+            Some(kind) => ProxyError::Timeout {
+                kind,
+                side,
+                backend,
+                tried: Vec::new(),
+            },
+            None => ProxyError::Session {
+                side,
+                backend,
+                source,
+            },
+        }
+    }
+
+    /// Which end of the session failed. `None` if the frontend failed to
+    /// start.
+    pub fn side(&self) -> Option<Side> {
+        match self {
+            ProxyError::Start { .. } => None,
+            ProxyError::NoBackend | ProxyError::Connect { .. } => Some(Side::Backend),
+            ProxyError::Timeout { side, .. } | ProxyError::Session { side, .. } => Some(*side),
+        }
+    }
+
+    /// The backend the client was being connected or proxied to, if any.
+    pub fn backend(&self) -> Option<&str> {
+        match self {
+            ProxyError::Start { .. } | ProxyError::NoBackend => None,
+            ProxyError::Connect { backend, .. }
+            | ProxyError::Timeout { backend, .. }
+            | ProxyError::Session { backend, .. } => Some(backend),
+        }
+    }
+
+    /// The backends a connect attempt was made to, in order. Empty unless
+    /// connecting failed.
+    pub fn tried(&self) -> &[String] {
+        match self {
+            ProxyError::Connect { tried, .. } | ProxyError::Timeout { tried, .. } => tried,
+            ProxyError::Start { .. } | ProxyError::NoBackend | ProxyError::Session { .. } => &[],
+        }
+    }
+
+    /// A short snake_case name for what went wrong, such as
+    /// `connection_refused`, `connection_reset`, `client_idle_timeout` or
+    /// `no_backend`. Used in logs and as a metrics label.
+    pub fn label(&self) -> String {
+        match self {
+            ProxyError::NoBackend => "no_backend".to_string(),
+            ProxyError::Timeout { kind, .. } => kind.label().to_string(),
+            ProxyError::Start { source, .. }
+            | ProxyError::Connect { source, .. }
+            | ProxyError::Session { source, .. } => logging::error_kind(source),
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::Start { address, source } => {
+                write!(f, "could not start frontend on {}: {}", address, source)
+            }
+            ProxyError::NoBackend => f.write_str("no backend available"),
+            ProxyError::Connect { tried, source, .. } => write!(
+                f,
+                "could not connect to any backend (tried {}): {}",
+                tried.join(", "),
+                source
+            ),
+            ProxyError::Timeout {
+                kind,
+                backend,
+                tried,
+                ..
+            } => match tried.len() {
+                0 | 1 => write!(f, "{} (backend {})", kind, backend),
+                _ => write!(f, "{} (tried {})", kind, tried.join(", ")),
+            },
+            ProxyError::Session {
+                side,
+                backend,
+                source,
+            } => write!(
+                f,
+                "session with backend {} failed on the {} side: {}",
+                backend, side, source
+            ),
+        }
+    }
+}
+
+impl Error for ProxyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProxyError::Start { source, .. }
+            | ProxyError::Connect { source, .. }
+            | ProxyError::Session { source, .. } => Some(source),
+            ProxyError::NoBackend | ProxyError::Timeout { .. } => None,
+        }
+    }
+}
+
+impl From<ProxyError> for io::Error {
+    fn from(error: ProxyError) -> io::Error {
+        let kind = match &error {
+            ProxyError::Start { source, .. }
+            | ProxyError::Connect { source, .. }
+            | ProxyError::Session { source, .. } => source.kind(),
+            ProxyError::NoBackend => io::ErrorKind::NotConnected,
+            ProxyError::Timeout { .. } => io::ErrorKind::TimedOut,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeouts_get_their_own_variant() {
+        let tried = vec!["a:1".to_string(), "b:1".to_string()];
+        let error = ProxyError::connect(tried.clone(), TimeoutKind::Connect.into());
+        assert!(matches!(
+            &error,
+            ProxyError::Timeout {
+                kind: TimeoutKind::Connect,
+                side: Side::Backend,
+                backend,
+                ..
+            } if backend == "b:1"
+        ));
+        assert_eq!(error.tried(), tried);
+        assert_eq!(error.label(), "connect_timeout");
+        assert_eq!(error.to_string(), "connect timeout (tried a:1, b:1)");
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        let error = ProxyError::session(Side::Client, "a:1", reset);
+        assert_eq!(error.side(), Some(Side::Client));
+        assert_eq!(error.backend(), Some("a:1"));
+        assert_eq!(error.label(), "connection_reset");
+        assert_eq!(
+            io::Error::from(error).kind(),
+            io::ErrorKind::ConnectionReset
+        );
+    }
+}
@@ -0,0 +1,809 @@
+//! Event-driven data path for Linux.
+//!
+//! Instead of parking two threads on every proxied session, a single
+//! thread owns all established sessions and multiplexes them with
+//! edge-triggered epoll. Sockets are switched to non-blocking mode when a
+//! session is registered; each direction has its own buffer and is pumped
+//! whenever its source is readable or its destination writable, with the
+//! same half-close and timeout behaviour as the threaded path in
+//! [`proxy`](crate::proxy). With zero copy enabled, the per-direction
+//! buffer is a pipe filled and drained with `splice(2)`.
+//!
+//! The callbacks of closed sessions run on a second thread. They write
+//! the access log and take the load balancer lock, neither of which may
+//! hold up the sessions still open.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    os::fd::AsRawFd,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    debug, error,
+    proxy::{self, SessionContext, SessionResult, Side, Transferred},
+    sys::{self, eventfd, Pipe, Poller, PIPE_CAPACITY},
+    warn, Timeouts,
+};
+
+const BUFFER_SIZE: usize = 16 * 1024;
+const MAX_EVENTS: usize = 1024;
+const TICK: Duration = Duration::from_millis(500);
+const WAKER_TOKEN: u64 = u64::MAX;
+
+/// Called once with the result of a session when it ends, off the event
+/// loop thread.
+pub(crate) type OnClose = Box<dyn FnOnce(SessionResult) + Send>;
+
+struct NewSession {
+    client: TcpStream,
+    server: TcpStream,
+    context: SessionContext,
+    on_close: OnClose,
+}
+
+/// Handle to the event loop thread. Sessions are handed over with
+/// [`EventLoop::register`]. Once the handle is dropped, the thread exits
+/// as soon as its remaining sessions have closed.
+pub(crate) struct EventLoop {
+    // Only `None` while dropping.
+    sender: Option<Sender<NewSession>>,
+    waker: File,
+}
+
+impl EventLoop {
+    pub(crate) fn start(zero_copy: bool, timeouts: Timeouts) -> io::Result<EventLoop> {
+        let poller = Poller::new()?;
+        let waker = eventfd()?;
+        poller.add(waker.as_raw_fd(), WAKER_TOKEN, sys::EPOLLIN | sys::EPOLLET)?;
+        let reader = waker.try_clone()?;
+        let (sender, receiver) = mpsc::channel();
+        let (closer, closed) = mpsc::channel::<(OnClose, SessionResult)>();
+
+        // Exits once the reactor has stopped and every callback has run.
+        thread::Builder::new()
+            .name("event-loop-close".to_string())
+            .spawn(move || {
+                for (on_close, result) in closed {
+                    on_close(result);
+                }
+            })?;
+        thread::Builder::new()
+            .name("event-loop".to_string())
+            .spawn(move || {
+                Reactor::new(poller, reader, receiver, closer, zero_copy, timeouts).run()
+            })?;
+
+        Ok(EventLoop {
+            sender: Some(sender),
+            waker,
+        })
+    }
+
+    /// Hands a connected client/backend pair to the event loop, which owns
+    /// both sockets from then on.
+    pub(crate) fn register(
+        &self,
+        client: TcpStream,
+        server: TcpStream,
+        context: SessionContext,
+        on_close: OnClose,
+    ) -> io::Result<()> {
+        client.set_nonblocking(true)?;
+        server.set_nonblocking(true)?;
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(NewSession {
+                client,
+                server,
+                context,
+                on_close,
+            })
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "event loop has stopped"))?;
+        self.wake()
+    }
+
+    fn wake(&self) -> io::Result<()> {
+        (&self.waker).write_all(&1u64.to_ne_bytes())
+    }
+}
+
+impl Drop for EventLoop {
+    fn drop(&mut self) {
+        self.sender = None;
+        let _ = self.wake();
+    }
+}
+
+struct Reactor {
+    poller: Poller,
+    waker: File,
+    receiver: Receiver<NewSession>,
+    // Where the callbacks of closed sessions are sent to be run.
+    closer: Sender<(OnClose, SessionResult)>,
+    sessions: HashMap<u64, Session>,
+    next_id: u64,
+    zero_copy: bool,
+    timeouts: Timeouts,
+    // Set once the handle is gone and no more sessions can arrive.
+    stopping: bool,
+}
+
+impl Reactor {
+    fn new(
+        poller: Poller,
+        waker: File,
+        receiver: Receiver<NewSession>,
+        closer: Sender<(OnClose, SessionResult)>,
+        zero_copy: bool,
+        timeouts: Timeouts,
+    ) -> Self {
+        Reactor {
+            poller,
+            waker,
+            receiver,
+            closer,
+            sessions: HashMap::new(),
+            next_id: 0,
+            zero_copy,
+            timeouts,
+            stopping: false,
+        }
+    }
+
+    fn run(mut self) {
+        let mut events = vec![sys::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        let mut last_tick = Instant::now();
+        loop {
+            let ready = match self.poller.wait(&mut events, TICK) {
+                Ok(ready) => ready,
+                Err(e) => {
+                    error!("Event loop stopped"; error = e);
+                    return;
+                }
+            };
+
+            for event in &events[..ready] {
+                let (token, flags) = (event.u64, event.events);
+                if token == WAKER_TOKEN {
+                    self.accept_new_sessions();
+                } else {
+                    self.handle_event(token, flags);
+                }
+            }
+
+            if last_tick.elapsed() >= TICK {
+                last_tick = Instant::now();
+                self.close_expired_sessions();
+            }
+            if self.stopping && self.sessions.is_empty() {
+                return;
+            }
+        }
+    }
+
+    fn accept_new_sessions(&mut self) {
+        let mut counter = [0; 8];
+        let _ = (&self.waker).read(&mut counter);
+        loop {
+            let new = match self.receiver.try_recv() {
+                Ok(new) => new,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    self.stopping = true;
+                    return;
+                }
+            };
+            let id = self.next_id;
+            self.next_id += 1;
+            let interest = sys::EPOLLIN | sys::EPOLLOUT | sys::EPOLLRDHUP | sys::EPOLLET;
+            let registered = self
+                .poller
+                .add(new.client.as_raw_fd(), id << 1, interest)
+                .and_then(|_| {
+                    self.poller
+                        .add(new.server.as_raw_fd(), id << 1 | 1, interest)
+                });
+            let session = Session::new(
+                new.client,
+                new.server,
+                new.context,
+                new.on_close,
+                self.zero_copy,
+            );
+            if let Err(e) = registered {
+                self.finish(session, Err((Side::Client, e)));
+                continue;
+            }
+            self.sessions.insert(id, session);
+            self.drive(id);
+        }
+    }
+
+    fn handle_event(&mut self, token: u64, flags: u32) {
+        let id = token >> 1;
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return;
+        };
+        let readiness = if token & 1 == 0 {
+            &mut session.client_ready
+        } else {
+            &mut session.server_ready
+        };
+        if flags & (sys::EPOLLIN | sys::EPOLLRDHUP | sys::EPOLLHUP | sys::EPOLLERR) != 0 {
+            readiness.readable = true;
+        }
+        if flags & (sys::EPOLLOUT | sys::EPOLLHUP | sys::EPOLLERR) != 0 {
+            readiness.writable = true;
+        }
+        self.drive(id);
+    }
+
+    fn drive(&mut self, id: u64) {
+        let Some(session) = self.sessions.get_mut(&id) else {
+            return;
+        };
+        let result = match session.drive() {
+            Ok(false) => return,
+            Ok(true) => Ok(session.transferred()),
+            Err(e) => Err(e),
+        };
+        let session = self.sessions.remove(&id).unwrap();
+        self.finish(session, result);
+    }
+
+    fn close_expired_sessions(&mut self) {
+        let expired: Vec<_> = self
+            .sessions
+            .iter()
+            .filter_map(|(id, s)| Some((*id, s.expired(&self.timeouts)?)))
+            .collect();
+        for (id, kind) in expired {
+            let session = self.sessions.remove(&id).unwrap();
+            debug!("Closing session: {}", kind; conn = session.context.id);
+            self.finish(session, Err((kind.side(), kind.into())));
+        }
+    }
+
+    fn finish(&mut self, mut session: Session, result: SessionResult) {
+        let _ = self.poller.delete(session.client.as_raw_fd());
+        let _ = self.poller.delete(session.server.as_raw_fd());
+        if result.is_err() {
+            let _ = session.client.shutdown(Shutdown::Both);
+            let _ = session.server.shutdown(Shutdown::Both);
+        }
+        if let Some(on_close) = session.on_close.take() {
+            // Only if the closing thread has died are callbacks run here.
+            if let Err(mpsc::SendError((on_close, result))) = self.closer.send((on_close, result)) {
+                on_close(result);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Readiness {
+    readable: bool,
+    writable: bool,
+}
+
+/// Bytes on their way from one socket to the other, held either in user
+/// space or, for zero-copy sessions, in a kernel pipe.
+enum Buffer {
+    Memory {
+        data: Box<[u8]>,
+        start: usize,
+        end: usize,
+    },
+    Pipe {
+        pipe: Pipe,
+        len: usize,
+    },
+}
+
+impl Buffer {
+    fn new(zero_copy: bool) -> Self {
+        if zero_copy {
+            match Pipe::new(true) {
+                Ok(pipe) => return Buffer::Pipe { pipe, len: 0 },
+                Err(e) => warn!("Falling back to buffered copy, cannot create pipe"; error = e),
+            }
+        }
+        Buffer::Memory {
+            data: vec![0; BUFFER_SIZE].into_boxed_slice(),
+            start: 0,
+            end: 0,
+        }
+    }
+
+    fn pending(&self) -> usize {
+        match self {
+            Buffer::Memory { start, end, .. } => end - start,
+            Buffer::Pipe { len, .. } => *len,
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        match self {
+            Buffer::Memory { data, end, .. } => *end < data.len(),
+            Buffer::Pipe { len, .. } => *len < PIPE_CAPACITY,
+        }
+    }
+
+    fn fill_from(&mut self, mut src: &TcpStream) -> io::Result<usize> {
+        match self {
+            Buffer::Memory { data, end, .. } => {
+                let n = src.read(&mut data[*end..])?;
+                *end += n;
+                Ok(n)
+            }
+            Buffer::Pipe { pipe, len } => {
+                let n = pipe.fill_from(src, PIPE_CAPACITY - *len)?;
+                *len += n;
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush_to(&mut self, mut dst: &TcpStream) -> io::Result<usize> {
+        match self {
+            Buffer::Memory { data, start, end } => {
+                let n = dst.write(&data[*start..*end])?;
+                *start += n;
+                if start == end {
+                    *start = 0;
+                    *end = 0;
+                }
+                proxy::record_copied(n);
+                Ok(n)
+            }
+            Buffer::Pipe { pipe, len } => {
+                let n = pipe.drain_to(dst, *len)?;
+                *len -= n;
+                proxy::record_spliced(n);
+                Ok(n)
+            }
+        }
+    }
+}
+
+/// One direction of a session.
+struct Direction {
+    buffer: Buffer,
+    /// The source has reached end of stream.
+    read_closed: bool,
+    /// The destination's write half has been shut down.
+    write_closed: bool,
+    total: u64,
+    /// When the source last sent anything or finished sending.
+    last_received: Instant,
+}
+
+impl Direction {
+    fn new(zero_copy: bool) -> Self {
+        Direction {
+            buffer: Buffer::new(zero_copy),
+            read_closed: false,
+            write_closed: false,
+            total: 0,
+            last_received: Instant::now(),
+        }
+    }
+
+    /// Moves as many bytes as possible from `src` to `dst` without
+    /// blocking. Returns whether anything happened.
+    fn pump(
+        &mut self,
+        src: &TcpStream,
+        src_ready: &mut Readiness,
+        dst: &TcpStream,
+        dst_ready: &mut Readiness,
+        source: Side,
+        context: &SessionContext,
+    ) -> Result<bool, (Side, io::Error)> {
+        let destination = source.peer();
+        let mut progress = false;
+        loop {
+            let mut moved = false;
+
+            while self.buffer.pending() > 0 && dst_ready.writable {
+                match self.buffer.flush_to(dst) {
+                    Ok(0) => return Err((destination, io::ErrorKind::WriteZero.into())),
+                    Ok(n) => {
+                        self.total += n as u64;
+                        context.record(source, n);
+                        moved = true;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => dst_ready.writable = false,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err((destination, e)),
+                }
+            }
+
+            if self.buffer.has_room() && src_ready.readable && !self.read_closed {
+                match self.buffer.fill_from(src) {
+                    Ok(0) => {
+                        self.read_closed = true;
+                        self.last_received = Instant::now();
+                        context.record_finished(source);
+                        moved = true;
+                    }
+                    Ok(_) => {
+                        self.last_received = Instant::now();
+                        moved = true;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        // A pipe can run out of slots before it is full of
+                        // bytes, so with data still queued the socket may
+                        // yet be readable. Try again once the pipe drains.
+                        if self.buffer.pending() == 0
+                            || matches!(self.buffer, Buffer::Memory { .. })
+                        {
+                            src_ready.readable = false;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err((source, e)),
+                }
+            }
+
+            if !moved {
+                break;
+            }
+            progress = true;
+        }
+
+        if self.read_closed && self.buffer.pending() == 0 && !self.write_closed {
+            let _ = dst.shutdown(Shutdown::Write);
+            self.write_closed = true;
+            progress = true;
+        }
+        Ok(progress)
+    }
+}
+
+struct Session {
+    client: TcpStream,
+    server: TcpStream,
+    client_ready: Readiness,
+    server_ready: Readiness,
+    upstream: Direction,
+    downstream: Direction,
+    started: Instant,
+    context: SessionContext,
+    on_close: Option<OnClose>,
+}
+
+impl Session {
+    fn new(
+        client: TcpStream,
+        server: TcpStream,
+        context: SessionContext,
+        on_close: OnClose,
+        zero_copy: bool,
+    ) -> Self {
+        // Assume both sockets are ready; the first attempt will tell.
+        let ready = Readiness {
+            readable: true,
+            writable: true,
+        };
+        Session {
+            client,
+            server,
+            client_ready: ready,
+            server_ready: ready,
+            upstream: Direction::new(zero_copy),
+            downstream: Direction::new(zero_copy),
+            started: Instant::now(),
+            context,
+            on_close: Some(on_close),
+        }
+    }
+
+    /// Pumps both directions until neither can make progress. Returns
+    /// whether the session is complete.
+    fn drive(&mut self) -> Result<bool, (Side, io::Error)> {
+        loop {
+            let up = self.upstream.pump(
+                &self.client,
+                &mut self.client_ready,
+                &self.server,
+                &mut self.server_ready,
+                Side::Client,
+                &self.context,
+            )?;
+            let down = self.downstream.pump(
+                &self.server,
+                &mut self.server_ready,
+                &self.client,
+                &mut self.client_ready,
+                Side::Backend,
+                &self.context,
+            )?;
+            if !up && !down {
+                break;
+            }
+        }
+        Ok(self.upstream.write_closed && self.downstream.write_closed)
+    }
+
+    fn expired(&self, timeouts: &Timeouts) -> Option<proxy::TimeoutKind> {
+        let last = self
+            .upstream
+            .last_received
+            .max(self.downstream.last_received);
+        timeouts.expired(
+            self.started.elapsed(),
+            last.elapsed(),
+            !self.upstream.read_closed,
+            !self.downstream.read_closed,
+        )
+    }
+
+    fn transferred(&self) -> Transferred {
+        Transferred {
+            client_to_backend: self.upstream.total,
+            backend_to_client: self.downstream.total,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TrafficCounters;
+    use std::{
+        net::TcpListener,
+        sync::{mpsc::channel, Arc},
+    };
+
+    fn pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let outer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (inner, _) = listener.accept().unwrap();
+        (outer, inner)
+    }
+
+    #[test]
+    fn test_event_loop_proxies_many_sessions() {
+        proxy_many_sessions(false);
+    }
+
+    #[test]
+    fn test_event_loop_proxies_many_sessions_zero_copy() {
+        let before = proxy::byte_counters();
+        proxy_many_sessions(true);
+        let after = proxy::byte_counters();
+        assert!(after.spliced - before.spliced >= 50 * 100_000);
+    }
+
+    fn proxy_many_sessions(zero_copy: bool) {
+        let event_loop = EventLoop::start(zero_copy, Timeouts::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (done_tx, done_rx) = channel();
+        let traffic = Arc::new(TrafficCounters::default());
+
+        let mut ends = Vec::new();
+        for _ in 0..50 {
+            let (client, proxy_client) = pair(&listener);
+            let (proxy_server, server) = pair(&listener);
+            let done_tx = done_tx.clone();
+            event_loop
+                .register(
+                    proxy_client,
+                    proxy_server,
+                    SessionContext::new(0, vec![Arc::clone(&traffic)]),
+                    Box::new(move |result| done_tx.send(result.is_ok()).unwrap()),
+                )
+                .unwrap();
+            ends.push((client, server));
+        }
+
+        let payload: Vec<u8> = (0..100_000).map(|i| (i % 253) as u8).collect();
+        for (i, (client, server)) in ends.iter_mut().enumerate() {
+            let greeting = format!("hello {}", i);
+            server.write_all(greeting.as_bytes()).unwrap();
+            let mut buffer = vec![0; greeting.len()];
+            client.read_exact(&mut buffer).unwrap();
+            assert_eq!(buffer, greeting.as_bytes());
+        }
+        for (client, server) in ends {
+            let sent = payload.clone();
+            let writer = thread::spawn(move || {
+                let mut client = client;
+                client.write_all(&sent).unwrap();
+                client.shutdown(Shutdown::Write).unwrap();
+                let mut rest = Vec::new();
+                client.read_to_end(&mut rest).unwrap();
+                rest
+            });
+            let mut server = server;
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).unwrap();
+            assert!(received == payload);
+            drop(server);
+            assert!(writer.join().unwrap().is_empty());
+        }
+
+        for _ in 0..50 {
+            assert!(done_rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        }
+        assert_eq!(traffic.get().client_to_backend, 50 * 100_000);
+    }
+
+    #[test]
+    fn test_slow_close_callbacks_do_not_stall_other_sessions() {
+        let event_loop = EventLoop::start(false, Timeouts::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (release_tx, release_rx) = channel::<()>();
+        let (mut first_client, proxy_client) = pair(&listener);
+        let (proxy_server, _first_server) = pair(&listener);
+        event_loop
+            .register(
+                proxy_client,
+                proxy_server,
+                SessionContext::default(),
+                Box::new(move |_| release_rx.recv().unwrap()),
+            )
+            .unwrap();
+        let (mut client, proxy_client) = pair(&listener);
+        let (proxy_server, mut server) = pair(&listener);
+        event_loop
+            .register(
+                proxy_client,
+                proxy_server,
+                SessionContext::default(),
+                Box::new(|_| {}),
+            )
+            .unwrap();
+
+        // The first session closes and its callback blocks.
+        first_client.set_linger_zero();
+        drop(first_client);
+        thread::sleep(Duration::from_millis(100));
+
+        client.write_all(b"ping").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut received = [0; 4];
+        server.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping");
+        release_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_event_loop_reports_backend_reset() {
+        let event_loop = EventLoop::start(true, Timeouts::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (_client, proxy_client) = pair(&listener);
+        let (proxy_server, server) = pair(&listener);
+        let (done_tx, done_rx) = channel();
+        event_loop
+            .register(
+                proxy_client,
+                proxy_server,
+                SessionContext::default(),
+                Box::new(move |result| done_tx.send(result.err().map(|(side, _)| side)).unwrap()),
+            )
+            .unwrap();
+
+        // Closing with unread data makes the kernel send a reset.
+        let mut server = server;
+        server.set_linger_zero();
+        drop(server);
+
+        let side = done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(side, Some(Side::Backend));
+    }
+
+    #[test]
+    fn test_event_loop_closes_idle_sessions() {
+        let timeouts = Timeouts {
+            client_idle: Duration::from_millis(200),
+            backend_idle: Duration::from_millis(200),
+            ..Timeouts::default()
+        };
+        let event_loop = EventLoop::start(false, timeouts).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (_client, proxy_client) = pair(&listener);
+        let (proxy_server, _server) = pair(&listener);
+        let (done_tx, done_rx) = channel();
+        event_loop
+            .register(
+                proxy_client,
+                proxy_server,
+                SessionContext::default(),
+                Box::new(move |result| {
+                    let (side, e) = result.unwrap_err();
+                    done_tx.send((side, proxy::TimeoutKind::of(&e))).unwrap()
+                }),
+            )
+            .unwrap();
+
+        let (side, kind) = done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(side, Side::Client);
+        assert_eq!(kind, Some(proxy::TimeoutKind::ClientIdle));
+    }
+
+    #[test]
+    fn test_one_way_stream_outlives_idle_timeouts() {
+        let timeouts = Timeouts {
+            client_idle: Duration::from_millis(200),
+            backend_idle: Duration::from_millis(200),
+            ..Timeouts::default()
+        };
+        let event_loop = EventLoop::start(false, timeouts).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, proxy_client) = pair(&listener);
+        let (proxy_server, mut server) = pair(&listener);
+        let (done_tx, done_rx) = channel();
+        event_loop
+            .register(
+                proxy_client,
+                proxy_server,
+                SessionContext::default(),
+                Box::new(move |result| done_tx.send(result.is_ok()).unwrap()),
+            )
+            .unwrap();
+
+        // The client is done sending and only the backend talks, slowly.
+        client.shutdown(Shutdown::Write).unwrap();
+        let streamer = thread::spawn(move || {
+            for _ in 0..10 {
+                server.write_all(b".").unwrap();
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).unwrap();
+        streamer.join().unwrap();
+        assert_eq!(received, b"..........");
+        assert!(done_rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    trait LingerZero {
+        fn set_linger_zero(&mut self);
+    }
+
+    impl LingerZero for TcpStream {
+        fn set_linger_zero(&mut self) {
+            #[repr(C)]
+            struct Linger {
+                onoff: i32,
+                linger: i32,
+            }
+            extern "C" {
+                fn setsockopt(
+                    fd: i32,
+                    level: i32,
+                    name: i32,
+                    value: *const Linger,
+                    len: u32,
+                ) -> i32;
+            }
+            const SOL_SOCKET: i32 = 1;
+            const SO_LINGER: i32 = 13;
+            let linger = Linger {
+                onoff: 1,
+                linger: 0,
+            };
+            // SAFETY: `linger` outlives the call and its size is passed along.
+            let result = unsafe {
+                setsockopt(
+                    self.as_raw_fd(),
+                    SOL_SOCKET,
+                    SO_LINGER,
+                    &linger,
+                    std::mem::size_of::<Linger>() as u32,
+                )
+            };
+            assert_eq!(result, 0);
+        }
+    }
+}
@@ -0,0 +1,527 @@
+//! Active health checking of backends.
+//!
+//! A [`HealthChecker`] runs on its own thread and probes every backend of a
+//! shared [`LoadBalancer`](crate::LoadBalancer) once per interval, at most
+//! [`MAX_CONCURRENT_PROBES`] at a time. Frontends that share a load balancer
+//! also share its checker through [`HealthChecker::shared`]. A backend
+//! is marked down after `fall` consecutive failed probes and back up after
+//! `rise` consecutive successful ones; strategies skip backends that are
+//! down.
+//!
+//! Probes either just open a TCP connection or send an HTTP request and
+//! check the response status and, optionally, its body.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{
+    connect::connect_timeout, info, logging, warn, Backend, LoadBalancer, SharedLoadBalancer,
+};
+
+/// Largest HTTP health response that is read before giving up.
+const MAX_HTTP_RESPONSE: usize = 64 * 1024;
+
+/// Most probes one checker runs at the same time.
+pub const MAX_CONCURRENT_PROBES: usize = 16;
+
+/// Checkers started through [`HealthChecker::shared`], by load balancer.
+static SHARED: Mutex<Vec<SharedChecker>> = Mutex::new(Vec::new());
+
+type SharedChecker = (Weak<Mutex<LoadBalancer>>, Weak<HealthChecker>);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HealthCheckKind {
+    /// The backend is up if a TCP connection can be opened.
+    #[default]
+    Tcp,
+    Http(HttpCheck),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpCheck {
+    pub method: String,
+    pub path: String,
+    /// Value of the `Host` header. Defaults to the backend address.
+    pub host: Option<String>,
+    pub expected_status: RangeInclusive<u16>,
+    /// If set, the response body must contain this string.
+    pub body_contains: Option<String>,
+}
+
+impl Default for HttpCheck {
+    fn default() -> Self {
+        HttpCheck {
+            method: "GET".to_string(),
+            path: "/healthz".to_string(),
+            host: None,
+            expected_status: 200..=299,
+            body_contains: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheckConfig {
+    pub kind: HealthCheckKind,
+    pub interval: Duration,
+    /// Limit for connecting and, for HTTP checks, for each read and write.
+    pub timeout: Duration,
+    /// Consecutive successful probes needed to mark a backend up.
+    pub rise: u32,
+    /// Consecutive failed probes needed to mark a backend down.
+    pub fall: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            kind: HealthCheckKind::Tcp,
+            interval: Duration::from_secs(2),
+            timeout: Duration::from_secs(1),
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProbeHistory {
+    successes: u32,
+    failures: u32,
+}
+
+/// Handle to a running health-check thread. Dropping it stops the thread.
+pub struct HealthChecker {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HealthChecker {
+    pub fn spawn(balancer: SharedLoadBalancer, config: HealthCheckConfig) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let mut history: HashMap<String, ProbeHistory> = HashMap::new();
+            loop {
+                let backends = balancer.lock().unwrap().backends().to_vec();
+                run_round(&backends, &config, &mut history);
+
+                let (stopped, cvar) = &*thread_stop;
+                let guard = stopped.lock().unwrap();
+                let (guard, _) = cvar
+                    .wait_timeout_while(guard, config.interval, |stopped| !*stopped)
+                    .unwrap();
+                if *guard {
+                    break;
+                }
+            }
+        });
+
+        HealthChecker {
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Returns the checker already probing `balancer`, or starts one with
+    /// `config`. The checker stops once every handle is dropped; until then
+    /// it keeps the configuration it was started with.
+    pub fn shared(balancer: &SharedLoadBalancer, config: HealthCheckConfig) -> Arc<Self> {
+        let mut shared = SHARED.lock().unwrap();
+        shared.retain(|(_, checker)| checker.strong_count() > 0);
+        let running = shared
+            .iter()
+            .find(|(pool, _)| pool.as_ptr() == Arc::as_ptr(balancer))
+            .and_then(|(_, checker)| checker.upgrade());
+        running.unwrap_or_else(|| {
+            let checker = Arc::new(HealthChecker::spawn(Arc::clone(balancer), config));
+            shared.push((Arc::downgrade(balancer), Arc::downgrade(&checker)));
+            checker
+        })
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let (stopped, cvar) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        cvar.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for HealthChecker {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run_round(
+    backends: &[Arc<Backend>],
+    config: &HealthCheckConfig,
+    history: &mut HashMap<String, ProbeHistory>,
+) {
+    // Probe from several threads so one slow backend cannot delay the rest,
+    // but never from more than MAX_CONCURRENT_PROBES however large the pool.
+    let next = AtomicUsize::new(0);
+    let mut results: Vec<Option<io::Result<()>>> = backends.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let probers: Vec<_> = (0..backends.len().min(MAX_CONCURRENT_PROBES))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut probed = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(backend) = backends.get(i) else {
+                            return probed;
+                        };
+                        probed.push((i, probe(backend.address(), config)));
+                    }
+                })
+            })
+            .collect();
+        for prober in probers {
+            for (i, result) in prober.join().unwrap_or_default() {
+                results[i] = Some(result);
+            }
+        }
+    });
+    let results = results
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|| Err(io::Error::other("health probe panicked"))));
+
+    history.retain(|address, _| backends.iter().any(|b| b.address() == address));
+    for (backend, result) in backends.iter().zip(results) {
+        let entry = history.entry(backend.address().to_string()).or_default();
+        record(backend, entry, result, config);
+    }
+}
+
+fn record(
+    backend: &Backend,
+    history: &mut ProbeHistory,
+    result: io::Result<()>,
+    config: &HealthCheckConfig,
+) {
+    if let Err(e) = result {
+        history.successes = 0;
+        history.failures = history.failures.saturating_add(1);
+        if backend.is_healthy() && history.failures >= config.fall {
+            backend.set_healthy(false);
+            warn!(
+                "Backend marked unhealthy after {} failed checks", history.failures;
+                backend = backend.address(),
+                error_kind = logging::error_kind(&e),
+                error = e,
+            );
+        }
+    } else {
+        history.failures = 0;
+        history.successes = history.successes.saturating_add(1);
+        if !backend.is_healthy() && history.successes >= config.rise {
+            backend.set_healthy(true);
+            info!("Backend is healthy again"; backend = backend.address());
+        }
+    }
+}
+
+/// Runs a single health probe of the configured kind against `address`.
+pub fn probe(address: &str, config: &HealthCheckConfig) -> io::Result<()> {
+    match &config.kind {
+        HealthCheckKind::Tcp => probe_tcp(address, config.timeout),
+        HealthCheckKind::Http(check) => probe_http(address, check, config.timeout),
+    }
+}
+
+/// Checks that a TCP connection to `address` can be opened within `timeout`.
+pub fn probe_tcp(address: &str, timeout: Duration) -> io::Result<()> {
+    connect_timeout(address, timeout).map(drop)
+}
+
+/// Sends the configured HTTP request to `address` and checks the response.
+pub fn probe_http(address: &str, check: &HttpCheck, timeout: Duration) -> io::Result<()> {
+    let mut stream = connect_timeout(address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: load-balancer-health-check\r\nConnection: close\r\n\r\n",
+        check.method,
+        check.path,
+        check.host.as_deref().unwrap_or(address)
+    );
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    (&mut stream)
+        .take(MAX_HTTP_RESPONSE as u64)
+        .read_to_end(&mut response)?;
+
+    let (status, body) = parse_response(&response)?;
+    if !check.expected_status.contains(&status) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} {} returned status {}, expected {}-{}",
+                check.method,
+                check.path,
+                status,
+                check.expected_status.start(),
+                check.expected_status.end()
+            ),
+        ));
+    }
+    if let Some(needle) = &check.body_contains {
+        if !String::from_utf8_lossy(body).contains(needle.as_str()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} {} body does not contain {:?}",
+                    check.method, check.path, needle
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Splits a raw HTTP response into its status code and body.
+fn parse_response(response: &[u8]) -> io::Result<(u16, &[u8])> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_string());
+
+    let head_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| invalid("incomplete HTTP response head"))?;
+    let head = std::str::from_utf8(&response[..head_end])
+        .map_err(|_| invalid("HTTP response head is not UTF-8"))?;
+    let status_line = head.lines().next().unwrap_or_default();
+    let status = status_line
+        .strip_prefix("HTTP/1.")
+        .and_then(|rest| rest.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("malformed HTTP status line"))?;
+
+    let mut body = &response[head_end + 4..];
+    let content_length = head.lines().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            value.trim().parse::<usize>().ok()
+        } else {
+            None
+        }
+    });
+    if let Some(length) = content_length {
+        body = &body[..length.min(body.len())];
+    }
+    Ok((status, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackendServer, LoadBalancer};
+    use std::{net::TcpStream, time::Instant};
+
+    fn wait_for(what: &str, mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_record_applies_rise_and_fall() {
+        let backend = Backend::new("127.0.0.1:1");
+        let config = HealthCheckConfig {
+            rise: 2,
+            fall: 3,
+            ..HealthCheckConfig::default()
+        };
+        let mut history = ProbeHistory::default();
+
+        let fail = || Err(io::Error::other("probe failed"));
+
+        record(&backend, &mut history, fail(), &config);
+        record(&backend, &mut history, fail(), &config);
+        assert!(backend.is_healthy());
+        record(&backend, &mut history, fail(), &config);
+        assert!(!backend.is_healthy());
+
+        record(&backend, &mut history, Ok(()), &config);
+        assert!(!backend.is_healthy());
+        record(&backend, &mut history, fail(), &config);
+        record(&backend, &mut history, Ok(()), &config);
+        record(&backend, &mut history, Ok(()), &config);
+        assert!(backend.is_healthy());
+    }
+
+    #[test]
+    fn test_round_probes_more_backends_than_probe_threads() {
+        let backends: Vec<Arc<Backend>> = (0..MAX_CONCURRENT_PROBES * 3 + 1)
+            .map(|_| Arc::new(Backend::new("127.0.0.1:1")))
+            .collect();
+        let config = HealthCheckConfig {
+            timeout: Duration::from_millis(200),
+            fall: 1,
+            ..HealthCheckConfig::default()
+        };
+        run_round(&backends, &config, &mut HashMap::new());
+        assert!(backends.iter().all(|backend| !backend.is_healthy()));
+    }
+
+    #[test]
+    fn test_frontends_share_one_checker_per_load_balancer() {
+        let balancer: SharedLoadBalancer = Arc::new(Mutex::new(LoadBalancer::new(vec![
+            "127.0.0.1:1".to_string(),
+        ])));
+        let other: SharedLoadBalancer = Arc::new(Mutex::new(LoadBalancer::new(vec![
+            "127.0.0.1:1".to_string(),
+        ])));
+        let first = HealthChecker::shared(&balancer, HealthCheckConfig::default());
+        let second = HealthChecker::shared(&balancer, HealthCheckConfig::default());
+        let third = HealthChecker::shared(&other, HealthCheckConfig::default());
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(!Arc::ptr_eq(&first, &third));
+
+        drop((first, second));
+        let restarted = HealthChecker::shared(&balancer, HealthCheckConfig::default());
+        assert_eq!(Arc::strong_count(&restarted), 1);
+    }
+
+    #[test]
+    fn test_health_checker_ejects_and_restores_backend() {
+        let server = BackendServer::start(0).unwrap();
+        let port = server.port();
+        let balancer: SharedLoadBalancer = Arc::new(Mutex::new(LoadBalancer::new(vec![
+            format!("127.0.0.1:{}", port),
+            "127.0.0.1:1".to_string(),
+        ])));
+        let config = HealthCheckConfig {
+            kind: HealthCheckKind::Tcp,
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(200),
+            rise: 2,
+            fall: 2,
+        };
+        let checker = HealthChecker::spawn(Arc::clone(&balancer), config);
+        let backend = |i: usize| balancer.lock().unwrap().backends()[i].clone();
+
+        wait_for("dead backend to be ejected", || !backend(1).is_healthy());
+        assert!(backend(0).is_healthy());
+        for _ in 0..4 {
+            let chosen = balancer.lock().unwrap().next_backend().map(str::to_string);
+            assert_eq!(chosen, Some(format!("127.0.0.1:{}", port)));
+        }
+
+        server.stop();
+        wait_for("stopped backend to be ejected", || !backend(0).is_healthy());
+        assert_eq!(balancer.lock().unwrap().next_backend(), None);
+
+        let _server = BackendServer::start(port).unwrap();
+        wait_for("restarted backend to recover", || backend(0).is_healthy());
+        checker.stop();
+    }
+
+    #[test]
+    fn test_probe_http_checks_status_and_body() {
+        let server = BackendServer::start(0).unwrap();
+        let address = format!("127.0.0.1:{}", server.port());
+        let timeout = Duration::from_secs(1);
+
+        let check = HttpCheck {
+            body_contains: Some("ok".to_string()),
+            ..HttpCheck::default()
+        };
+        probe_http(&address, &check, timeout).unwrap();
+
+        let wrong_body = HttpCheck {
+            body_contains: Some("ready".to_string()),
+            ..HttpCheck::default()
+        };
+        let err = probe_http(&address, &wrong_body, timeout).unwrap_err();
+        assert!(err.to_string().contains("does not contain"), "{}", err);
+
+        server.set_healthy(false);
+        let err = probe_http(&address, &HttpCheck::default(), timeout).unwrap_err();
+        assert!(err.to_string().contains("status 503"), "{}", err);
+
+        let accept_503 = HttpCheck {
+            expected_status: 200..=503,
+            ..HttpCheck::default()
+        };
+        probe_http(&address, &accept_503, timeout).unwrap();
+    }
+
+    #[test]
+    fn test_parse_response_honours_content_length() {
+        let raw = b"HTTP/1.1 204 No Content\r\nContent-Length: 2\r\n\r\nokEXTRA";
+        let (status, body) = parse_response(raw).unwrap();
+        assert_eq!(status, 204);
+        assert_eq!(body, b"ok");
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+        assert!(parse_response(b"SSH-2.0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_http_health_check_through_proxy() {
+        let healthy = BackendServer::start(0).unwrap();
+        let failing = BackendServer::start(0).unwrap();
+        failing.set_healthy(false);
+
+        let balancer: SharedLoadBalancer = Arc::new(Mutex::new(LoadBalancer::new(vec![
+            format!("127.0.0.1:{}", failing.port()),
+            format!("127.0.0.1:{}", healthy.port()),
+        ])));
+        let config = crate::ProxyConfig {
+            health_check: Some(HealthCheckConfig {
+                kind: HealthCheckKind::Http(HttpCheck::default()),
+                interval: Duration::from_millis(20),
+                timeout: Duration::from_millis(500),
+                rise: 1,
+                fall: 1,
+            }),
+            ..crate::ProxyConfig::default()
+        };
+        let proxy_port = 18108;
+        let proxy_balancer = Arc::clone(&balancer);
+        thread::spawn(move || crate::run_load_balancer_with(proxy_port, proxy_balancer, config));
+
+        let failing_backend = balancer.lock().unwrap().backends()[0].clone();
+        wait_for("failing backend to be ejected", || {
+            !failing_backend.is_healthy()
+        });
+        wait_for("proxy to listen", || {
+            TcpStream::connect(("127.0.0.1", proxy_port)).is_ok()
+        });
+
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(("127.0.0.1", proxy_port)).unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+                .unwrap();
+            client.shutdown(std::net::Shutdown::Write).unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+            assert!(
+                response.contains(&format!("Response from backend on port {}", healthy.port())),
+                "{}",
+                response
+            );
+        }
+    }
+}
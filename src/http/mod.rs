@@ -0,0 +1,185 @@
+//! Layer-7 proxying of HTTP/1.1.
+//!
+//! With [`ProxyConfig::http`](crate::ProxyConfig::http) set, a frontend no
+//! longer copies bytes blindly. It reads each request head from the
+//! client, parses it into a [`Request`], forwards it and its body to the
+//! backend, then does the same with the [`Response`]: one request/response
+//! pair after the other, for as long as both ends keep the connection
+//! alive. Having the parsed heads in hand is what routing on them and
+//! rewriting headers build on.
+//!
+//! The backend is chosen once the head of the first request has been
+//! read, so [`HashKey::Header`](crate::HashKey::Header) and
+//! [`HashKey::Cookie`](crate::HashKey::Cookie) see its header fields.
+//! Later requests on the same connection go to the same backend.
+//!
+//! Heads are read incrementally and may not grow beyond
+//! [`HttpConfig::max_head_size`] bytes or [`HttpConfig::max_headers`]
+//! fields. Bodies are streamed, never buffered whole. They are delimited
+//! by `Content-Length` or the chunked transfer coding, whose framing is
+//! checked as it passes; a response with neither runs until the backend
+//! closes. Requests carrying both, which could be used to smuggle a
+//! request past the proxy, are refused.
+//!
+//! Malformed requests are answered with `400 Bad Request`, or a more
+//! specific status, and the connection is closed. If the backend fails or
+//! sends a malformed response before any of it has reached the client,
+//! the client gets `502 Bad Gateway`, or `504 Gateway Timeout` if a
+//! timeout expired. Clients turned away because the frontend has too many
+//! connections get `503 Service Unavailable`. A successful `Upgrade` or
+//! `CONNECT` turns the rest of the session into a plain byte tunnel.
+//!
+//! A request is forwarded whole before its response is read, so clients
+//! sending `Expect: 100-continue` go ahead with the body once their own
+//! wait for `100 Continue` is over. Each HTTP session is driven by a thread
+//! of its own whatever the [`IoModel`](crate::IoModel), so idle keep-alive
+//! connections do not hold workers; `max_connections` bounds them instead.
+
+use std::fmt;
+
+mod parse;
+mod session;
+
+pub(crate) use session::{error_response, proxy_session, read_first_request, FirstRequest};
+
+/// Limits on what an HTTP frontend accepts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpConfig {
+    /// Largest request or response head, from the start line to the blank
+    /// line that ends it. Also bounds the trailers of chunked bodies.
+    pub max_head_size: usize,
+    /// Most header fields in one head.
+    pub max_headers: usize,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            max_head_size: 16 * 1024,
+            max_headers: 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        })
+    }
+}
+
+/// Header fields in the order they were received. Names keep their
+/// original case and are compared without it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    fields: Vec<(String, String)>,
+}
+
+impl Headers {
+    /// The value of the first field called `name`.
+    pub fn get<'a>(&'a self, name: &'a str) -> Option<&'a str> {
+        self.get_all(name).next()
+    }
+
+    /// The values of every field called `name`.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.fields
+            .iter()
+            .filter(move |(field, _)| field.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the comma-separated values of the `name` fields include
+    /// `token`, such as `close` in `Connection: keep-alive, close`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|value| value.split(','))
+            .any(|item| item.trim().eq_ignore_ascii_case(token))
+    }
+
+    pub fn push(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.fields.push((name.into(), value.into()));
+    }
+
+    /// Removes every field called `name`, returning how many there were.
+    pub fn remove(&mut self, name: &str) -> usize {
+        let before = self.fields.len();
+        self.fields
+            .retain(|(field, _)| !field.eq_ignore_ascii_case(name));
+        before - self.fields.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        for (name, value) in &self.fields {
+            out.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// The request target as sent, usually a path with an optional query.
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers,
+}
+
+impl Request {
+    /// The head as it is sent on the wire, ending with the blank line.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.method, self.target, self.version).into_bytes();
+        self.headers.write_to(&mut out);
+        out
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub version: Version,
+    pub status: u16,
+    pub reason: String,
+    pub headers: Headers,
+}
+
+impl Response {
+    /// The head as it is sent on the wire, ending with the blank line.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{} {} {}\r\n", self.version, self.status, self.reason).into_bytes();
+        self.headers.write_to(&mut out);
+        out
+    }
+}
+
+/// The request of an HTTP session the access log reports on, and the
+/// status it was answered with once known. The method and target are
+/// empty for requests that could not be parsed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Exchange {
+    pub(crate) method: String,
+    pub(crate) target: String,
+    pub(crate) status: Option<u16>,
+}
@@ -0,0 +1,343 @@
+//! Parsing heads and working out how bodies are delimited.
+
+use std::{error::Error, fmt, io};
+
+use super::{Headers, Request, Response, Version};
+use crate::Side;
+
+/// A message that cannot be forwarded, with the status to answer the
+/// client with. Carried inside the [`io::ErrorKind::InvalidData`] errors
+/// it causes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    pub(crate) status: u16,
+    message: String,
+}
+
+impl ParseError {
+    /// A malformed message from `side`. Clients get `400 Bad Request` for
+    /// theirs; a broken response earns the client `502 Bad Gateway`.
+    pub(crate) fn malformed(side: Side, message: impl Into<String>) -> Self {
+        let status = match side {
+            Side::Client => 400,
+            Side::Backend => 502,
+        };
+        ParseError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// A head from `side` over the size limits.
+    pub(crate) fn too_large(side: Side, message: impl Into<String>) -> Self {
+        let status = match side {
+            Side::Client => 431,
+            Side::Backend => 502,
+        };
+        ParseError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    fn with_status(status: u16, message: impl Into<String>) -> Self {
+        ParseError {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// The parse error, if any, that caused `error`.
+    pub(crate) fn of(error: &io::Error) -> Option<&ParseError> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+impl From<ParseError> for io::Error {
+    fn from(error: ParseError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// How the body of a message is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Body {
+    None,
+    Length(u64),
+    Chunked,
+    /// Everything the sender sends until it closes the connection.
+    UntilClose,
+}
+
+/// Parses a request head, given without the blank line that ends it.
+pub(crate) fn parse_request(head: &[u8], max_headers: usize) -> Result<Request, ParseError> {
+    let malformed = |message: &str| ParseError::malformed(Side::Client, message);
+    let head = std::str::from_utf8(head).map_err(|_| malformed("request head is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(malformed("malformed request line"));
+    };
+    if !is_token(method) {
+        return Err(malformed("malformed method"));
+    }
+    if target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(malformed("malformed request target"));
+    }
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        other if other.starts_with("HTTP/") => {
+            return Err(ParseError::with_status(
+                505,
+                format!("unsupported version `{}`", other),
+            ))
+        }
+        _ => return Err(malformed("malformed request line")),
+    };
+    Ok(Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        version,
+        headers: parse_headers(lines, max_headers, Side::Client)?,
+    })
+}
+
+/// Parses a response head, given without the blank line that ends it.
+pub(crate) fn parse_response(head: &[u8], max_headers: usize) -> Result<Response, ParseError> {
+    let malformed = |message: &str| ParseError::malformed(Side::Backend, message);
+    let head = std::str::from_utf8(head).map_err(|_| malformed("response head is not UTF-8"))?;
+    let mut lines = head.split("\r\n");
+    let status_line = lines.next().unwrap_or_default();
+    let (version, rest) = status_line
+        .split_once(' ')
+        .ok_or_else(|| malformed("malformed status line"))?;
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ => return Err(malformed("malformed status line")),
+    };
+    let (code, reason) = rest.split_once(' ').unwrap_or((rest, ""));
+    let status = match code.parse() {
+        Ok(status @ 100..=599) if code.len() == 3 => status,
+        _ => return Err(malformed("malformed status code")),
+    };
+    Ok(Response {
+        version,
+        status,
+        reason: reason.to_string(),
+        headers: parse_headers(lines, max_headers, Side::Backend)?,
+    })
+}
+
+fn parse_headers<'a>(
+    lines: impl Iterator<Item = &'a str>,
+    max_headers: usize,
+    side: Side,
+) -> Result<Headers, ParseError> {
+    let mut headers = Headers::default();
+    for line in lines {
+        if headers.len() == max_headers {
+            return Err(ParseError::too_large(
+                side,
+                format!("more than {} header fields", max_headers),
+            ));
+        }
+        // Folded lines start with whitespace and leave `name` empty.
+        let (name, value) = line
+            .split_once(':')
+            .filter(|(name, _)| is_token(name))
+            .ok_or_else(|| ParseError::malformed(side, "malformed header field"))?;
+        let value = value.trim_matches([' ', '\t']);
+        if value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+            return Err(ParseError::malformed(side, "malformed header field"));
+        }
+        headers.push(name, value);
+    }
+    Ok(headers)
+}
+
+/// How the body of `request` is delimited. Requests without framing
+/// headers have no body.
+pub(crate) fn request_body(request: &Request) -> Result<Body, ParseError> {
+    let chunked = transfer_coding(&request.headers);
+    let length = content_length(&request.headers, Side::Client)?;
+    match (chunked, length) {
+        (Some(_), Some(_)) => Err(ParseError::malformed(
+            Side::Client,
+            "both Transfer-Encoding and Content-Length are set",
+        )),
+        (Some(true), None) => Ok(Body::Chunked),
+        (Some(false), None) => Err(ParseError::with_status(501, "unsupported transfer coding")),
+        (None, None) | (None, Some(0)) => Ok(Body::None),
+        (None, Some(length)) => Ok(Body::Length(length)),
+    }
+}
+
+/// How the body of `response`, answering a request with `method`, is
+/// delimited.
+pub(crate) fn response_body(method: &str, response: &Response) -> Result<Body, ParseError> {
+    if method == "HEAD" || matches!(response.status, 100..=199 | 204 | 304) {
+        return Ok(Body::None);
+    }
+    match transfer_coding(&response.headers) {
+        Some(true) => return Ok(Body::Chunked),
+        Some(false) => return Ok(Body::UntilClose),
+        None => {}
+    }
+    Ok(match content_length(&response.headers, Side::Backend)? {
+        Some(0) => Body::None,
+        Some(length) => Body::Length(length),
+        None => Body::UntilClose,
+    })
+}
+
+/// Whether the connection may carry another message after this one.
+pub(crate) fn keeps_alive(version: Version, headers: &Headers) -> bool {
+    match version {
+        _ if headers.has_token("connection", "close") => false,
+        Version::Http11 => true,
+        Version::Http10 => headers.has_token("connection", "keep-alive"),
+    }
+}
+
+/// Parses the size from the line that starts a chunk, ignoring chunk
+/// extensions.
+pub(crate) fn chunk_size(line: &[u8], side: Side) -> Result<u64, ParseError> {
+    let malformed = || ParseError::malformed(side, "malformed chunk size");
+    let line = std::str::from_utf8(line).map_err(|_| malformed())?;
+    let line = line.strip_suffix("\r\n").unwrap_or(line);
+    let size = line.split(';').next().unwrap_or_default();
+    let size = size.trim_end_matches([' ', '\t']);
+    if size.is_empty() || size.len() > 15 || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(malformed());
+    }
+    u64::from_str_radix(size, 16).map_err(|_| malformed())
+}
+
+/// Whether the last transfer coding is `chunked`, or `None` without a
+/// `Transfer-Encoding` field.
+fn transfer_coding(headers: &Headers) -> Option<bool> {
+    let last = headers
+        .get_all("transfer-encoding")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .last()?;
+    Some(last.eq_ignore_ascii_case("chunked"))
+}
+
+/// The `Content-Length`, which may be repeated as long as every copy
+/// agrees.
+fn content_length(headers: &Headers, side: Side) -> Result<Option<u64>, ParseError> {
+    let mut length = None;
+    for value in headers
+        .get_all("content-length")
+        .flat_map(|value| value.split(','))
+    {
+        let value = value.trim();
+        let parsed = (!value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()))
+            .then(|| value.parse::<u64>().ok())
+            .flatten();
+        match (parsed, length) {
+            (Some(parsed), None) => length = Some(parsed),
+            (Some(parsed), Some(length)) if parsed == length => {}
+            _ => return Err(ParseError::malformed(side, "invalid Content-Length")),
+        }
+    }
+    Ok(length)
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(head: &str) -> Result<Request, ParseError> {
+        parse_request(head.as_bytes(), 100)
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let request =
+            parse("POST /items?id=1 HTTP/1.1\r\nHost: example.com\r\nContent-Length:  5 ").unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.target, "/items?id=1");
+        assert_eq!(request.version, Version::Http11);
+        assert_eq!(request.headers.get("host"), Some("example.com"));
+        assert_eq!(request_body(&request), Ok(Body::Length(5)));
+        assert!(keeps_alive(request.version, &request.headers));
+        assert_eq!(
+            String::from_utf8(request.to_bytes()).unwrap(),
+            "POST /items?id=1 HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\n"
+        );
+
+        for (head, status) in [
+            ("GET /\r\nHost: a", 400),
+            ("GET  / HTTP/1.1", 400),
+            ("GET / HTTP/2.0", 505),
+            ("GET / HTTP/1.1\r\nHost : a", 400),
+            ("GET / HTTP/1.1\r\nX-A: 1\r\n folded", 400),
+            ("POST / HTTP/1.1\r\nContent-Length: 1, 2", 400),
+            ("POST / HTTP/1.1\r\nContent-Length: -1", 400),
+            (
+                "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 3",
+                400,
+            ),
+            ("POST / HTTP/1.1\r\nTransfer-Encoding: gzip", 501),
+        ] {
+            let status_of = |head| match parse(head) {
+                Ok(request) => request_body(&request).map(drop),
+                Err(e) => Err(e),
+            };
+            assert_eq!(status_of(head).unwrap_err().status, status, "{:?}", head);
+        }
+        let many = format!("GET / HTTP/1.1{}", "\r\nX-A: 1".repeat(3));
+        assert_eq!(parse_request(many.as_bytes(), 2).unwrap_err().status, 431);
+    }
+
+    #[test]
+    fn test_response_framing() {
+        let response = |head: &str| parse_response(head.as_bytes(), 100).unwrap();
+        let chunked = response("HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked");
+        assert_eq!(response_body("GET", &chunked), Ok(Body::Chunked));
+        assert_eq!(response_body("HEAD", &chunked), Ok(Body::None));
+        let not_modified = response("HTTP/1.1 304 Not Modified\r\nContent-Length: 10");
+        assert_eq!(response_body("GET", &not_modified), Ok(Body::None));
+        let legacy = response("HTTP/1.0 200");
+        assert_eq!(legacy.reason, "");
+        assert_eq!(response_body("GET", &legacy), Ok(Body::UntilClose));
+        assert!(!keeps_alive(legacy.version, &legacy.headers));
+        let closing = response("HTTP/1.1 200 OK\r\nConnection: Keep-Alive, Close");
+        assert!(!keeps_alive(closing.version, &closing.headers));
+
+        for head in ["HTTP/1.1 20 OK", "HTTP/1.1 OK", "ICY 200 OK"] {
+            let err = parse_response(head.as_bytes(), 100).unwrap_err();
+            assert_eq!(err.status, 502, "{:?}", head);
+        }
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        assert_eq!(chunk_size(b"1a\r\n", Side::Client), Ok(26));
+        assert_eq!(chunk_size(b"0;name=value\r\n", Side::Client), Ok(0));
+        for line in [&b"\r\n"[..], b"x1\r\n", b"-1\r\n", b"1000000000000000\r\n"] {
+            assert!(chunk_size(line, Side::Backend).is_err(), "{:?}", line);
+        }
+    }
+}
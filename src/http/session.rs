@@ -0,0 +1,909 @@
+//! Forwarding one request/response pair after the other.
+
+use std::{
+    cell::Cell,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    rc::Rc,
+    time::Instant,
+};
+
+use super::{
+    parse::{self, Body, ParseError},
+    Exchange, HttpConfig,
+};
+use crate::{
+    debug, logging,
+    proxy::{self, is_timeout, SessionContext, SessionResult, BUFFER_SIZE, TIMEOUT_CHECK},
+    trace, Side, TimeoutKind, Timeouts, Transferred,
+};
+
+/// Longest chunk size line, extensions included.
+const MAX_CHUNK_LINE: usize = 4096;
+
+type Failure = (Side, io::Error);
+
+/// A client whose first request head has been read, or has failed to be,
+/// before a backend is chosen for it.
+pub(crate) struct FirstRequest {
+    config: HttpConfig,
+    client: Conn,
+    clock: Rc<Clock>,
+    head: Result<Option<Vec<u8>>, Failure>,
+}
+
+impl FirstRequest {
+    pub(crate) fn stream(&self) -> &TcpStream {
+        &self.client.stream
+    }
+
+    /// The header fields of the request, or none if it could not be read
+    /// or parsed. A request that failed is answered once the session
+    /// starts.
+    pub(crate) fn headers(&self) -> Vec<(String, String)> {
+        let Ok(Some(head)) = &self.head else {
+            return Vec::new();
+        };
+        match parse::parse_request(head, self.config.max_headers) {
+            Ok(request) => request
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/// Reads the head of the first request from `client`. The client's idle
+/// and session timeouts apply from here on.
+pub(crate) fn read_first_request(
+    client: TcpStream,
+    id: u64,
+    config: &HttpConfig,
+    timeouts: &Timeouts,
+) -> FirstRequest {
+    let clock = Rc::new(Clock::new(timeouts.clone()));
+    let context = SessionContext::new(id, Vec::new());
+    let mut client = Conn::new(client, Side::Client, &clock, &context);
+    let head = client
+        .stream
+        .set_read_timeout(Some(TIMEOUT_CHECK))
+        .map_err(|e| (Side::Client, e))
+        .and_then(|()| client.read_head(config.max_head_size));
+    FirstRequest {
+        config: config.clone(),
+        client,
+        clock,
+        head,
+    }
+}
+
+/// Proxies HTTP/1.1 between the client of `first` and `server` until
+/// either closes the connection, one of them fails or a timeout expires.
+pub(crate) fn proxy_session(
+    first: FirstRequest,
+    server: TcpStream,
+    context: &SessionContext,
+) -> SessionResult {
+    let FirstRequest {
+        config,
+        mut client,
+        clock,
+        head,
+    } = first;
+    client.context = context.clone();
+    server
+        .set_read_timeout(Some(TIMEOUT_CHECK))
+        .map_err(|e| (Side::Backend, e))?;
+    let session = Session {
+        client,
+        server: Conn::new(server, Side::Backend, &clock, context),
+        config: &config,
+        context,
+        clock,
+        first_head: Some(head),
+        recorded: false,
+        forwarding: false,
+        awaiting_response: false,
+        response_started: false,
+    };
+    session.run()
+}
+
+/// What to do once an exchange is over.
+enum Next {
+    KeepAlive,
+    /// Close the connection, as `Side` asked for.
+    Close(Side),
+    /// Tunnel bytes as they come, after a protocol switch.
+    Tunnel,
+}
+
+struct Session<'a> {
+    client: Conn,
+    server: Conn,
+    config: &'a HttpConfig,
+    context: &'a SessionContext,
+    clock: Rc<Clock>,
+    // The head of the first request, read before the backend was chosen.
+    first_head: Option<Result<Option<Vec<u8>>, Failure>>,
+    // Whether the current request has been recorded for the access log.
+    recorded: bool,
+    // Whether a request is on its way to the backend or being answered.
+    forwarding: bool,
+    // Whether the request has been sent in full and awaits its response.
+    awaiting_response: bool,
+    // Whether any of that response has been sent to the client.
+    response_started: bool,
+}
+
+impl Session<'_> {
+    fn run(mut self) -> SessionResult {
+        loop {
+            let next = match self.exchange() {
+                Ok(next) => next,
+                Err(failure) => {
+                    self.answer_failure(&failure);
+                    self.client.shutdown(Shutdown::Both);
+                    self.server.shutdown(Shutdown::Both);
+                    return Err(failure);
+                }
+            };
+            match next {
+                Next::KeepAlive => {}
+                Next::Close(side) => {
+                    self.context.record_finished(side);
+                    self.client.shutdown(Shutdown::Write);
+                    self.server.shutdown(Shutdown::Write);
+                    return Ok(self.transferred());
+                }
+                Next::Tunnel => return self.tunnel(),
+            }
+        }
+    }
+
+    /// Forwards the next request and its response.
+    fn exchange(&mut self) -> Result<Next, Failure> {
+        self.recorded = false;
+        self.response_started = false;
+        if let Some(kind) = self.clock.expired() {
+            return Err((kind.side(), kind.into()));
+        }
+        let max_head = self.config.max_head_size;
+        let max_headers = self.config.max_headers;
+        let head = match self.first_head.take() {
+            Some(head) => head,
+            None => self.client.read_head(max_head),
+        };
+        let Some(head) = head? else {
+            debug!("Client finished sending"; conn = self.context.id);
+            return Ok(Next::Close(Side::Client));
+        };
+        let request = parse::parse_request(&head, max_headers).map_err(client_error)?;
+        self.context.record_request(Exchange {
+            method: request.method.clone(),
+            target: request.target.clone(),
+            status: None,
+        });
+        self.recorded = true;
+        let request_body = parse::request_body(&request).map_err(client_error)?;
+        debug!(
+            "Forwarding request";
+            conn = self.context.id,
+            method = request.method,
+            target = request.target,
+        );
+        let client_keeps_alive = parse::keeps_alive(request.version, &request.headers);
+
+        self.forwarding = true;
+        self.server.send(&request.to_bytes())?;
+        forward_body(&mut self.client, &mut self.server, request_body, max_head)?;
+        self.awaiting_response = true;
+
+        let response = loop {
+            let head = self.server.read_head(max_head)?.ok_or_else(|| {
+                let e = io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "backend closed the connection without responding",
+                );
+                (Side::Backend, e)
+            })?;
+            let response = parse::parse_response(&head, max_headers).map_err(backend_error)?;
+            // Interim responses come ahead of the final one.
+            if response.status >= 200 || response.status == 101 {
+                break response;
+            }
+            self.client.send(&response.to_bytes())?;
+        };
+        let tunnel = response.status == 101
+            || (request.method == "CONNECT" && (200..300).contains(&response.status));
+        let response_body = match tunnel {
+            true => Body::None,
+            false => parse::response_body(&request.method, &response).map_err(backend_error)?,
+        };
+        self.context.record_status(response.status);
+        debug!(
+            "Forwarding response";
+            conn = self.context.id,
+            status = response.status,
+        );
+        self.response_started = true;
+        self.client.send(&response.to_bytes())?;
+        if tunnel {
+            return Ok(Next::Tunnel);
+        }
+        forward_body(&mut self.server, &mut self.client, response_body, max_head)?;
+        self.forwarding = false;
+        self.awaiting_response = false;
+
+        Ok(
+            if response_body == Body::UntilClose
+                || !parse::keeps_alive(response.version, &response.headers)
+            {
+                Next::Close(Side::Backend)
+            } else if !client_keeps_alive {
+                Next::Close(Side::Client)
+            } else {
+                Next::KeepAlive
+            },
+        )
+    }
+
+    /// Tells the client why its request failed, if it can still be told.
+    fn answer_failure(&mut self, (side, error): &Failure) {
+        if self.response_started {
+            return;
+        }
+        let timeout = TimeoutKind::of(error).is_some();
+        let status = match ParseError::of(error) {
+            Some(parse_error) => parse_error.status,
+            None if timeout && self.awaiting_response => 504,
+            None if *side == Side::Backend && self.forwarding => match timeout {
+                true => 504,
+                false => 502,
+            },
+            None => return,
+        };
+        debug!(
+            "Answering with {}", status;
+            conn = self.context.id,
+            error_kind = logging::error_kind(error),
+            error = error,
+        );
+        if self.recorded {
+            self.context.record_status(status);
+        } else {
+            // The request could not be read or parsed.
+            self.context.record_request(Exchange {
+                status: Some(status),
+                ..Exchange::default()
+            });
+        }
+        let _ = self.client.stream.write_all(&error_response(status));
+    }
+
+    /// Hands the connection over to the byte-level proxy once both ends
+    /// have switched protocols. Bytes already read are sent on first.
+    fn tunnel(mut self) -> SessionResult {
+        debug!("Switching to a tunnel"; conn = self.context.id);
+        let from_client = self.client.take_buffered();
+        self.server.send(&from_client)?;
+        let from_server = self.server.take_buffered();
+        self.client.send(&from_server)?;
+        let before = self.transferred();
+        let tunneled = proxy::proxy_session(
+            self.client.stream,
+            self.server.stream,
+            false,
+            self.clock.timeouts(),
+            self.context,
+        )?;
+        Ok(Transferred {
+            client_to_backend: before.client_to_backend + tunneled.client_to_backend,
+            backend_to_client: before.backend_to_client + tunneled.backend_to_client,
+        })
+    }
+
+    fn transferred(&self) -> Transferred {
+        Transferred {
+            client_to_backend: self.server.sent,
+            backend_to_client: self.client.sent,
+        }
+    }
+}
+
+/// Copies a message body from `from` to `to`.
+fn forward_body(
+    from: &mut Conn,
+    to: &mut Conn,
+    body: Body,
+    max_trailers: usize,
+) -> Result<(), Failure> {
+    match body {
+        Body::None => Ok(()),
+        Body::Length(length) => from.copy_exact(length, to),
+        Body::Chunked => loop {
+            let line = from.read_line(MAX_CHUNK_LINE)?;
+            let size = parse::chunk_size(&line, from.side).map_err(|e| (from.side, e.into()))?;
+            to.send(&line)?;
+            if size == 0 {
+                return copy_trailers(from, to, max_trailers);
+            }
+            from.copy_exact(size, to)?;
+            let end = from.read_line(2)?;
+            to.send(&end)?;
+        },
+        Body::UntilClose => from.copy_to_end(to),
+    }
+}
+
+/// Copies the trailer fields of a chunked body, up to and including the
+/// blank line that ends them.
+fn copy_trailers(from: &mut Conn, to: &mut Conn, max_size: usize) -> Result<(), Failure> {
+    let mut size = 0;
+    loop {
+        let line = from.read_line(max_size)?;
+        size += line.len();
+        if size > max_size {
+            let e = ParseError::too_large(from.side, "trailers are too large");
+            return Err((from.side, e.into()));
+        }
+        to.send(&line)?;
+        if line == b"\r\n" {
+            return Ok(());
+        }
+    }
+}
+
+fn client_error(error: ParseError) -> Failure {
+    (Side::Client, error.into())
+}
+
+fn backend_error(error: ParseError) -> Failure {
+    (Side::Backend, error.into())
+}
+
+/// A plain-text response with the given error status, after which the
+/// connection is closed.
+pub(crate) fn error_response(status: u16) -> Vec<u8> {
+    let body = format!("{}\n", reason(status));
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "Error",
+    }
+}
+
+/// When the session last saw traffic and which sides have finished
+/// sending, for telling when the session's timeouts expire.
+struct Clock {
+    timeouts: Timeouts,
+    started: Instant,
+    last: Cell<Instant>,
+    client_finished: Cell<bool>,
+    backend_finished: Cell<bool>,
+}
+
+impl Clock {
+    fn new(timeouts: Timeouts) -> Self {
+        let now = Instant::now();
+        Clock {
+            timeouts,
+            started: now,
+            last: Cell::new(now),
+            client_finished: Cell::new(false),
+            backend_finished: Cell::new(false),
+        }
+    }
+
+    fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    fn record(&self) {
+        self.last.set(Instant::now());
+    }
+
+    fn record_finished(&self, source: Side) {
+        self.record();
+        match source {
+            Side::Client => self.client_finished.set(true),
+            Side::Backend => self.backend_finished.set(true),
+        }
+    }
+
+    fn expired(&self) -> Option<TimeoutKind> {
+        self.timeouts.expired(
+            self.started.elapsed(),
+            self.last.get().elapsed(),
+            !self.client_finished.get(),
+            !self.backend_finished.get(),
+        )
+    }
+}
+
+/// One end of the session, with the bytes read from it but not yet
+/// forwarded.
+struct Conn {
+    stream: TcpStream,
+    side: Side,
+    buffer: Vec<u8>,
+    // Start of the bytes in `buffer` that have not been consumed.
+    start: usize,
+    // Bytes written to this end.
+    sent: u64,
+    clock: Rc<Clock>,
+    context: SessionContext,
+}
+
+impl Conn {
+    fn new(stream: TcpStream, side: Side, clock: &Rc<Clock>, context: &SessionContext) -> Self {
+        Conn {
+            stream,
+            side,
+            buffer: Vec::new(),
+            start: 0,
+            sent: 0,
+            clock: Rc::clone(clock),
+            context: context.clone(),
+        }
+    }
+
+    fn buffered(&self) -> &[u8] {
+        &self.buffer[self.start..]
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.start += n;
+        if self.start == self.buffer.len() {
+            self.buffer.clear();
+            self.start = 0;
+        }
+    }
+
+    fn take_buffered(&mut self) -> Vec<u8> {
+        let bytes = self.buffered().to_vec();
+        self.consume(bytes.len());
+        bytes
+    }
+
+    /// Reads more bytes into the buffer, returning how many or 0 at end of
+    /// stream.
+    fn fill(&mut self) -> Result<usize, Failure> {
+        self.buffer.drain(..self.start);
+        self.start = 0;
+        let len = self.buffer.len();
+        self.buffer.resize(len + BUFFER_SIZE, 0);
+        let result = loop {
+            match self.stream.read(&mut self.buffer[len..]) {
+                Ok(n) => break Ok(n),
+                Err(e) if is_timeout(&e) => {
+                    if let Some(kind) = self.clock.expired() {
+                        debug!("Closing session: {}", kind; conn = self.context.id);
+                        break Err((kind.side(), kind.into()));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err((self.side, e)),
+            }
+        };
+        let n = *result.as_ref().unwrap_or(&0);
+        self.buffer.truncate(len + n);
+        match result {
+            Ok(0) => self.clock.record_finished(self.side),
+            Ok(n) => {
+                trace!("Read {} bytes from {}", n, self.side; conn = self.context.id);
+                self.clock.record();
+            }
+            Err(_) => {}
+        }
+        result
+    }
+
+    /// Reads up to the blank line that ends a head and returns the head
+    /// without it, or `None` if the connection is closed before a head
+    /// starts.
+    fn read_head(&mut self, max_size: usize) -> Result<Option<Vec<u8>>, Failure> {
+        let too_large = |side| {
+            let e = ParseError::too_large(side, format!("head exceeds {} bytes", max_size));
+            Err((side, e.into()))
+        };
+        let mut scanned = 0;
+        loop {
+            // Empty lines ahead of a request are ignored.
+            while self.buffered().starts_with(b"\r\n") {
+                self.consume(2);
+            }
+            if let Some(end) = find(self.buffered(), b"\r\n\r\n", scanned) {
+                if end + 4 > max_size {
+                    return too_large(self.side);
+                }
+                let head = self.buffered()[..end].to_vec();
+                self.consume(end + 4);
+                return Ok(Some(head));
+            }
+            if self.buffered().len() > max_size {
+                return too_large(self.side);
+            }
+            scanned = self.buffered().len();
+            if self.fill()? == 0 {
+                if self.buffered().is_empty() {
+                    return Ok(None);
+                }
+                return Err(self.closed_early());
+            }
+        }
+    }
+
+    /// Reads a line of at most `max_size` bytes, including its CRLF.
+    fn read_line(&mut self, max_size: usize) -> Result<Vec<u8>, Failure> {
+        let mut scanned = 0;
+        loop {
+            if let Some(end) = find(self.buffered(), b"\r\n", scanned) {
+                if end + 2 <= max_size {
+                    let line = self.buffered()[..end + 2].to_vec();
+                    self.consume(end + 2);
+                    return Ok(line);
+                }
+            }
+            if self.buffered().len() >= max_size {
+                let e = ParseError::malformed(self.side, "malformed chunked body");
+                return Err((self.side, e.into()));
+            }
+            scanned = self.buffered().len();
+            if self.fill()? == 0 {
+                return Err(self.closed_early());
+            }
+        }
+    }
+
+    /// Forwards exactly `length` bytes to `to`.
+    fn copy_exact(&mut self, mut length: u64, to: &mut Conn) -> Result<(), Failure> {
+        while length > 0 {
+            if self.buffered().is_empty() && self.fill()? == 0 {
+                return Err(self.closed_early());
+            }
+            let n = self.buffered().len().min(length as usize);
+            to.send(&self.buffered()[..n])?;
+            self.consume(n);
+            length -= n as u64;
+        }
+        Ok(())
+    }
+
+    /// Forwards everything to `to` until this end closes the connection.
+    fn copy_to_end(&mut self, to: &mut Conn) -> Result<(), Failure> {
+        loop {
+            if self.buffered().is_empty() && self.fill()? == 0 {
+                return Ok(());
+            }
+            let n = self.buffered().len();
+            to.send(&self.buffered()[..n])?;
+            self.consume(n);
+        }
+    }
+
+    /// Writes bytes read from the other end to this one.
+    fn send(&mut self, bytes: &[u8]) -> Result<(), Failure> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        self.stream.write_all(bytes).map_err(|e| (self.side, e))?;
+        self.sent += bytes.len() as u64;
+        self.context.record(self.side.peer(), bytes.len());
+        Ok(())
+    }
+
+    fn shutdown(&self, how: Shutdown) {
+        let _ = self.stream.shutdown(how);
+    }
+
+    fn closed_early(&self) -> Failure {
+        let e = io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("{} closed the connection mid-message", self.side),
+        );
+        (self.side, e)
+    }
+}
+
+/// The position of `needle` in `haystack`, searching from a little before
+/// `from` so that a match straddling it is found.
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    let from = from.saturating_sub(needle.len() - 1);
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|i| from + i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        run_load_balancer_with, AccessLog, BackendServer, ConsistentHash, HashKey, LoadBalancer,
+        OverloadPolicy, ProxyConfig, SelectContext,
+    };
+    use std::{
+        net::TcpListener,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    fn start_frontend(port: u16, backend: String, access_log: Option<Arc<AccessLog>>) {
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![backend])));
+        let config = ProxyConfig {
+            http: Some(HttpConfig::default()),
+            access_log,
+            ..ProxyConfig::default()
+        };
+        thread::spawn(move || run_load_balancer_with(port, balancer, config));
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    fn roundtrip(port: u16, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    /// Reads from `stream` until `received` ends with `end`.
+    fn read_until(stream: &mut TcpStream, received: &mut Vec<u8>, end: &[u8]) {
+        let mut buffer = [0; 1024];
+        while !received.ends_with(end) {
+            let n = stream.read(&mut buffer).unwrap();
+            assert!(
+                n > 0,
+                "closed after {:?}",
+                String::from_utf8_lossy(received)
+            );
+            received.extend_from_slice(&buffer[..n]);
+        }
+    }
+
+    #[test]
+    fn test_heads_over_the_limit_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let head = "GET / HTTP/1.1\r\nHost: lb\r\n\r\n";
+        client.write_all(head.repeat(2).as_bytes()).unwrap();
+        let clock = Rc::new(Clock::new(Timeouts::default()));
+        let mut conn = Conn::new(stream, Side::Client, &clock, &SessionContext::default());
+
+        assert!(conn.read_head(head.len()).unwrap().is_some());
+        let (side, e) = conn.read_head(head.len() - 1).unwrap_err();
+        assert_eq!(side, Side::Client);
+        assert_eq!(ParseError::of(&e).unwrap().status, 431);
+    }
+
+    #[test]
+    fn test_requests_are_forwarded_to_the_mock_backend() {
+        let backend = BackendServer::start(0).unwrap();
+        let path = std::env::temp_dir().join(format!("lb-http-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let format = "%{method} %{path} %{status} %{reason}".parse().unwrap();
+        let access_log = Arc::new(AccessLog::open(&path, format).unwrap());
+        start_frontend(
+            18120,
+            format!("127.0.0.1:{}", backend.port()),
+            Some(access_log),
+        );
+
+        let response = roundtrip(18120, b"GET /items?page=2 HTTP/1.1\r\nHost: lb\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with(&format!(
+            "\r\n\r\nResponse from backend on port {}\n",
+            backend.port()
+        )));
+
+        let response = roundtrip(18120, b"GET / HTTP/1.1\r\nBad Header: x\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        thread::sleep(Duration::from_millis(100));
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "GET /items?page=2 200 backend_close\n- - 400 error\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keep_alive_with_chunked_bodies() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let backend = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            read_until(&mut stream, &mut received, b"X-Checksum: 1\r\n\r\n");
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n",
+                )
+                .unwrap();
+            read_until(
+                &mut stream,
+                &mut received,
+                b"/second HTTP/1.1\r\nHost: lb\r\n\r\n",
+            );
+            stream
+                .write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\nConnection: close\r\n\r\nnope",
+                )
+                .unwrap();
+            received
+        });
+        start_frontend(18121, address, None);
+
+        // Both requests are sent at once; the second waits in the proxy
+        // until the first has been answered.
+        let requests = "POST /upload HTTP/1.1\r\nHost: lb\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        5;ext=1\r\nhello\r\n0\r\nX-Checksum: 1\r\n\r\n\
+                        GET /second HTTP/1.1\r\nHost: lb\r\n\r\n";
+        let response = roundtrip(18121, requests.as_bytes());
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+             HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\nConnection: close\r\n\r\nnope"
+        );
+        assert_eq!(backend.join().unwrap(), requests.as_bytes());
+    }
+
+    #[test]
+    fn test_backend_is_chosen_by_request_headers() {
+        let backends = [
+            BackendServer::start(0).unwrap(),
+            BackendServer::start(0).unwrap(),
+        ];
+        let addresses: Vec<_> = backends
+            .iter()
+            .map(|backend| format!("127.0.0.1:{}", backend.port()))
+            .collect();
+        let by_user = || ConsistentHash::new(HashKey::Header("X-User".to_string()));
+        let mut expected = LoadBalancer::with_strategy(addresses.clone(), Box::new(by_user()));
+        let balancer = Arc::new(Mutex::new(LoadBalancer::with_strategy(
+            addresses,
+            Box::new(by_user()),
+        )));
+        let config = ProxyConfig {
+            http: Some(HttpConfig::default()),
+            ..ProxyConfig::default()
+        };
+        thread::spawn(move || run_load_balancer_with(18123, balancer, config));
+        thread::sleep(Duration::from_millis(100));
+
+        let mut used = Vec::new();
+        for user in (0..16).map(|i| format!("user-{}", i)) {
+            let mut ctx = SelectContext::default();
+            ctx.headers.push(("x-user".to_string(), user.clone()));
+            let backend = expected.select(&ctx).unwrap();
+            let request = format!("GET / HTTP/1.1\r\nX-User: {}\r\n\r\n", user);
+            let response = roundtrip(18123, request.as_bytes());
+            let port = backend.address().rsplit(':').next().unwrap();
+            assert!(
+                response.ends_with(&format!("Response from backend on port {}\n", port)),
+                "{}: {}",
+                user,
+                response
+            );
+            used.push(port.to_string());
+        }
+        used.sort();
+        used.dedup();
+        assert_eq!(used.len(), 2);
+    }
+
+    #[test]
+    fn test_connections_over_limit_get_service_unavailable() {
+        let backend = BackendServer::start(0).unwrap();
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![format!(
+            "127.0.0.1:{}",
+            backend.port()
+        )])));
+        let config = ProxyConfig {
+            http: Some(HttpConfig::default()),
+            max_connections: 1,
+            overload: OverloadPolicy::Reject,
+            ..ProxyConfig::default()
+        };
+        thread::spawn(move || run_load_balancer_with(18124, balancer, config));
+        thread::sleep(Duration::from_millis(100));
+
+        // Holds the only permit while its request head is incomplete.
+        let mut first = TcpStream::connect(("127.0.0.1", 18124)).unwrap();
+        first.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let mut second = TcpStream::connect(("127.0.0.1", 18124)).unwrap();
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.contains("\r\nConnection: close\r\n"));
+
+        first.write_all(b"\r\n").unwrap();
+        let mut response = String::new();
+        first.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn test_open_sessions_do_not_hold_workers() {
+        let backend = BackendServer::start(0).unwrap();
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![format!(
+            "127.0.0.1:{}",
+            backend.port()
+        )])));
+        let config = ProxyConfig {
+            http: Some(HttpConfig::default()),
+            workers: 1,
+            overload: OverloadPolicy::Reject,
+            ..ProxyConfig::default()
+        };
+        thread::spawn(move || run_load_balancer_with(18127, balancer, config));
+        thread::sleep(Duration::from_millis(100));
+
+        // Stays open with its request head incomplete.
+        let mut first = TcpStream::connect(("127.0.0.1", 18127)).unwrap();
+        first.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        for _ in 0..3 {
+            let response = roundtrip(18127, b"GET / HTTP/1.1\r\nHost: lb\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        }
+        first.write_all(b"\r\n").unwrap();
+        let mut response = String::new();
+        first.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[test]
+    fn test_backend_failures_are_answered_with_bad_gateway() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut received = Vec::new();
+                read_until(&mut stream, &mut received, b"\r\n\r\n");
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n");
+            }
+        });
+        start_frontend(18122, address, None);
+
+        let response = roundtrip(18122, b"GET / HTTP/1.1\r\nHost: lb\r\n\r\n");
+        assert!(
+            response.starts_with("HTTP/1.1 502 Bad Gateway\r\n"),
+            "{}",
+            response
+        );
+    }
+}
@@ -1,154 +1,869 @@
 use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
+    io::Write,
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
+pub mod access_log;
+pub mod admin;
+pub mod admission;
+mod backend;
+pub mod config;
+pub mod connect;
+mod error;
+#[cfg(target_os = "linux")]
+mod event_loop;
+pub mod health;
+pub mod http;
+pub mod logging;
+pub mod metrics;
+mod mock;
+pub mod outlier;
+mod pool;
+mod proxy;
+mod registry;
+pub mod shutdown;
+#[cfg(unix)]
+pub mod signals;
+pub mod strategy;
+#[cfg(target_os = "linux")]
+mod sys;
+
+pub use access_log::{AccessLog, AccessLogFormat};
+use access_log::{AccessRecord, Termination};
+use admission::{Admission, Admit, Permit};
+pub use admission::{ConnectionStats, OverloadPolicy};
+pub use backend::{Backend, BackendState, ConnectionGuard, DrainProgress};
+pub use config::{Config, ConfigError};
+pub use connect::{connect_with_retry, BackendConnection, RetryPolicy};
+pub use error::ProxyError;
+pub use health::{HealthCheckConfig, HealthCheckKind, HealthChecker, HttpCheck};
+pub use http::HttpConfig;
+use metrics::FrontendMetrics;
+pub use mock::{run_backend, BackendServer};
+pub use outlier::{Outcome, OutlierConfig};
+use pool::WorkerPool;
+pub use proxy::{byte_counters, ByteCounters, Side, TimeoutKind, TrafficCounters, Transferred};
+use proxy::{proxy_session, SessionContext};
+use registry::SessionRegistry;
+pub use shutdown::ShutdownHandle;
+pub use strategy::{
+    BalancingStrategy, ConsistentHash, HashKey, LeastConnections, Maglev, PowerOfTwoChoices,
+    RoundRobin, SelectContext, WeightedRoundRobin,
+};
+
+/// A load balancer shared between the accept loop, connection handlers and
+/// background tasks such as health checking.
+pub type SharedLoadBalancer = Arc<Mutex<LoadBalancer>>;
+
+/// Source of the ids that tell client connections apart in logs.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Optional behaviour of a running load balancer.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Identifies the frontend in metrics and logs. Defaults to the
+    /// address it listens on.
+    pub name: Option<String>,
+    /// Probe backends in the background and skip those that fail. Disabled
+    /// when `None`. Frontends sharing a load balancer share one checker,
+    /// configured by whichever started first.
+    pub health_check: Option<HealthCheckConfig>,
+    /// Eject backends whose sessions keep failing. Disabled when `None`.
+    pub outlier_detection: Option<OutlierConfig>,
+    /// Parse and forward HTTP/1.1 requests and responses instead of raw
+    /// bytes. Plain TCP when `None`. See [`http`].
+    pub http: Option<HttpConfig>,
+    /// How connect failures are retried on other backends.
+    pub retry: RetryPolicy,
+    pub timeouts: Timeouts,
+    pub io_model: IoModel,
+    /// Forward bytes with `splice(2)` instead of copying them through user
+    /// space. Only has an effect on Linux.
+    pub zero_copy: bool,
+    /// Threads that connect accepted clients to backends. In the threaded
+    /// I/O model each session also keeps its worker until it closes, so
+    /// this bounds the number of concurrent sessions too. HTTP sessions run
+    /// on threads of their own instead.
+    pub workers: usize,
+    /// Connections handled at once, from acceptance until the session
+    /// closes. Capped at `workers` where sessions keep their worker, so
+    /// that connections over the limit are queued or rejected by the
+    /// `overload` policy instead of waiting for a worker.
+    pub max_connections: usize,
+    /// What to do with connections accepted over `max_connections`.
+    pub overload: OverloadPolicy,
+    /// Stops the frontend once triggered.
+    pub shutdown: ShutdownHandle,
+    /// Where to write a line for every client connection once it is over.
+    /// Several frontends may share one. Disabled when `None`.
+    pub access_log: Option<Arc<AccessLog>>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            name: None,
+            health_check: None,
+            outlier_detection: None,
+            http: None,
+            retry: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            io_model: IoModel::default(),
+            zero_copy: true,
+            workers: 128,
+            max_connections: 10_000,
+            overload: OverloadPolicy::default(),
+            shutdown: ShutdownHandle::default(),
+            access_log: None,
+        }
+    }
+}
+
+/// Limits on how long connecting and proxying may take. Expired session
+/// timeouts are noticed within half a second.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timeouts {
+    /// How long each attempt to connect to a backend may take.
+    pub connect: Duration,
+    /// How long a session may go without traffic in either direction
+    /// while the client has not finished sending. Fails the session with
+    /// [`TimeoutKind::ClientIdle`].
+    pub client_idle: Duration,
+    /// How long a session may go without traffic in either direction
+    /// while the backend has not finished sending. Fails the session with
+    /// [`TimeoutKind::BackendIdle`].
+    pub backend_idle: Duration,
+    /// Lifetime after which a session is closed even if it is busy.
+    /// Unlimited when `None`.
+    pub max_session: Option<Duration>,
+    /// How long sessions may take to finish once a shutdown has been
+    /// requested, after which they are closed.
+    pub shutdown_grace: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            connect: Duration::from_secs(3),
+            client_idle: Duration::from_secs(60),
+            backend_idle: Duration::from_secs(60),
+            max_session: None,
+            shutdown_grace: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Timeouts {
+    /// Which timeout, if any, has expired for a session of the given age
+    /// that has been quiet in both directions for `quiet`. Only a side that
+    /// has not finished sending is held to its idle timeout, so a session
+    /// busy in one direction never idles.
+    pub(crate) fn expired(
+        &self,
+        age: Duration,
+        quiet: Duration,
+        client_sending: bool,
+        backend_sending: bool,
+    ) -> Option<TimeoutKind> {
+        if self.max_session.is_some_and(|max| age >= max) {
+            Some(TimeoutKind::MaxSession)
+        } else if client_sending && quiet >= self.client_idle {
+            Some(TimeoutKind::ClientIdle)
+        } else if backend_sending && quiet >= self.backend_idle {
+            Some(TimeoutKind::BackendIdle)
+        } else {
+            None
+        }
+    }
+}
+
+/// How established sessions are driven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoModel {
+    /// Two blocking threads per session, one for each direction.
+    Threaded,
+    /// All sessions are multiplexed on one epoll thread. Only available on
+    /// Linux; elsewhere this falls back to `Threaded`.
+    EventLoop,
+}
+
+impl Default for IoModel {
+    fn default() -> Self {
+        if cfg!(target_os = "linux") {
+            IoModel::EventLoop
+        } else {
+            IoModel::Threaded
+        }
+    }
+}
+
+/// Addresses of the backends affected by
+/// [`LoadBalancer::replace_backends`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackendChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Backends that stayed but got a new weight or new tags.
+    pub updated: Vec<String>,
+}
+
+impl BackendChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.updated.is_empty()
+    }
+}
+
 pub struct LoadBalancer {
-    backends: Vec<String>,
-    current: usize,
+    backends: Vec<Arc<Backend>>,
+    strategy: Box<dyn BalancingStrategy>,
+    outlier_detection: Option<OutlierConfig>,
 }
 
 impl LoadBalancer {
     pub fn new(backends: Vec<String>) -> Self {
-        LoadBalancer {
-            backends,
-            current: 0,
-        }
+        Self::with_strategy(backends, Box::new(RoundRobin::new()))
     }
 
-    pub fn next_backend(&mut self) -> &str {
-        let backend = &self.backends[self.current];
-        self.current = (self.current + 1) % self.backends.len();
-        backend
+    pub fn with_strategy(backends: Vec<String>, strategy: Box<dyn BalancingStrategy>) -> Self {
+        Self::from_backends(backends.into_iter().map(Backend::new).collect(), strategy)
     }
-}
 
-pub fn handle_client(mut client: TcpStream, backend: &str) -> Result<(), std::io::Error> {
-    println!(
-        "Handling client request, forwarding to backend: {}",
-        backend
-    );
-    let mut server = TcpStream::connect(backend)?;
-    println!("Connected to backend server");
+    pub fn from_backends(backends: Vec<Backend>, strategy: Box<dyn BalancingStrategy>) -> Self {
+        let mut load_balancer = LoadBalancer {
+            backends: backends.into_iter().map(Arc::new).collect(),
+            strategy,
+            outlier_detection: None,
+        };
+        load_balancer
+            .strategy
+            .backends_changed(&load_balancer.backends);
+        load_balancer
+    }
 
-    client.set_read_timeout(Some(Duration::from_secs(5)))?;
-    server.set_read_timeout(Some(Duration::from_secs(5)))?;
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
 
-    let mut buffer = [0; 1024];
+    pub fn backend(&self, address: &str) -> Option<&Arc<Backend>> {
+        self.backends.iter().find(|b| b.address() == address)
+    }
 
-    // Read the request from the client and forward it to the backend
-    loop {
-        match client.read(&mut buffer) {
-            Ok(0) => {
-                println!("Client closed the connection before sending data");
-                break;
-            }
-            Ok(n) => {
-                println!("Read {} bytes from client", n);
-                if let Err(e) = server.write_all(&buffer[..n]) {
-                    println!("Error writing to backend server: {}", e);
-                    return Err(e);
+    /// Swaps in a new backend list. Backends whose address is already
+    /// known are kept, with their health, ejection and connection state,
+    /// and only take the new weight and tags. Sessions on removed backends
+    /// carry on until they close.
+    pub fn replace_backends(&mut self, backends: Vec<Backend>) -> BackendChanges {
+        let mut changes = BackendChanges::default();
+        let backends: Vec<Arc<Backend>> = backends
+            .into_iter()
+            .map(|new| match self.backend(new.address()) {
+                Some(existing) => {
+                    if existing.weight() != new.weight() || existing.tags() != new.tags() {
+                        existing.set_weight(new.weight());
+                        existing.set_tags(new.tags());
+                        changes.updated.push(new.address().to_string());
+                    }
+                    Arc::clone(existing)
                 }
-                server.flush()?;
-                println!("Wrote {} bytes to backend server", n);
-            }
-            Err(e) => {
-                println!("Error reading from client: {}", e);
-                return Err(e);
-            }
+                None => {
+                    changes.added.push(new.address().to_string());
+                    Arc::new(new)
+                }
+            })
+            .collect();
+        changes.removed = self
+            .backends
+            .iter()
+            .filter(|old| !backends.iter().any(|b| b.address() == old.address()))
+            .map(|old| old.address().to_string())
+            .collect();
+
+        self.backends = backends;
+        self.strategy.backends_changed(&self.backends);
+        changes
+    }
+
+    /// Adds a backend, returning `false` if one with the same address is
+    /// already in the pool.
+    pub fn add_backend(&mut self, backend: Backend) -> bool {
+        if self.backend(backend.address()).is_some() {
+            return false;
         }
+        self.backends.push(Arc::new(backend));
+        self.strategy.backends_changed(&self.backends);
+        true
+    }
 
-        // Read the response from the backend and send it to the client
-        match server.read(&mut buffer) {
-            Ok(0) => {
-                println!("Backend closed the connection without sending data");
-                break;
-            }
-            Ok(n) => {
-                println!("Read {} bytes from backend", n);
-                if let Err(e) = client.write_all(&buffer[..n]) {
-                    println!("Error writing to client: {}", e);
-                    return Err(e);
-                }
-                client.flush()?;
-                println!("Wrote {} bytes back to client", n);
-            }
-            Err(e) => {
-                println!("Error reading from backend: {}", e);
-                return Err(e);
+    /// Removes the backend with the given address. Its sessions carry on
+    /// until they close.
+    pub fn remove_backend(&mut self, address: &str) -> Option<Arc<Backend>> {
+        let index = self.backends.iter().position(|b| b.address() == address)?;
+        let backend = self.backends.remove(index);
+        self.strategy.backends_changed(&self.backends);
+        Some(backend)
+    }
+
+    /// Drains the backend with the given address: it gets no new
+    /// connections, and its sessions are closed once `timeout` elapses.
+    /// Returns `false` if no such backend exists.
+    pub fn drain(&self, address: &str, timeout: Option<Duration>) -> bool {
+        match self.backend(address) {
+            Some(backend) => {
+                backend.drain(timeout);
+                true
             }
+            None => false,
         }
     }
 
-    Ok(())
-}
+    /// Changes the weight of the backend with the given address, returning
+    /// `false` if no such backend exists.
+    pub fn set_weight(&mut self, address: &str, weight: u32) -> bool {
+        match self.backend(address) {
+            Some(backend) => {
+                backend.set_weight(weight);
+                self.strategy.backends_changed(&self.backends);
+                true
+            }
+            None => false,
+        }
+    }
 
-pub fn run_backend(port: u16) -> Result<(), std::io::Error> {
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
-    println!("Backend server listening on 127.0.0.1:{}", port);
+    pub fn strategy_name(&self) -> &'static str {
+        self.strategy.name()
+    }
 
-    for stream in listener.incoming() {
-        let mut stream = stream?;
-        println!("Backend on port {} received a connection", port);
+    pub fn set_strategy(&mut self, strategy: Box<dyn BalancingStrategy>) {
+        self.strategy = strategy;
+        self.strategy.backends_changed(&self.backends);
+    }
 
-        // Send a valid HTTP response with headers and body
-        let body = format!("Response from backend on port {}\n", port);
-        let response = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nContent-Type: text/plain\r\n\r\n{}",
-            body.len(),
-            body
-        );
+    pub fn set_outlier_detection(&mut self, config: Option<OutlierConfig>) {
+        self.outlier_detection = config;
+    }
 
-        match stream.write_all(response.as_bytes()) {
-            Ok(_) => {
-                stream.flush()?;
-                println!("Backend on port {} sent response", port);
-            }
-            Err(e) => println!("Backend on port {} error sending response: {}", port, e),
+    /// Feeds the outcome of a proxied session back into outlier detection.
+    /// Does nothing unless outlier detection is enabled.
+    pub fn report(&self, backend: &Backend, outcome: Outcome) {
+        if let Some(config) = &self.outlier_detection {
+            outlier::record(config, backend, &self.backends, outcome);
         }
+    }
 
-        // Ensure the response is sent before closing the connection
-        stream.flush()?;
+    pub fn select(&mut self, ctx: &SelectContext) -> Option<Arc<Backend>> {
+        let index = self.strategy.select(&self.backends, ctx)?;
+        self.backends.get(index).cloned()
+    }
+
+    pub fn next_backend(&mut self) -> Option<&str> {
+        let index = self
+            .strategy
+            .select(&self.backends, &SelectContext::default())?;
+        self.backends.get(index).map(|backend| backend.address())
     }
-    Ok(())
 }
 
-pub fn run_load_balancer(port: u16, backend_ports: Vec<u16>) -> Result<(), std::io::Error> {
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
-    let mut load_balancer = LoadBalancer::new(
+pub fn handle_client(client: TcpStream, backend: &str) -> Result<(), ProxyError> {
+    debug!("Handling client request"; backend = backend);
+    let server = TcpStream::connect(backend)
+        .map_err(|e| ProxyError::connect(vec![backend.to_string()], e))?;
+    debug!("Connected to backend server"; backend = backend);
+    proxy_session(
+        client,
+        server,
+        true,
+        &Timeouts::default(),
+        &SessionContext::default(),
+    )
+    .map(drop)
+    .map_err(|(side, e)| ProxyError::session(side, backend, e))
+}
+
+pub fn run_load_balancer(port: u16, backend_ports: Vec<u16>) -> Result<(), ProxyError> {
+    let load_balancer = LoadBalancer::new(
         backend_ports
             .iter()
             .map(|p| format!("127.0.0.1:{}", p))
             .collect(),
     );
+    run_load_balancer_with(
+        port,
+        Arc::new(Mutex::new(load_balancer)),
+        ProxyConfig::default(),
+    )
+}
+
+/// Runs a load balancer on 127.0.0.1 at `port` until `config.shutdown` is
+/// triggered.
+pub fn run_load_balancer_with(
+    port: u16,
+    load_balancer: SharedLoadBalancer,
+    config: ProxyConfig,
+) -> Result<(), ProxyError> {
+    run_frontend(
+        SocketAddr::from(([127, 0, 0, 1], port)),
+        load_balancer,
+        config,
+    )
+}
+
+/// Accepts clients on `address` and proxies them to the backends of
+/// `load_balancer`. Several frontends may share one load balancer.
+///
+/// The frontend's counters are available from [`metrics`] under
+/// `config.name`, and the backends of `load_balancer` are reported as a
+/// pool of the same name unless the load balancer was registered with
+/// [`metrics::register_pool`] already.
+///
+/// Runs until `config.shutdown` is triggered. The listener is then closed
+/// and sessions get `config.timeouts.shutdown_grace` to finish before the
+/// remaining ones are closed; the function returns once all are gone.
+/// Errors of individual sessions are logged and counted in the metrics;
+/// only failing to start is returned, as [`ProxyError::Start`].
+pub fn run_frontend(
+    address: SocketAddr,
+    load_balancer: SharedLoadBalancer,
+    config: ProxyConfig,
+) -> Result<(), ProxyError> {
+    let start_error = |source| ProxyError::Start { address, source };
+    let listener = TcpListener::bind(address).map_err(start_error)?;
+    let address = listener.local_addr().map_err(start_error)?;
+    let name = config.name.unwrap_or_else(|| address.to_string());
+
+    info!(
+        "Load balancer listening";
+        frontend = name,
+        address = address,
+        strategy = load_balancer.lock().unwrap().strategy_name(),
+    );
+
+    let sessions = SessionDriver::new(config.io_model, config.zero_copy, &config.timeouts)
+        .map_err(start_error)?;
+    let _health_checker = config
+        .health_check
+        .map(|health_check| HealthChecker::shared(&load_balancer, health_check));
+    if config.outlier_detection.is_some() {
+        load_balancer
+            .lock()
+            .unwrap()
+            .set_outlier_detection(config.outlier_detection);
+    }
+
+    let max_connections = match config.http.is_none() && sessions.keeps_worker() {
+        true => config.max_connections.min(config.workers),
+        false => config.max_connections,
+    };
+    if max_connections < config.max_connections {
+        info!(
+            "Limiting connections to the number of workers";
+            frontend = name,
+            max_connections = max_connections,
+        );
+    }
 
-    println!("Load balancer listening on 127.0.0.1:{}", port);
+    metrics::register_pool(&name, &load_balancer);
+    let registration = metrics::register_frontend(name);
+    let admission = Arc::new(Admission::new(
+        max_connections,
+        config.overload,
+        Arc::clone(registration.0.connections()),
+    ));
+    // Every queued job holds a permit or a place in the overload queue, so
+    // the worker queue never fills up before admission rejects a client.
+    let queue_capacity = max_connections.saturating_add(match config.overload {
+        OverloadPolicy::Queue { capacity, .. } => capacity,
+        OverloadPolicy::Reject => 0,
+    });
+    let workers = WorkerPool::new(config.workers, queue_capacity).map_err(start_error)?;
+    let frontend = Arc::new(Frontend {
+        load_balancer,
+        retry: config.retry,
+        timeouts: config.timeouts.clone(),
+        http: config.http,
+        sessions,
+        registry: Arc::default(),
+        metrics: Arc::clone(&registration.0),
+        access_log: config.access_log,
+    });
 
-    for stream in listener.incoming() {
+    config.shutdown.register(address);
+    // Checked before accepting too: a shutdown triggered before the
+    // listener was registered does not wake it.
+    while !config.shutdown.is_triggered() {
+        let stream = listener.accept().map(|(stream, _)| stream);
+        if config.shutdown.is_triggered() {
+            break;
+        }
         match stream {
             Ok(stream) => {
-                let backend = load_balancer.next_backend().to_string();
-                println!("New connection, forwarding to {}", backend);
-                let backend_clone = backend.clone();
-                thread::spawn(move || {
-                    if let Err(e) = handle_client(stream, &backend_clone) {
-                        eprintln!("Error handling client: {}", e);
+                let accepted = Instant::now();
+                let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+                let permit = match admission.admit() {
+                    Admit::Now(permit) => Some(permit),
+                    Admit::Queued => None,
+                    Admit::Rejected => {
+                        reject(stream, id, &frontend);
+                        continue;
+                    }
+                };
+                let admission = Arc::clone(&admission);
+                let frontend = Arc::clone(&frontend);
+                let job = Box::new(move || {
+                    let permit = match permit.or_else(|| admission.wait(accepted)) {
+                        Some(permit) => permit,
+                        None => return reject(stream, id, &frontend),
+                    };
+                    if frontend.http.is_none() {
+                        return serve(stream, id, accepted, permit, &frontend);
+                    }
+                    // HTTP sessions block until they close, so they get a
+                    // thread of their own rather than keeping the worker.
+                    let spawned = thread::Builder::new()
+                        .name(format!("http-session-{}", id))
+                        .spawn(move || serve(stream, id, accepted, permit, &frontend));
+                    if let Err(e) = spawned {
+                        warn!(
+                            "Dropping client connection: cannot start session thread";
+                            conn = id,
+                            error = e,
+                        );
                     }
                 });
+                if workers.try_execute(job).is_err() {
+                    warn!("Dropping client connection: worker queue is full"; conn = id);
+                }
             }
             Err(e) => {
-                eprintln!("Error accepting connection: {}", e);
+                error!(
+                    "Error accepting connection";
+                    frontend = frontend.metrics.name(),
+                    error_kind = logging::error_kind(&e),
+                    error = e,
+                );
             }
         }
     }
+
+    config.shutdown.unregister(address);
+    drop(listener);
+    info!("Load balancer shutting down"; frontend = frontend.metrics.name());
+    admission.close();
+    let grace = config.timeouts.shutdown_grace;
+    let deadline = Instant::now() + grace;
+    let drained = admission.wait_idle(grace);
+    let closed = frontend.registry.close();
+    if !drained {
+        warn!(
+            "Closed {} sessions still open after {:?}", closed, grace;
+            frontend = frontend.metrics.name(),
+        );
+    }
+    // Joins the workers, which finish any connects still under way.
+    drop(workers);
+    admission.wait_idle(deadline.saturating_duration_since(Instant::now()));
+    info!("Load balancer stopped"; frontend = frontend.metrics.name());
+    drop(registration);
     Ok(())
 }
 
+/// Closes a connection turned away by admission control, answering with
+/// `503 Service Unavailable` in HTTP mode.
+fn reject(mut stream: TcpStream, id: u64, frontend: &Frontend) {
+    warn!(
+        "Rejecting connection: too many connections";
+        conn = id,
+        client = peer(&stream),
+    );
+    if frontend.http.is_some() {
+        let _ = stream.write_all(&http::error_response(503));
+    }
+}
+
+/// The address of the peer of `stream`, or `-` if it is not known.
+fn peer(stream: &TcpStream) -> String {
+    stream
+        .peer_addr()
+        .map_or_else(|_| "-".to_string(), |addr| addr.to_string())
+}
+
+/// What the workers of a frontend share for serving its clients.
+struct Frontend {
+    load_balancer: SharedLoadBalancer,
+    retry: RetryPolicy,
+    timeouts: Timeouts,
+    http: Option<HttpConfig>,
+    sessions: SessionDriver,
+    registry: Arc<SessionRegistry>,
+    metrics: Arc<FrontendMetrics>,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+/// An accepted client. In HTTP mode the head of its first request has
+/// been read so that the backend can be chosen by its headers.
+enum Client {
+    Tcp(TcpStream),
+    Http(http::FirstRequest),
+}
+
+impl Client {
+    fn stream(&self) -> &TcpStream {
+        match self {
+            Client::Tcp(stream) => stream,
+            Client::Http(first) => first.stream(),
+        }
+    }
+}
+
+/// Connects an admitted client to a backend and hands the session to the
+/// session driver. `permit` is released when the session closes.
+fn serve(stream: TcpStream, id: u64, accepted: Instant, permit: Permit, frontend: &Frontend) {
+    let start = SystemTime::now()
+        .checked_sub(accepted.elapsed())
+        .unwrap_or_else(SystemTime::now);
+    let client = peer(&stream);
+    let client_addr = stream.peer_addr().ok();
+    let mut ctx = match client_addr {
+        Some(addr) => SelectContext::for_client(addr),
+        None => SelectContext::default(),
+    };
+    let stream = match &frontend.http {
+        Some(http) => {
+            let first = http::read_first_request(stream, id, http, &frontend.timeouts);
+            ctx.headers = first.headers();
+            Client::Http(first)
+        }
+        None => Client::Tcp(stream),
+    };
+    let connection = match connect_with_retry(
+        &frontend.load_balancer,
+        &ctx,
+        &frontend.retry,
+        frontend.timeouts.connect,
+    ) {
+        Ok(connection) => connection,
+        Err(e) => {
+            frontend.metrics.record_failed();
+            frontend.metrics.record_error(&e);
+            warn!(
+                "Dropping client connection: no backend could be connected";
+                conn = id,
+                client = client,
+                error_kind = e.label(),
+                error = e,
+            );
+            if let Some(access_log) = &frontend.access_log {
+                access_log.write(&AccessRecord {
+                    conn: id,
+                    frontend: frontend.metrics.name(),
+                    client: client_addr,
+                    backend: None,
+                    start,
+                    duration: accepted.elapsed(),
+                    transferred: Transferred::default(),
+                    termination: Termination::of(Some(&e), None),
+                    error: Some(e.label()),
+                    request: None,
+                });
+            }
+            return;
+        }
+    };
+    debug!(
+        "New connection";
+        conn = id,
+        client = client,
+        backend = connection.backend().address(),
+        active = connection.backend().active_connections(),
+    );
+    if connection.tried.len() > 1 {
+        info!(
+            "Connected after trying {}", connection.tried.join(", ");
+            conn = id,
+            backend = connection.backend().address(),
+        );
+    }
+    let BackendConnection {
+        stream: server,
+        mut guard,
+        ..
+    } = connection;
+    let tracked = frontend.registry.track(stream.stream(), &server);
+    if tracked.is_none() && frontend.registry.is_closed() {
+        info!("Dropping client connection: shutting down"; conn = id, client = client);
+        return;
+    }
+    guard.track(stream.stream(), &server);
+    let load_balancer = Arc::clone(&frontend.load_balancer);
+    let metrics = Arc::clone(&frontend.metrics);
+    let access_log = frontend.access_log.clone();
+    let traffic = Arc::new(TrafficCounters::default());
+    let context = SessionContext::new(
+        id,
+        vec![
+            Arc::clone(&traffic),
+            Arc::clone(metrics.traffic()),
+            Arc::clone(guard.backend().metrics().traffic()),
+        ],
+    );
+    let session = context.clone();
+    let started = Instant::now();
+    let on_close: Box<dyn FnOnce(proxy::SessionResult) + Send> = Box::new(move |result| {
+        let duration = started.elapsed();
+        metrics.session_duration().observe(duration);
+        let backend = guard.backend();
+        let transferred = traffic.get();
+        let result = result.map_err(|(side, e)| ProxyError::session(side, backend.address(), e));
+        if let Some(access_log) = &access_log {
+            access_log.write(&AccessRecord {
+                conn: id,
+                frontend: metrics.name(),
+                client: client_addr,
+                backend: Some(backend.address()),
+                start,
+                duration: accepted.elapsed(),
+                transferred,
+                termination: Termination::of(result.as_ref().err(), session.first_finished()),
+                error: result.as_ref().err().map(ProxyError::label),
+                request: session.last_request(),
+            });
+        }
+        let outcome = match result {
+            Ok(_) => {
+                info!(
+                    "Session finished";
+                    conn = id,
+                    client = client,
+                    backend = backend.address(),
+                    sent = transferred.client_to_backend,
+                    received = transferred.backend_to_client,
+                    duration_ms = duration.as_millis(),
+                );
+                Outcome::Success
+            }
+            Err(e) => {
+                metrics.record_error(&e);
+                // Clients going away or idling out is routine.
+                let side = e.side().unwrap_or(Side::Client);
+                let level = match side {
+                    Side::Client => logging::Level::Info,
+                    Side::Backend => logging::Level::Warn,
+                };
+                log!(
+                    level,
+                    "Session ended with an error";
+                    conn = id,
+                    client = client,
+                    backend = backend.address(),
+                    side = side,
+                    error_kind = e.label(),
+                    error = e,
+                    duration_ms = duration.as_millis(),
+                );
+                match side {
+                    Side::Client => Outcome::Success,
+                    Side::Backend => {
+                        backend.metrics().record_session_failure();
+                        Outcome::Failure
+                    }
+                }
+            }
+        };
+        load_balancer
+            .lock()
+            .unwrap()
+            .report(guard.backend(), outcome);
+        drop(tracked);
+        drop(permit);
+    });
+    match stream {
+        Client::Tcp(stream) => frontend.sessions.run(stream, server, context, on_close),
+        // HTTP sessions are driven here, on their own thread.
+        Client::Http(first) => on_close(http::proxy_session(first, server, &context)),
+    }
+}
+
+/// Runs established sessions on the configured [`IoModel`].
+enum SessionDriver {
+    Threaded {
+        zero_copy: bool,
+        timeouts: Timeouts,
+    },
+    #[cfg(target_os = "linux")]
+    EventLoop(Arc<event_loop::EventLoop>),
+}
+
+impl SessionDriver {
+    fn new(
+        io_model: IoModel,
+        zero_copy: bool,
+        timeouts: &Timeouts,
+    ) -> Result<Self, std::io::Error> {
+        let timeouts = timeouts.clone();
+        match io_model {
+            IoModel::Threaded => Ok(SessionDriver::Threaded {
+                zero_copy,
+                timeouts,
+            }),
+            #[cfg(target_os = "linux")]
+            IoModel::EventLoop => Ok(SessionDriver::EventLoop(Arc::new(
+                event_loop::EventLoop::start(zero_copy, timeouts)?,
+            ))),
+            #[cfg(not(target_os = "linux"))]
+            IoModel::EventLoop => {
+                warn!("Event loop is only available on Linux, using threads");
+                Ok(SessionDriver::Threaded {
+                    zero_copy,
+                    timeouts,
+                })
+            }
+        }
+    }
+
+    /// Whether sessions occupy the thread that starts them until they
+    /// close.
+    fn keeps_worker(&self) -> bool {
+        matches!(self, SessionDriver::Threaded { .. })
+    }
+
+    /// Proxies between `client` and `server`, calling `on_close` with the
+    /// result once the session is over. Blocks for the whole session in
+    /// the threaded model and returns straight away otherwise.
+    fn run(
+        &self,
+        client: TcpStream,
+        server: TcpStream,
+        context: SessionContext,
+        on_close: Box<dyn FnOnce(proxy::SessionResult) + Send>,
+    ) {
+        match self {
+            SessionDriver::Threaded {
+                zero_copy,
+                timeouts,
+            } => on_close(proxy_session(
+                client, server, *zero_copy, timeouts, &context,
+            )),
+            #[cfg(target_os = "linux")]
+            SessionDriver::EventLoop(event_loop) => {
+                // If registration fails the sockets are dropped, which
+                // closes the session.
+                if let Err(e) = event_loop.register(client, server, context, on_close) {
+                    error!("Error registering session with event loop"; error = e);
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Read, Write};
     use std::net::TcpStream;
     use std::thread;
     use std::time::Duration;
@@ -162,10 +877,156 @@ mod tests {
         ];
         let mut lb = LoadBalancer::new(backends);
 
-        assert_eq!(lb.next_backend(), "127.0.0.1:8081");
-        assert_eq!(lb.next_backend(), "127.0.0.1:8082");
-        assert_eq!(lb.next_backend(), "127.0.0.1:8083");
-        assert_eq!(lb.next_backend(), "127.0.0.1:8081"); // Should wrap around
+        assert_eq!(lb.next_backend(), Some("127.0.0.1:8081"));
+        assert_eq!(lb.next_backend(), Some("127.0.0.1:8082"));
+        assert_eq!(lb.next_backend(), Some("127.0.0.1:8083"));
+        assert_eq!(lb.next_backend(), Some("127.0.0.1:8081")); // Should wrap around
+    }
+
+    struct AlwaysLast;
+
+    impl BalancingStrategy for AlwaysLast {
+        fn name(&self) -> &'static str {
+            "always_last"
+        }
+
+        fn select(&mut self, backends: &[Arc<Backend>], ctx: &SelectContext) -> Option<usize> {
+            backends.iter().rposition(|b| ctx.is_eligible(b))
+        }
+    }
+
+    #[test]
+    fn test_load_balancer_custom_strategy() {
+        let backends = vec!["127.0.0.1:8081".to_string(), "127.0.0.1:8082".to_string()];
+        let mut lb = LoadBalancer::with_strategy(backends, Box::new(AlwaysLast));
+
+        assert_eq!(lb.strategy_name(), "always_last");
+        assert_eq!(lb.next_backend(), Some("127.0.0.1:8082"));
+        lb.backends()[1].set_healthy(false);
+        assert_eq!(lb.next_backend(), Some("127.0.0.1:8081"));
+        lb.backends()[0].set_healthy(false);
+        assert_eq!(lb.next_backend(), None);
+    }
+
+    #[test]
+    fn test_replace_backends_keeps_state_of_remaining_backends() {
+        let mut lb = LoadBalancer::new(vec!["a:1".to_string(), "b:1".to_string()]);
+        let a = Arc::clone(&lb.backends()[0]);
+        let b = Arc::clone(&lb.backends()[1]);
+        a.set_healthy(false);
+        let in_flight = b.acquire();
+
+        let changes = lb.replace_backends(vec![
+            Backend::with_weight("a:1", 5).with_tags(vec!["blue".to_string()]),
+            Backend::new("c:1"),
+        ]);
+        assert_eq!(
+            changes,
+            BackendChanges {
+                added: vec!["c:1".to_string()],
+                removed: vec!["b:1".to_string()],
+                updated: vec!["a:1".to_string()],
+            }
+        );
+
+        assert!(Arc::ptr_eq(&lb.backends()[0], &a));
+        assert!(!a.is_healthy());
+        assert_eq!(a.weight(), 5);
+        assert_eq!(a.tags(), ["blue"]);
+        // The removed backend still serves its open session.
+        assert_eq!(in_flight.backend().active_connections(), 1);
+        assert_eq!(lb.next_backend(), Some("c:1"));
+    }
+
+    #[test]
+    fn test_drain_timeout_closes_remaining_sessions() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for mut conn in backend.incoming().flatten() {
+                thread::spawn(move || conn.read_to_end(&mut Vec::new()));
+            }
+        });
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![backend_addr.clone()])));
+        let drained = Arc::clone(&balancer.lock().unwrap().backends()[0]);
+        let proxy = Arc::clone(&balancer);
+        thread::spawn(move || run_load_balancer_with(18115, proxy, ProxyConfig::default()));
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(("127.0.0.1", 18115)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(drained.drain_progress(), None);
+
+        let started = Instant::now();
+        assert!(balancer
+            .lock()
+            .unwrap()
+            .drain(&backend_addr, Some(Duration::from_millis(300))));
+        let progress = drained.drain_progress().unwrap();
+        assert_eq!(progress.remaining_connections, 1);
+        assert!(progress.time_left.unwrap() <= Duration::from_millis(300));
+        assert_eq!(balancer.lock().unwrap().next_backend(), None);
+
+        // The session is cut off once the drain times out, which is
+        // tracked with millisecond precision.
+        client.read_to_end(&mut Vec::new()).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(290));
+        thread::sleep(Duration::from_millis(100));
+        assert!(drained.drain_progress().unwrap().is_complete());
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_sessions_then_closes_them() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for mut conn in backend.incoming().flatten() {
+                thread::spawn(move || conn.read_to_end(&mut Vec::new()));
+            }
+        });
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![backend_addr])));
+        let shutdown = ShutdownHandle::new();
+        let config = ProxyConfig {
+            timeouts: Timeouts {
+                shutdown_grace: Duration::from_millis(300),
+                ..Timeouts::default()
+            },
+            shutdown: shutdown.clone(),
+            ..ProxyConfig::default()
+        };
+        let proxy = thread::spawn(move || run_load_balancer_with(18116, balancer, config));
+        thread::sleep(Duration::from_millis(100));
+
+        let mut client = TcpStream::connect(("127.0.0.1", 18116)).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        shutdown.trigger();
+        client.read_to_end(&mut Vec::new()).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        proxy.join().unwrap().unwrap();
+        assert!(TcpStream::connect(("127.0.0.1", 18116)).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_before_start_stops_frontend() {
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec!["a:1".to_string()])));
+        let shutdown = ShutdownHandle::new();
+        shutdown.trigger();
+        let config = ProxyConfig {
+            shutdown,
+            ..ProxyConfig::default()
+        };
+        let proxy = thread::spawn(move || run_load_balancer_with(18126, balancer, config));
+        thread::sleep(Duration::from_millis(300));
+        assert!(proxy.is_finished());
+        proxy.join().unwrap().unwrap();
     }
 
     #[test]
@@ -183,4 +1044,136 @@ mod tests {
 
         assert!(response.contains(&format!("Response from backend on port {}", port)));
     }
+
+    #[test]
+    fn test_run_load_balancer_io_models() {
+        let backend = BackendServer::start(0).unwrap();
+        for (port, io_model) in [(18112, IoModel::Threaded), (18113, IoModel::EventLoop)] {
+            let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![format!(
+                "127.0.0.1:{}",
+                backend.port()
+            )])));
+            let config = ProxyConfig {
+                io_model,
+                ..ProxyConfig::default()
+            };
+            thread::spawn(move || run_load_balancer_with(port, balancer, config));
+            thread::sleep(Duration::from_millis(100));
+
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(
+                response.contains(&format!("Response from backend on port {}", backend.port())),
+                "{:?}: {}",
+                io_model,
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn test_connections_over_limit_are_rejected() {
+        // Holds each session open until the client closes its side.
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut conn in backend.incoming().flatten() {
+                thread::spawn(move || conn.read_to_end(&mut Vec::new()));
+            }
+        });
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(
+            vec![backend_addr.to_string()],
+        )));
+        let config = ProxyConfig {
+            name: Some("rejecting".to_string()),
+            max_connections: 1,
+            overload: OverloadPolicy::Reject,
+            ..ProxyConfig::default()
+        };
+        thread::spawn(move || run_load_balancer_with(18114, balancer, config));
+        thread::sleep(Duration::from_millis(100));
+        let frontend = metrics::frontend("rejecting").unwrap();
+        let stats = frontend.connections();
+
+        let first = TcpStream::connect(("127.0.0.1", 18114)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(stats.active(), 1);
+
+        let mut second = TcpStream::connect(("127.0.0.1", 18114)).unwrap();
+        let mut response = Vec::new();
+        second.read_to_end(&mut response).unwrap();
+        assert!(response.is_empty());
+        assert_eq!(stats.rejected(), 1);
+
+        drop(first);
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(stats.active(), 0);
+        assert_eq!(stats.accepted(), 2);
+    }
+
+    #[test]
+    fn test_threaded_sessions_are_limited_by_workers() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut conn in backend.incoming().flatten() {
+                thread::spawn(move || conn.read_to_end(&mut Vec::new()));
+            }
+        });
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(
+            vec![backend_addr.to_string()],
+        )));
+        let config = ProxyConfig {
+            name: Some("one-worker".to_string()),
+            io_model: IoModel::Threaded,
+            workers: 1,
+            overload: OverloadPolicy::Reject,
+            ..ProxyConfig::default()
+        };
+        thread::spawn(move || run_load_balancer_with(18125, balancer, config));
+        thread::sleep(Duration::from_millis(100));
+        let frontend = metrics::frontend("one-worker").unwrap();
+        let stats = frontend.connections();
+
+        let _first = TcpStream::connect(("127.0.0.1", 18125)).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        // The only worker is busy with the first session, so the second
+        // connection is rejected instead of waiting for it.
+        let mut second = TcpStream::connect(("127.0.0.1", 18125)).unwrap();
+        second.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(stats.rejected(), 1);
+    }
+
+    #[test]
+    fn test_access_log_records_sessions() {
+        let backend = BackendServer::start(0).unwrap();
+        let backend_addr = format!("127.0.0.1:{}", backend.port());
+        let path = std::env::temp_dir().join(format!("lb-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let format = "%{frontend} %{backend} %{bytes_in} %{reason} %{error}"
+            .parse()
+            .unwrap();
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![backend_addr.clone()])));
+        let config = ProxyConfig {
+            name: Some("logged".to_string()),
+            access_log: Some(Arc::new(AccessLog::open(&path, format).unwrap())),
+            ..ProxyConfig::default()
+        };
+        thread::spawn(move || run_load_balancer_with(18118, balancer, config));
+        thread::sleep(Duration::from_millis(100));
+
+        let mut stream = TcpStream::connect(("127.0.0.1", 18118)).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        stream.read_to_end(&mut Vec::new()).unwrap();
+        drop(stream);
+        thread::sleep(Duration::from_millis(200));
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("logged {} 18 backend_close -\n", backend_addr)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,506 @@
+//! Leveled, structured logging.
+//!
+//! Records are written to standard error, one per line, as logfmt or JSON.
+//! Besides the level, the module and the message, each record carries the
+//! fields given at the call site, such as the connection id, the client
+//! address, the backend and the kind of error:
+//!
+//! ```text
+//! ts=2026-01-02T03:04:05.678Z level=warn module=connect msg="Error connecting to backend" conn=17 backend=10.0.0.1:80 error_kind=connection_refused error="Connection refused (os error 111)"
+//! ```
+//!
+//! Which records are written is decided by a [`Filter`] such as
+//! `"info,proxy=trace"`: a default level followed by levels for modules
+//! and everything below them. Modules are named by their path in the
+//! crate, so `admin` covers `admin::json` too. The filter and the format
+//! can be changed at any time with [`set_filter`] and [`set_format`].
+//!
+//! Records are produced with the [`error!`](crate::error),
+//! [`warn!`](crate::warn), [`info!`](crate::info),
+//! [`debug!`](crate::debug) and [`trace!`](crate::trace) macros, which
+//! take a format string and its arguments, then optionally `;` and
+//! `key = value` fields whose values implement `Display`:
+//!
+//! ```
+//! # let (id, address) = (1, "10.0.0.1:80");
+//! load_balancer::info!("Connected to {}", address; conn = id);
+//! ```
+
+use std::{
+    fmt::{self, Display, Write as _},
+    io::{self, Write as _},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        RwLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{admin::json::Json, TimeoutKind};
+
+/// The `[log]` section of a configuration file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogConfig {
+    pub filter: Filter,
+    pub format: Format,
+}
+
+impl LogConfig {
+    /// Makes these the settings of the process.
+    pub fn apply(&self) {
+        set_filter(self.filter.clone());
+        set_format(self.format);
+    }
+}
+
+/// How important a record is. Each level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Level> {
+        [
+            Level::Error,
+            Level::Warn,
+            Level::Info,
+            Level::Debug,
+            Level::Trace,
+        ]
+        .into_iter()
+        .find(|level| level.name() == name)
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How records are written out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// `key=value` pairs, quoted where needed.
+    #[default]
+    Logfmt,
+    /// One JSON object per line, with every value as a string.
+    Json,
+}
+
+impl Format {
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Logfmt => "logfmt",
+            Format::Json => "json",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "logfmt" => Some(Format::Logfmt),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// The level below which records are dropped, overall and per module.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    default: Level,
+    // Sorted by module so that longer, more specific paths come later.
+    modules: Vec<(String, Level)>,
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::new(Level::Info)
+    }
+}
+
+impl Filter {
+    pub fn new(default: Level) -> Self {
+        Filter {
+            default,
+            modules: Vec::new(),
+        }
+    }
+
+    /// Sets the level of `module` and the modules below it.
+    pub fn with_module(mut self, module: &str, level: Level) -> Self {
+        self.modules.retain(|(m, _)| m != module);
+        self.modules.push((module.to_string(), level));
+        self.modules.sort();
+        self
+    }
+
+    /// The most verbose level written for `module`.
+    pub fn level(&self, module: &str) -> Level {
+        self.modules
+            .iter()
+            .rev()
+            .find(|(prefix, _)| {
+                module
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> Level {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Level::max)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    /// Parses filters such as `"debug"` or `"warn,proxy=trace,health=debug"`.
+    fn from_str(spec: &str) -> Result<Filter, String> {
+        let parse_level = |name: &str| {
+            Level::from_name(name.trim()).ok_or_else(|| {
+                format!(
+                    "unknown level `{}`; expected error, warn, info, debug or trace",
+                    name.trim()
+                )
+            })
+        };
+        let mut filter = Filter::default();
+        let mut default = None;
+        for directive in spec.split(',').map(str::trim) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim();
+                    if module.is_empty() {
+                        return Err(format!("`{}` does not name a module", directive));
+                    }
+                    filter = filter.with_module(module, parse_level(level)?);
+                }
+                None if default.is_none() => default = Some(parse_level(directive)?),
+                None => return Err(format!("more than one default level in `{}`", spec)),
+            }
+        }
+        if let Some(default) = default {
+            filter.default = default;
+        }
+        Ok(filter)
+    }
+}
+
+impl Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.default.name())?;
+        for (module, level) in &self.modules {
+            write!(f, ",{}={}", module, level)?;
+        }
+        Ok(())
+    }
+}
+
+static FILTER: RwLock<Option<Filter>> = RwLock::new(None);
+// The most verbose level of the filter, so that most records that will be
+// dropped are dropped without taking the lock.
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static FORMAT: AtomicU8 = AtomicU8::new(Format::Logfmt as u8);
+
+pub fn set_filter(filter: Filter) {
+    let mut current = FILTER.write().unwrap();
+    MAX_LEVEL.store(filter.max_level() as u8, Ordering::Relaxed);
+    *current = Some(filter);
+}
+
+pub fn filter() -> Filter {
+    FILTER.read().unwrap().clone().unwrap_or_default()
+}
+
+pub fn set_format(format: Format) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn format() -> Format {
+    match FORMAT.load(Ordering::Relaxed) {
+        f if f == Format::Json as u8 => Format::Json,
+        _ => Format::Logfmt,
+    }
+}
+
+/// Whether a record at `level` from the module at `module_path` would be
+/// written.
+#[doc(hidden)]
+pub fn enabled(level: Level, module_path: &str) -> bool {
+    if level as u8 > MAX_LEVEL.load(Ordering::Relaxed) {
+        return false;
+    }
+    match &*FILTER.read().unwrap() {
+        Some(filter) => level <= filter.level(module_name(module_path)),
+        None => level <= Level::Info,
+    }
+}
+
+/// Writes a record. Called by the logging macros.
+#[doc(hidden)]
+pub fn write(
+    level: Level,
+    module_path: &str,
+    message: fmt::Arguments<'_>,
+    fields: &[(&str, &dyn Display)],
+) {
+    let line = render(
+        format(),
+        SystemTime::now(),
+        level,
+        module_name(module_path),
+        message,
+        fields,
+    );
+    let _ = io::stderr().lock().write_all(line.as_bytes());
+}
+
+/// The name of a module within the crate, as used by filters: `proxy` for
+/// `load_balancer::proxy`. Records from the crate root keep the crate name.
+fn module_name(module_path: &str) -> &str {
+    match module_path.split_once("::") {
+        Some((_, module)) => module,
+        None => module_path,
+    }
+}
+
+/// A short, stable name for what went wrong, such as `client_idle_timeout` or
+/// `connection_refused`, for the `error_kind` field.
+pub fn error_kind(error: &io::Error) -> String {
+    if let Some(kind) = TimeoutKind::of(error) {
+        return kind.label().to_string();
+    }
+    let mut name = String::new();
+    for c in format!("{:?}", error.kind()).chars() {
+        if c.is_ascii_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+fn render(
+    format: Format,
+    time: SystemTime,
+    level: Level,
+    module: &str,
+    message: fmt::Arguments<'_>,
+    fields: &[(&str, &dyn Display)],
+) -> String {
+    let entries = [
+        ("ts", timestamp(time)),
+        ("level", level.name().to_string()),
+        ("module", module.to_string()),
+        ("msg", message.to_string()),
+    ]
+    .into_iter()
+    .chain(fields.iter().map(|(key, value)| (*key, value.to_string())));
+
+    let mut line = match format {
+        Format::Logfmt => {
+            let mut line = String::new();
+            for (key, value) in entries {
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                let _ = write!(line, "{}={}", key, logfmt_value(&value));
+            }
+            line
+        }
+        Format::Json => Json::Object(
+            entries
+                .map(|(key, value)| (key.to_string(), Json::String(value)))
+                .collect(),
+        )
+        .to_string(),
+    };
+    line.push('\n');
+    line
+}
+
+fn logfmt_value(value: &str) -> String {
+    let plain = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_graphic() && c != '"' && c != '=' && c != '\\');
+    if plain {
+        return value.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Formats `time` as an RFC 3339 UTC timestamp with milliseconds.
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (days, seconds_of_day) = (seconds / 86_400, seconds % 86_400);
+    // Converts days since 1970-01-01 to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Writes a record at the given [`Level`]; see the [module
+/// documentation](crate::logging).
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $message:literal $(, $arg:expr)* $(; $($key:ident = $value:expr),+ $(,)?)?) => {{
+        let level = $level;
+        if $crate::logging::enabled(level, module_path!()) {
+            $crate::logging::write(
+                level,
+                module_path!(),
+                format_args!($message $(, $arg)*),
+                &[$($((stringify!($key), &$value as &dyn ::std::fmt::Display)),+)?],
+            );
+        }
+    }};
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($tokens:tt)+) => { $crate::log!($crate::logging::Level::Error, $($tokens)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($tokens:tt)+) => { $crate::log!($crate::logging::Level::Warn, $($tokens)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($tokens:tt)+) => { $crate::log!($crate::logging::Level::Info, $($tokens)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($tokens:tt)+) => { $crate::log!($crate::logging::Level::Debug, $($tokens)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($tokens:tt)+) => { $crate::log!($crate::logging::Level::Trace, $($tokens)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_filter_levels_by_module() {
+        let filter: Filter = "warn, proxy=trace, admin=info, admin::json=error"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.level("health"), Level::Warn);
+        assert_eq!(filter.level("proxy"), Level::Trace);
+        assert_eq!(filter.level("proxy_extra"), Level::Warn);
+        assert_eq!(filter.level("admin"), Level::Info);
+        assert_eq!(filter.level("admin::json"), Level::Error);
+        assert_eq!(filter.max_level(), Level::Trace);
+        assert_eq!(
+            filter.to_string(),
+            "warn,admin=info,admin::json=error,proxy=trace"
+        );
+        assert_eq!(filter.to_string().parse::<Filter>().unwrap(), filter);
+
+        assert_eq!(
+            "proxy=debug".parse::<Filter>().unwrap().default,
+            Level::Info
+        );
+        assert!("loud"
+            .parse::<Filter>()
+            .unwrap_err()
+            .contains("unknown level"));
+        assert!("info,debug".parse::<Filter>().is_err());
+        assert!("=debug".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn test_records_are_rendered_as_logfmt_and_json() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_767_323_045_678);
+        let fields: [(&str, &dyn Display); 2] = [("conn", &17), ("error", &"said \"no\"")];
+        let render = |format| {
+            render(
+                format,
+                time,
+                Level::Warn,
+                "connect",
+                format_args!("Error connecting to {}", "b:1"),
+                &fields,
+            )
+        };
+        assert_eq!(
+            render(Format::Logfmt),
+            "ts=2026-01-02T03:04:05.678Z level=warn module=connect \
+             msg=\"Error connecting to b:1\" conn=17 error=\"said \\\"no\\\"\"\n"
+        );
+        assert_eq!(
+            render(Format::Json),
+            "{\"ts\":\"2026-01-02T03:04:05.678Z\",\"level\":\"warn\",\"module\":\"connect\",\
+             \"msg\":\"Error connecting to b:1\",\"conn\":\"17\",\"error\":\"said \\\"no\\\"\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(error_kind(&refused), "connection_refused");
+        assert_eq!(
+            error_kind(&TimeoutKind::ClientIdle.into()),
+            "client_idle_timeout"
+        );
+    }
+}
@@ -1,22 +1,212 @@
-use std::{thread, time::Duration};
+use std::{
+    env, process,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
 
-use load_balancer::{run_backend, run_load_balancer};
+use load_balancer::{
+    access_log::AccessLog,
+    admin::AdminServer,
+    config::Pools,
+    error, info,
+    logging::{self, Filter, Format},
+    run_backend, run_frontend, run_load_balancer_with,
+    signals::{self, Signal},
+    warn, Config, LoadBalancer, ProxyConfig, ShutdownHandle,
+};
 
+/// Usage: `load-balancer [CONFIG]`. Without a configuration file, three
+/// mock backends are started on ports 8081-8083 behind a load balancer on
+/// port 8080. With one, `SIGHUP` reloads the backend pools from it.
+/// `SIGTERM` and `SIGINT` shut down gracefully; a second one exits at once.
+/// `SIGUSR1` reopens the access log, so that it can be rotated.
+///
+/// `LB_LOG` sets the log level, such as `info,proxy=debug`, and
+/// `LB_LOG_FORMAT` picks `logfmt` or `json`. The `[log]` section of a
+/// configuration file takes precedence once it is loaded.
 fn main() -> Result<(), std::io::Error> {
+    configure_logging_from_env();
+    for signal in [
+        Signal::Hangup,
+        Signal::Interrupt,
+        Signal::Terminate,
+        Signal::User1,
+    ] {
+        signals::listen(signal)?;
+    }
+    match env::args().nth(1) {
+        Some(path) => run_from_config(&path),
+        None => run_demo(),
+    }
+}
+
+fn run_from_config(path: &str) -> Result<(), std::io::Error> {
+    let config = match Config::from_file(path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Invalid configuration"; path = path, error = e);
+            process::exit(2);
+        }
+    };
+
+    let access_log = match &config.access_log {
+        Some(access_log) => match access_log.open() {
+            Ok(log) => Some(Arc::new(log)),
+            Err(e) => {
+                error!(
+                    "Cannot open the access log";
+                    path = access_log.path.display(),
+                    error = e,
+                );
+                process::exit(2);
+            }
+        },
+        None => None,
+    };
+    let pools = Pools::start(&config);
+    let shutdown = ShutdownHandle::new();
+    let frontends: Vec<_> = config
+        .frontends
+        .iter()
+        .map(|frontend| {
+            let load_balancer = pools.load_balancer(&frontend.pool).unwrap();
+            let proxy_config = ProxyConfig {
+                shutdown: shutdown.clone(),
+                access_log: access_log.clone(),
+                ..config.proxy_config(frontend)
+            };
+            let (name, address) = (frontend.name.clone(), frontend.address);
+            thread::spawn(move || {
+                if let Err(e) = run_frontend(address, load_balancer, proxy_config) {
+                    error!(
+                        "Frontend stopped";
+                        frontend = name,
+                        address = address,
+                        error = e,
+                    );
+                }
+            })
+        })
+        .collect();
+
+    let pools = Arc::new(Mutex::new(pools));
+    let _admin = match config.admin {
+        Some(address) => Some(AdminServer::start(
+            address,
+            Arc::clone(&pools),
+            Some(path.into()),
+        )?),
+        None => None,
+    };
+
+    wait_for(frontends, &shutdown, access_log.as_deref(), || {
+        info!("Reloading configuration"; path = path);
+        match pools.lock().unwrap().reload_from(path) {
+            Ok(summary) => info!("Configuration reloaded"; summary = format!("{:?}", summary)),
+            Err(e) => error!("Keeping the current configuration"; error = e),
+        }
+    });
+    Ok(())
+}
+
+fn run_demo() -> Result<(), std::io::Error> {
     let backend_ports = vec![8081, 8082, 8083];
 
     for &port in &backend_ports {
         thread::spawn(move || {
-            println!("Starting backend server on port {}", port);
+            info!("Starting backend server"; port = port);
             if let Err(e) = run_backend(port) {
-                eprintln!("Backend server on port {} error: {}", port, e);
+                error!("Backend server failed"; port = port, error = e);
             }
         });
     }
 
-    println!("Waiting for backend servers to start...");
+    info!("Waiting for backend servers to start");
     thread::sleep(Duration::from_secs(2));
 
-    println!("Starting load balancer...");
-    run_load_balancer(8080, backend_ports)
+    info!("Starting load balancer");
+    let load_balancer = LoadBalancer::new(
+        backend_ports
+            .iter()
+            .map(|p| format!("127.0.0.1:{}", p))
+            .collect(),
+    );
+    let shutdown = ShutdownHandle::new();
+    let config = ProxyConfig {
+        shutdown: shutdown.clone(),
+        ..ProxyConfig::default()
+    };
+    let frontend = thread::spawn(move || {
+        run_load_balancer_with(8080, Arc::new(Mutex::new(load_balancer)), config)
+    });
+    while !frontend.is_finished() {
+        thread::sleep(SIGNAL_POLL);
+        handle_shutdown_signals(&shutdown);
+    }
+    Ok(frontend.join().unwrap()?)
+}
+
+/// How often the main thread checks for signals.
+const SIGNAL_POLL: Duration = Duration::from_millis(200);
+
+/// Handles signals until every frontend has stopped, calling `reload` on
+/// `SIGHUP`.
+fn wait_for(
+    frontends: Vec<JoinHandle<()>>,
+    shutdown: &ShutdownHandle,
+    access_log: Option<&AccessLog>,
+    mut reload: impl FnMut(),
+) {
+    while !frontends.iter().all(|frontend| frontend.is_finished()) {
+        thread::sleep(SIGNAL_POLL);
+        handle_shutdown_signals(shutdown);
+        if signals::take(Signal::Hangup) {
+            reload();
+        }
+        if signals::take(Signal::User1) {
+            if let Some(access_log) = access_log {
+                match access_log.reopen() {
+                    Ok(()) => info!("Access log reopened"; path = access_log.path().display()),
+                    Err(e) => error!(
+                        "Cannot reopen the access log";
+                        path = access_log.path().display(),
+                        error = e,
+                    ),
+                }
+            }
+        }
+    }
+}
+
+fn handle_shutdown_signals(shutdown: &ShutdownHandle) {
+    if signals::take(Signal::Interrupt) || signals::take(Signal::Terminate) {
+        if shutdown.is_triggered() {
+            warn!("Exiting without waiting for sessions to finish");
+            process::exit(1);
+        }
+        info!("Shutting down, waiting for sessions to finish");
+        shutdown.trigger();
+    }
+}
+
+fn configure_logging_from_env() {
+    if let Ok(level) = env::var("LB_LOG") {
+        match level.parse::<Filter>() {
+            Ok(filter) => logging::set_filter(filter),
+            Err(e) => {
+                error!("Invalid LB_LOG"; error = e);
+                process::exit(2);
+            }
+        }
+    }
+    if let Ok(format) = env::var("LB_LOG_FORMAT") {
+        match Format::from_name(&format) {
+            Some(format) => logging::set_format(format),
+            None => {
+                error!("Invalid LB_LOG_FORMAT, expected logfmt or json"; value = format);
+                process::exit(2);
+            }
+        }
+    }
 }
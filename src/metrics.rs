@@ -0,0 +1,693 @@
+//! Metrics in the Prometheus text exposition format.
+//!
+//! Every running frontend registers its [`FrontendMetrics`] here, and
+//! every load balancer in use is registered as a named pool, so that
+//! [`render`] can report on all of them at once. Backends carry their own
+//! [`BackendMetrics`]. The admin API serves the result at `GET /metrics`.
+//!
+//! | Metric                                   | Labels                    |
+//! |------------------------------------------|---------------------------|
+//! | `lb_frontend_connections_accepted_total` | `frontend`                |
+//! | `lb_frontend_connections_rejected_total` | `frontend`                |
+//! | `lb_frontend_connections_failed_total`   | `frontend`                |
+//! | `lb_frontend_errors_total`               | `frontend`,`side`,`error` |
+//! | `lb_frontend_connections_active`         | `frontend`                |
+//! | `lb_frontend_connections_queued`         | `frontend`                |
+//! | `lb_frontend_received_bytes_total`       | `frontend`                |
+//! | `lb_frontend_sent_bytes_total`           | `frontend`                |
+//! | `lb_frontend_session_duration_seconds`   | `frontend`                |
+//! | `lb_backend_connections_total`           | `pool`,`backend`          |
+//! | `lb_backend_connections_active`          | `pool`,`backend`          |
+//! | `lb_backend_connect_failures_total`      | `pool`,`backend`          |
+//! | `lb_backend_session_failures_total`      | `pool`,`backend`          |
+//! | `lb_backend_sent_bytes_total`            | `pool`,`backend`          |
+//! | `lb_backend_received_bytes_total`        | `pool`,`backend`          |
+//! | `lb_backend_connect_duration_seconds`    | `pool`,`backend`          |
+//! | `lb_backend_healthy`                     | `pool`,`backend`          |
+//! | `lb_backend_ejected`                     | `pool`,`backend`          |
+//! | `lb_backend_available`                   | `pool`,`backend`          |
+//! | `lb_backend_weight`                      | `pool`,`backend`          |
+//! | `lb_forwarded_bytes_total`               | `method`                  |
+//!
+//! Errors are counted by the [`ProxyError::side`] that failed and the
+//! [`ProxyError::label`] of what went wrong, such as `connection_refused`
+//! or `backend_idle_timeout`.
+//!
+//! Bytes are counted as they are forwarded, so long sessions show up
+//! before they end. "Sent" and "received" are from the point of view of
+//! the load balancer.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+
+use crate::{
+    byte_counters, Backend, ConnectionStats, ProxyError, SharedLoadBalancer, Side, TrafficCounters,
+};
+
+/// Upper bounds, in seconds, of the connect latency buckets.
+const CONNECT_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Upper bounds, in seconds, of the session duration buckets.
+const SESSION_BUCKETS: &[f64] = &[
+    0.01, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0,
+];
+
+/// A histogram of durations with fixed buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    // Non-cumulative counts, one per bound and one for +Inf.
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let index = self
+            .bounds
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(self.bounds.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+}
+
+/// Counters of one frontend.
+#[derive(Debug)]
+pub struct FrontendMetrics {
+    name: String,
+    connections: Arc<ConnectionStats>,
+    failed: AtomicU64,
+    errors: Mutex<BTreeMap<(Side, String), u64>>,
+    traffic: Arc<TrafficCounters>,
+    session_duration: Histogram,
+}
+
+impl FrontendMetrics {
+    fn new(name: String) -> Self {
+        FrontendMetrics {
+            name,
+            connections: Arc::default(),
+            failed: AtomicU64::new(0),
+            errors: Mutex::default(),
+            traffic: Arc::default(),
+            session_duration: Histogram::new(SESSION_BUCKETS),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Admission counters: accepted, rejected, active and queued
+    /// connections.
+    pub fn connections(&self) -> &Arc<ConnectionStats> {
+        &self.connections
+    }
+
+    /// Admitted connections for which no backend could be connected.
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Failed connects and sessions, by the side that failed and the
+    /// label of the error.
+    pub fn errors(&self) -> BTreeMap<(Side, String), u64> {
+        self.errors.lock().unwrap().clone()
+    }
+
+    pub(crate) fn record_error(&self, error: &ProxyError) {
+        if let Some(side) = error.side() {
+            *self
+                .errors
+                .lock()
+                .unwrap()
+                .entry((side, error.label()))
+                .or_default() += 1;
+        }
+    }
+
+    pub fn traffic(&self) -> &Arc<TrafficCounters> {
+        &self.traffic
+    }
+
+    pub fn session_duration(&self) -> &Histogram {
+        &self.session_duration
+    }
+}
+
+/// Counters of one backend, kept by the [`Backend`] itself.
+#[derive(Debug)]
+pub struct BackendMetrics {
+    connections: AtomicU64,
+    connect_failures: AtomicU64,
+    session_failures: AtomicU64,
+    traffic: Arc<TrafficCounters>,
+    connect_duration: Histogram,
+}
+
+impl Default for BackendMetrics {
+    fn default() -> Self {
+        BackendMetrics {
+            connections: AtomicU64::new(0),
+            connect_failures: AtomicU64::new(0),
+            session_failures: AtomicU64::new(0),
+            traffic: Arc::default(),
+            connect_duration: Histogram::new(CONNECT_BUCKETS),
+        }
+    }
+}
+
+impl BackendMetrics {
+    /// Sessions established with the backend.
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn connect_failures(&self) -> u64 {
+        self.connect_failures.load(Ordering::Relaxed)
+    }
+
+    /// Sessions that ended because of an error on the backend's side.
+    pub fn session_failures(&self) -> u64 {
+        self.session_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn traffic(&self) -> &Arc<TrafficCounters> {
+        &self.traffic
+    }
+
+    pub fn connect_duration(&self) -> &Histogram {
+        &self.connect_duration
+    }
+
+    pub(crate) fn record_connect(&self, took: Duration) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.connect_duration.observe(took);
+    }
+
+    pub(crate) fn record_connect_failure(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_session_failure(&self) {
+        self.session_failures.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    frontends: Vec<Arc<FrontendMetrics>>,
+    pools: Vec<(String, Weak<Mutex<crate::LoadBalancer>>)>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    frontends: Vec::new(),
+    pools: Vec::new(),
+});
+
+/// Creates the metrics of a frontend that is starting. They are reported
+/// until the returned guard is dropped.
+pub(crate) fn register_frontend(name: String) -> FrontendRegistration {
+    let metrics = Arc::new(FrontendMetrics::new(name));
+    REGISTRY
+        .lock()
+        .unwrap()
+        .frontends
+        .push(Arc::clone(&metrics));
+    FrontendRegistration(metrics)
+}
+
+/// Keeps a frontend's metrics registered.
+pub(crate) struct FrontendRegistration(pub(crate) Arc<FrontendMetrics>);
+
+impl Drop for FrontendRegistration {
+    fn drop(&mut self) {
+        REGISTRY
+            .lock()
+            .unwrap()
+            .frontends
+            .retain(|m| !Arc::ptr_eq(m, &self.0));
+    }
+}
+
+/// The metrics of the running frontend called `name`.
+pub fn frontend(name: &str) -> Option<Arc<FrontendMetrics>> {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .frontends
+        .iter()
+        .find(|m| m.name == name)
+        .cloned()
+}
+
+/// Reports the backends of `load_balancer` under the pool label `name`
+/// for as long as the load balancer exists. Registering a load balancer
+/// again keeps its first name.
+pub fn register_pool(name: &str, load_balancer: &SharedLoadBalancer) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry.pools.retain(|(_, pool)| pool.strong_count() > 0);
+    let known = registry
+        .pools
+        .iter()
+        .any(|(_, pool)| pool.as_ptr() == Arc::as_ptr(load_balancer));
+    if !known {
+        registry
+            .pools
+            .push((name.to_string(), Arc::downgrade(load_balancer)));
+    }
+}
+
+/// Stops reporting the pool called `name`.
+pub fn unregister_pool(name: &str) {
+    REGISTRY
+        .lock()
+        .unwrap()
+        .pools
+        .retain(|(pool, _)| pool != name);
+}
+
+/// Renders every registered metric in the Prometheus text format.
+pub fn render() -> String {
+    let (frontends, pools) = {
+        let registry = REGISTRY.lock().unwrap();
+        let pools: Vec<(String, SharedLoadBalancer)> = registry
+            .pools
+            .iter()
+            .filter_map(|(name, pool)| Some((name.clone(), pool.upgrade()?)))
+            .collect();
+        (registry.frontends.clone(), pools)
+    };
+    let backends: Vec<(String, Arc<Backend>)> = pools
+        .iter()
+        .flat_map(|(name, pool)| {
+            let backends = pool.lock().unwrap().backends().to_vec();
+            backends.into_iter().map(move |b| (name.clone(), b))
+        })
+        .collect();
+
+    let mut out = Exposition::default();
+    let frontend = |m: &FrontendMetrics| vec![("frontend", m.name.clone())];
+    out.family(
+        "lb_frontend_connections_accepted_total",
+        "counter",
+        "Client connections accepted, including rejected ones.",
+        frontends
+            .iter()
+            .map(|m| (frontend(m), m.connections.accepted())),
+    );
+    out.family(
+        "lb_frontend_connections_rejected_total",
+        "counter",
+        "Client connections closed because of the connection limit.",
+        frontends
+            .iter()
+            .map(|m| (frontend(m), m.connections.rejected())),
+    );
+    out.family(
+        "lb_frontend_connections_failed_total",
+        "counter",
+        "Admitted client connections for which no backend could be connected.",
+        frontends.iter().map(|m| (frontend(m), m.failed())),
+    );
+    out.family(
+        "lb_frontend_errors_total",
+        "counter",
+        "Failed connects and sessions, by the side that failed and the error.",
+        frontends.iter().flat_map(|m| {
+            m.errors().into_iter().map(|((side, error), count)| {
+                let mut labels = frontend(m);
+                labels.push(("side", side.to_string()));
+                labels.push(("error", error));
+                (labels, count)
+            })
+        }),
+    );
+    out.family(
+        "lb_frontend_connections_active",
+        "gauge",
+        "Client connections being handled.",
+        frontends
+            .iter()
+            .map(|m| (frontend(m), m.connections.active() as u64)),
+    );
+    out.family(
+        "lb_frontend_connections_queued",
+        "gauge",
+        "Client connections waiting for a free slot.",
+        frontends
+            .iter()
+            .map(|m| (frontend(m), m.connections.queued() as u64)),
+    );
+    out.family(
+        "lb_frontend_received_bytes_total",
+        "counter",
+        "Bytes received from clients and forwarded to backends.",
+        frontends
+            .iter()
+            .map(|m| (frontend(m), m.traffic.get().client_to_backend)),
+    );
+    out.family(
+        "lb_frontend_sent_bytes_total",
+        "counter",
+        "Bytes forwarded from backends to clients.",
+        frontends
+            .iter()
+            .map(|m| (frontend(m), m.traffic.get().backend_to_client)),
+    );
+    out.histograms(
+        "lb_frontend_session_duration_seconds",
+        "Duration of proxied sessions.",
+        frontends.iter().map(|m| (frontend(m), &m.session_duration)),
+    );
+
+    let backend = |(pool, b): &(String, Arc<Backend>)| {
+        vec![("pool", pool.clone()), ("backend", b.address().to_string())]
+    };
+    out.family(
+        "lb_backend_connections_total",
+        "counter",
+        "Sessions established with the backend.",
+        backends
+            .iter()
+            .map(|b| (backend(b), b.1.metrics().connections())),
+    );
+    out.family(
+        "lb_backend_connections_active",
+        "gauge",
+        "Connections to the backend being proxied.",
+        backends
+            .iter()
+            .map(|b| (backend(b), b.1.active_connections() as u64)),
+    );
+    out.family(
+        "lb_backend_connect_failures_total",
+        "counter",
+        "Failed attempts to connect to the backend.",
+        backends
+            .iter()
+            .map(|b| (backend(b), b.1.metrics().connect_failures())),
+    );
+    out.family(
+        "lb_backend_session_failures_total",
+        "counter",
+        "Sessions ended by an error on the backend's side.",
+        backends
+            .iter()
+            .map(|b| (backend(b), b.1.metrics().session_failures())),
+    );
+    out.family(
+        "lb_backend_sent_bytes_total",
+        "counter",
+        "Bytes forwarded from clients to the backend.",
+        backends
+            .iter()
+            .map(|b| (backend(b), b.1.metrics().traffic().get().client_to_backend)),
+    );
+    out.family(
+        "lb_backend_received_bytes_total",
+        "counter",
+        "Bytes received from the backend and forwarded to clients.",
+        backends
+            .iter()
+            .map(|b| (backend(b), b.1.metrics().traffic().get().backend_to_client)),
+    );
+    out.histograms(
+        "lb_backend_connect_duration_seconds",
+        "Time taken by successful connects to the backend.",
+        backends
+            .iter()
+            .map(|b| (backend(b), b.1.metrics().connect_duration())),
+    );
+    out.family(
+        "lb_backend_healthy",
+        "gauge",
+        "Whether health checks consider the backend healthy.",
+        backends
+            .iter()
+            .map(|b| (backend(b), b.1.is_healthy() as u64)),
+    );
+    out.family(
+        "lb_backend_ejected",
+        "gauge",
+        "Whether outlier detection has ejected the backend.",
+        backends
+            .iter()
+            .map(|b| (backend(b), b.1.is_ejected() as u64)),
+    );
+    out.family(
+        "lb_backend_available",
+        "gauge",
+        "Whether the backend is given new connections.",
+        backends
+            .iter()
+            .map(|b| (backend(b), b.1.is_available() as u64)),
+    );
+    out.family(
+        "lb_backend_weight",
+        "gauge",
+        "Configured weight of the backend.",
+        backends
+            .iter()
+            .map(|b| (backend(b), u64::from(b.1.weight()))),
+    );
+
+    let forwarded = byte_counters();
+    out.family(
+        "lb_forwarded_bytes_total",
+        "counter",
+        "Bytes forwarded by all sessions, by how they were moved.",
+        [
+            (vec![("method", "splice".to_string())], forwarded.spliced),
+            (vec![("method", "copy".to_string())], forwarded.copied),
+        ],
+    );
+    out.text
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    fn family(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (Labels, u64)>,
+    ) {
+        self.header(name, kind, help);
+        for (labels, value) in samples {
+            let _ = writeln!(self.text, "{}{} {}", name, format_labels(&labels), value);
+        }
+    }
+
+    fn histograms<'a>(
+        &mut self,
+        name: &str,
+        help: &str,
+        histograms: impl IntoIterator<Item = (Labels, &'a Histogram)>,
+    ) {
+        self.header(name, "histogram", help);
+        for (labels, histogram) in histograms {
+            let mut cumulative = 0;
+            for (i, bucket) in histogram.buckets.iter().enumerate() {
+                cumulative += bucket.load(Ordering::Relaxed);
+                let le = match histogram.bounds.get(i) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                };
+                let mut labels = labels.clone();
+                labels.push(("le", le));
+                let _ = writeln!(
+                    self.text,
+                    "{}_bucket{} {}",
+                    name,
+                    format_labels(&labels),
+                    cumulative
+                );
+            }
+            let labels = format_labels(&labels);
+            let _ = writeln!(
+                self.text,
+                "{}_sum{} {}",
+                name,
+                labels,
+                histogram.sum().as_secs_f64()
+            );
+            let _ = writeln!(self.text, "{}_count{} {}", name, labels, cumulative);
+        }
+    }
+}
+
+fn format_labels(labels: &[(&str, String)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{run_load_balancer_with, BackendServer, LoadBalancer, ProxyConfig, RetryPolicy};
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(100));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(5));
+
+        let mut out = Exposition::default();
+        out.histograms(
+            "t_seconds",
+            "Test.",
+            [(vec![("x", "a\"b".to_string())], &histogram)],
+        );
+        assert_eq!(
+            out.text,
+            "# HELP t_seconds Test.\n\
+             # TYPE t_seconds histogram\n\
+             t_seconds_bucket{x=\"a\\\"b\",le=\"0.1\"} 2\n\
+             t_seconds_bucket{x=\"a\\\"b\",le=\"1\"} 3\n\
+             t_seconds_bucket{x=\"a\\\"b\",le=\"+Inf\"} 4\n\
+             t_seconds_sum{x=\"a\\\"b\"} 5.65\n\
+             t_seconds_count{x=\"a\\\"b\"} 4\n"
+        );
+    }
+
+    #[test]
+    fn test_sessions_are_counted_per_frontend_and_backend() {
+        let backend = BackendServer::start(0).unwrap();
+        let address = format!("127.0.0.1:{}", backend.port());
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![address.clone()])));
+        register_pool("metrics-test", &balancer);
+        let config = ProxyConfig {
+            name: Some("metrics-test".to_string()),
+            ..ProxyConfig::default()
+        };
+        let proxy = Arc::clone(&balancer);
+        thread::spawn(move || run_load_balancer_with(18117, proxy, config));
+        thread::sleep(Duration::from_millis(100));
+
+        let request = b"GET / HTTP/1.1\r\n\r\n";
+        let mut stream = TcpStream::connect(("127.0.0.1", 18117)).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        drop(stream);
+        thread::sleep(Duration::from_millis(100));
+
+        let text = render();
+        let frontend = "{frontend=\"metrics-test\"}";
+        let backend = format!("{{pool=\"metrics-test\",backend=\"{}\"}}", address);
+        for line in [
+            format!("lb_frontend_connections_accepted_total{} 1", frontend),
+            format!("lb_frontend_connections_active{} 0", frontend),
+            format!(
+                "lb_frontend_received_bytes_total{} {}",
+                frontend,
+                request.len()
+            ),
+            format!(
+                "lb_frontend_sent_bytes_total{} {}",
+                frontend,
+                response.len()
+            ),
+            format!("lb_frontend_session_duration_seconds_count{} 1", frontend),
+            format!("lb_backend_connections_total{} 1", backend),
+            format!("lb_backend_connect_duration_seconds_count{} 1", backend),
+            format!("lb_backend_healthy{} 1", backend),
+        ] {
+            assert!(text.contains(&line), "missing `{}` in:\n{}", line, text);
+        }
+    }
+
+    #[test]
+    fn test_errors_are_counted_by_side_and_label() {
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let balancer = Arc::new(Mutex::new(LoadBalancer::new(vec![dead.to_string()])));
+        let config = ProxyConfig {
+            name: Some("errors-test".to_string()),
+            retry: RetryPolicy { attempts: 1 },
+            ..ProxyConfig::default()
+        };
+        thread::spawn(move || run_load_balancer_with(18119, balancer, config));
+        thread::sleep(Duration::from_millis(100));
+
+        let mut stream = TcpStream::connect(("127.0.0.1", 18119)).unwrap();
+        stream.read_to_end(&mut Vec::new()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let errors = frontend("errors-test").unwrap().errors();
+        assert_eq!(
+            errors.get(&(Side::Backend, "connection_refused".to_string())),
+            Some(&1)
+        );
+        let line = "lb_frontend_errors_total{frontend=\"errors-test\",side=\"backend\",\
+                    error=\"connection_refused\"} 1";
+        assert!(render().contains(line), "missing `{}`", line);
+    }
+}
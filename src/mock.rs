@@ -0,0 +1,170 @@
+//! Stand-in HTTP backends used by `main.rs` and the tests.
+//!
+//! Each connection gets a short window to send a request. `GET /healthz`
+//! answers with the server's health; every other request, and clients that
+//! send nothing at all, get a plain-text body naming the backend's port.
+
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::{debug, error, info};
+
+pub const HEALTH_PATH: &str = "/healthz";
+
+/// How long a backend waits for a request before answering anyway.
+const REQUEST_WAIT: Duration = Duration::from_millis(200);
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+pub fn run_backend(port: u16) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
+    info!("Backend server listening on 127.0.0.1:{}", port);
+    serve_backend(
+        listener,
+        port,
+        &AtomicBool::new(false),
+        &Arc::new(AtomicBool::new(true)),
+    )
+}
+
+/// A [`run_backend`] server running on a background thread that can be
+/// stopped again, so tests can take backends up and down.
+pub struct BackendServer {
+    port: u16,
+    stop: Arc<AtomicBool>,
+    healthy: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackendServer {
+    /// Starts a backend on `port`, or on a free port if `port` is 0.
+    pub fn start(port: u16) -> Result<Self, std::io::Error> {
+        let listener = TcpListener::bind(format!("127.0.0.1:{}", port))?;
+        let port = listener.local_addr()?.port();
+        info!("Backend server listening on 127.0.0.1:{}", port);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let healthy = Arc::new(AtomicBool::new(true));
+        let thread_stop = Arc::clone(&stop);
+        let thread_healthy = Arc::clone(&healthy);
+        let thread = thread::spawn(move || {
+            if let Err(e) = serve_backend(listener, port, &thread_stop, &thread_healthy) {
+                error!("Backend server failed"; port = port, error = e);
+            }
+        });
+
+        Ok(BackendServer {
+            port,
+            stop,
+            healthy,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Controls whether the health endpoint reports success. The server
+    /// keeps accepting connections either way.
+    pub fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::SeqCst);
+    }
+
+    /// Stops accepting connections and closes the listening socket.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it notices the stop flag.
+        let _ = TcpStream::connect(format!("127.0.0.1:{}", self.port));
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        info!("Backend server stopped"; port = self.port);
+    }
+}
+
+impl Drop for BackendServer {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.shutdown();
+        }
+    }
+}
+
+fn serve_backend(
+    listener: TcpListener,
+    port: u16,
+    stop: &AtomicBool,
+    healthy: &Arc<AtomicBool>,
+) -> Result<(), std::io::Error> {
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = stream?;
+        debug!("Backend received a connection"; port = port);
+        let healthy = Arc::clone(healthy);
+        thread::spawn(move || {
+            if let Err(e) = respond(stream, port, healthy.load(Ordering::SeqCst)) {
+                debug!("Backend failed to send a response"; port = port, error = e);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, port: u16, healthy: bool) -> Result<(), std::io::Error> {
+    let path = read_request_path(&mut stream);
+
+    let (status, body) = match path.as_deref() {
+        Some(HEALTH_PATH) if healthy => ("200 OK", "ok\n".to_string()),
+        Some(HEALTH_PATH) => ("503 Service Unavailable", "unhealthy\n".to_string()),
+        _ => (
+            "200 OK",
+            format!("Response from backend on port {}\n", port),
+        ),
+    };
+
+    // Send a valid HTTP response with headers and body
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+
+    // Ensure the response is sent before closing the connection
+    stream.flush()?;
+    debug!("Backend sent a response"; port = port);
+    Ok(())
+}
+
+/// Reads the request head, if the client sends one, and returns the path
+/// from its request line.
+fn read_request_path(stream: &mut TcpStream) -> Option<String> {
+    stream.set_read_timeout(Some(REQUEST_WAIT)).ok()?;
+    let mut head = Vec::new();
+    let mut buffer = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD {
+        match stream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => head.extend_from_slice(&buffer[..n]),
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let request_line = head.lines().next()?;
+    request_line.split_whitespace().nth(1).map(str::to_string)
+}
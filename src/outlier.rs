@@ -0,0 +1,266 @@
+//! Passive outlier detection.
+//!
+//! Connection handlers report whether each proxied session failed on the
+//! backend side. A backend is ejected after too many consecutive failures,
+//! or when its failure rate over a window is too high. Each ejection of the
+//! same backend lasts twice as long as the previous one, up to a cap, and
+//! no more than a set share of the pool may be ejected at once.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{warn, Backend};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutlierConfig {
+    /// Consecutive failures that eject a backend. `0` disables this check.
+    pub consecutive_failures: u32,
+    /// Failure percentage within `window` that ejects a backend. Disabled
+    /// when `None`.
+    pub failure_rate_percent: Option<u32>,
+    pub window: Duration,
+    /// Sessions needed in a window before the failure rate is considered.
+    pub min_requests: u32,
+    /// Length of the first ejection; later ones double each time.
+    pub base_ejection: Duration,
+    pub max_ejection: Duration,
+    /// Upper bound on the share of the pool that may be ejected at once.
+    /// One backend may always be ejected unless this is `0`.
+    pub max_ejection_percent: u32,
+}
+
+impl Default for OutlierConfig {
+    fn default() -> Self {
+        OutlierConfig {
+            consecutive_failures: 5,
+            failure_rate_percent: None,
+            window: Duration::from_secs(10),
+            min_requests: 20,
+            base_ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(300),
+            max_ejection_percent: 10,
+        }
+    }
+}
+
+/// How a proxied session ended, from the backend's point of view.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    /// The backend refused the connection, reset it, or failed a read or
+    /// write.
+    Failure,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct OutlierStats {
+    consecutive_failures: u32,
+    window_start: Option<Instant>,
+    window_successes: u32,
+    window_failures: u32,
+    ejections: u32,
+    last_ejected: Option<Instant>,
+}
+
+/// Records `outcome` for `backend` and ejects it if it has become an
+/// outlier. Returns the length of the ejection, if one was started.
+pub(crate) fn record(
+    config: &OutlierConfig,
+    backend: &Backend,
+    pool: &[Arc<Backend>],
+    outcome: Outcome,
+) -> Option<Duration> {
+    let now = Instant::now();
+    let mut stats = backend.outlier_stats();
+
+    if stats
+        .window_start
+        .is_none_or(|start| now.duration_since(start) >= config.window)
+    {
+        stats.window_start = Some(now);
+        stats.window_successes = 0;
+        stats.window_failures = 0;
+    }
+
+    match outcome {
+        Outcome::Success => {
+            stats.consecutive_failures = 0;
+            stats.window_successes = stats.window_successes.saturating_add(1);
+            // Forget earlier ejections once the backend has behaved for as
+            // long as the longest possible ejection.
+            if stats
+                .last_ejected
+                .is_some_and(|at| now.duration_since(at) >= config.max_ejection * 2)
+            {
+                stats.ejections = 0;
+                stats.last_ejected = None;
+            }
+            return None;
+        }
+        Outcome::Failure => {
+            stats.consecutive_failures = stats.consecutive_failures.saturating_add(1);
+            stats.window_failures = stats.window_failures.saturating_add(1);
+        }
+    }
+
+    if backend.is_ejected() {
+        return None;
+    }
+
+    let too_many_consecutive = config.consecutive_failures > 0
+        && stats.consecutive_failures >= config.consecutive_failures;
+    let total = stats.window_successes + stats.window_failures;
+    let rate_too_high = config.failure_rate_percent.is_some_and(|percent| {
+        total >= config.min_requests.max(1)
+            && u64::from(stats.window_failures) * 100 >= u64::from(percent) * u64::from(total)
+    });
+    if !too_many_consecutive && !rate_too_high {
+        return None;
+    }
+
+    let ejected = pool.iter().filter(|b| b.is_ejected()).count();
+    if ejected >= max_ejected(config, pool.len()) {
+        warn!(
+            "Backend is an outlier but {} of {} backends are already ejected", ejected, pool.len();
+            backend = backend.address(),
+        );
+        return None;
+    }
+
+    let duration = config
+        .base_ejection
+        .checked_mul(1 << stats.ejections.min(16))
+        .map_or(config.max_ejection, |d| d.min(config.max_ejection));
+    stats.ejections += 1;
+    stats.last_ejected = Some(now);
+    stats.consecutive_failures = 0;
+    stats.window_start = None;
+    backend.eject_for(duration);
+
+    warn!(
+        "Backend ejected for {:?}", duration;
+        backend = backend.address(),
+        consecutive_failures = if too_many_consecutive {
+            config.consecutive_failures
+        } else {
+            0
+        },
+        window_failures = stats.window_failures,
+        window_total = total,
+    );
+    Some(duration)
+}
+
+fn max_ejected(config: &OutlierConfig, pool_size: usize) -> usize {
+    if config.max_ejection_percent == 0 {
+        return 0;
+    }
+    (pool_size * config.max_ejection_percent.min(100) as usize / 100).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(n: usize) -> Vec<Arc<Backend>> {
+        (0..n)
+            .map(|i| Arc::new(Backend::new(format!("10.0.0.{}:80", i))))
+            .collect()
+    }
+
+    fn fail(
+        config: &OutlierConfig,
+        pool: &[Arc<Backend>],
+        i: usize,
+        times: u32,
+    ) -> Option<Duration> {
+        let mut result = None;
+        for _ in 0..times {
+            result = record(config, &pool[i], pool, Outcome::Failure).or(result);
+        }
+        result
+    }
+
+    #[test]
+    fn test_consecutive_failures_eject_with_growing_duration() {
+        let config = OutlierConfig {
+            consecutive_failures: 3,
+            base_ejection: Duration::from_millis(10),
+            max_ejection: Duration::from_millis(35),
+            max_ejection_percent: 100,
+            ..OutlierConfig::default()
+        };
+        let backends = pool(2);
+
+        assert_eq!(fail(&config, &backends, 0, 2), None);
+        record(&config, &backends[0], &backends, Outcome::Success);
+        assert_eq!(fail(&config, &backends, 0, 2), None);
+        assert!(backends[0].is_available());
+
+        assert_eq!(
+            fail(&config, &backends, 0, 1),
+            Some(Duration::from_millis(10))
+        );
+        assert!(!backends[0].is_available());
+        assert!(backends[1].is_available());
+
+        std::thread::sleep(Duration::from_millis(15));
+        assert!(backends[0].is_available());
+        assert_eq!(
+            fail(&config, &backends, 0, 3),
+            Some(Duration::from_millis(20))
+        );
+
+        std::thread::sleep(Duration::from_millis(25));
+        assert_eq!(
+            fail(&config, &backends, 0, 3),
+            Some(Duration::from_millis(35))
+        );
+    }
+
+    #[test]
+    fn test_failure_rate_ejects() {
+        let config = OutlierConfig {
+            consecutive_failures: 0,
+            failure_rate_percent: Some(50),
+            min_requests: 10,
+            max_ejection_percent: 100,
+            ..OutlierConfig::default()
+        };
+        let backends = pool(1);
+
+        for _ in 0..4 {
+            record(&config, &backends[0], &backends, Outcome::Success);
+            record(&config, &backends[0], &backends, Outcome::Failure);
+        }
+        assert!(backends[0].is_available());
+        record(&config, &backends[0], &backends, Outcome::Success);
+        assert!(record(&config, &backends[0], &backends, Outcome::Failure).is_some());
+        assert!(!backends[0].is_available());
+    }
+
+    #[test]
+    fn test_max_ejection_percent_caps_ejections() {
+        let config = OutlierConfig {
+            consecutive_failures: 1,
+            max_ejection_percent: 25,
+            ..OutlierConfig::default()
+        };
+        let backends = pool(8);
+
+        assert!(fail(&config, &backends, 0, 1).is_some());
+        assert!(fail(&config, &backends, 1, 1).is_some());
+        assert_eq!(fail(&config, &backends, 2, 5), None);
+        assert!(backends[2].is_available());
+
+        let single = pool(3);
+        let config = OutlierConfig {
+            max_ejection_percent: 10,
+            ..config
+        };
+        assert!(fail(&config, &single, 0, 1).is_some());
+        assert_eq!(fail(&config, &single, 1, 1), None);
+    }
+}
@@ -0,0 +1,126 @@
+//! A fixed set of worker threads fed from a bounded queue.
+
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+};
+
+pub(crate) type Job = Box<dyn FnOnce() + Send>;
+
+pub(crate) struct WorkerPool {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+    capacity: usize,
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    stopping: bool,
+}
+
+impl WorkerPool {
+    /// Starts `workers` threads. At most `capacity` jobs may wait for a
+    /// free worker.
+    pub(crate) fn new(workers: usize, capacity: usize) -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                stopping: false,
+            }),
+            available: Condvar::new(),
+            capacity,
+        });
+        let threads = (0..workers.max(1))
+            .map(|i| {
+                let shared = Arc::clone(&shared);
+                thread::Builder::new()
+                    .name(format!("lb-worker-{}", i))
+                    .spawn(move || work(&shared))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(WorkerPool { shared, threads })
+    }
+
+    /// Queues `job`, or hands it back if the queue is full.
+    pub(crate) fn try_execute(&self, job: Job) -> Result<(), Job> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.jobs.len() >= self.shared.capacity {
+            return Err(job);
+        }
+        state.jobs.push_back(job);
+        self.shared.available.notify_one();
+        Ok(())
+    }
+}
+
+fn work(shared: &Shared) {
+    loop {
+        let job = {
+            let state = shared.state.lock().unwrap();
+            let mut state = shared
+                .available
+                .wait_while(state, |state| state.jobs.is_empty() && !state.stopping)
+                .unwrap();
+            match state.jobs.pop_front() {
+                Some(job) => job,
+                None => return,
+            }
+        };
+        job();
+    }
+}
+
+impl Drop for WorkerPool {
+    /// Lets the workers finish the queued jobs, then joins them.
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stopping = true;
+        self.shared.available.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc,
+    };
+
+    #[test]
+    fn test_worker_pool_runs_jobs_and_bounds_queue() {
+        let pool = WorkerPool::new(1, 2).unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started, wait_started) = mpsc::channel();
+        pool.try_execute(Box::new(move || {
+            started.send(()).unwrap();
+            blocked.recv().unwrap();
+        }))
+        .ok()
+        .unwrap();
+        wait_started.recv().unwrap();
+
+        let ran = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let ran = Arc::clone(&ran);
+            let job: Job = Box::new(move || {
+                ran.fetch_add(1, Ordering::SeqCst);
+            });
+            assert!(pool.try_execute(job).is_ok());
+        }
+        assert!(pool.try_execute(Box::new(|| {})).is_err());
+
+        release.send(()).unwrap();
+        drop(pool);
+        assert_eq!(ran.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,690 @@
+//! Copying bytes between a client and its backend.
+//!
+//! Each direction is pumped on its own thread so that either side may
+//! speak first, pipeline, or stream for as long as it likes. When one side
+//! finishes sending, its peer's write half is shut down so the half-close
+//! reaches the other end, and the session ends once both directions are
+//! done. An error in either direction tears down the whole session, and
+//! so does reaching one of the configured [`Timeouts`].
+//!
+//! This is the portable data path. On Linux the
+//! [`event_loop`](crate::event_loop) does the same job for all sessions on
+//! a single thread.
+//!
+//! With zero copy enabled on Linux, bytes are moved socket-to-pipe-to-socket
+//! with `splice(2)` and never enter user space; elsewhere, or if a pipe
+//! cannot be created, they are copied through a buffer. Process-wide totals
+//! for both paths are available from [`byte_counters`].
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{debug, http::Exchange, logging, trace, warn, Timeouts};
+
+/// How often a blocked read wakes up to check for timeouts.
+pub(crate) const TIMEOUT_CHECK: Duration = Duration::from_millis(500);
+
+/// Size of the user-space buffer used when bytes are copied.
+pub(crate) const BUFFER_SIZE: usize = 16 * 1024;
+
+static SPLICED_BYTES: AtomicU64 = AtomicU64::new(0);
+static COPIED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes forwarded by all sessions in this process, split by how they were
+/// moved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ByteCounters {
+    /// Moved between sockets with `splice(2)`, without a user-space copy.
+    pub spliced: u64,
+    /// Copied through a user-space buffer.
+    pub copied: u64,
+}
+
+pub fn byte_counters() -> ByteCounters {
+    ByteCounters {
+        spliced: SPLICED_BYTES.load(Ordering::Relaxed),
+        copied: COPIED_BYTES.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn record_spliced(n: usize) {
+    SPLICED_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+pub(crate) fn record_copied(n: usize) {
+    COPIED_BYTES.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// Which end of a proxied session an error came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Side {
+    Client,
+    Backend,
+}
+
+impl Side {
+    /// The other end of the session.
+    pub fn peer(self) -> Side {
+        match self {
+            Side::Client => Side::Backend,
+            Side::Backend => Side::Client,
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Side::Client => "client",
+            Side::Backend => "backend",
+        })
+    }
+}
+
+/// Bytes copied in each direction over the lifetime of a session.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Transferred {
+    pub client_to_backend: u64,
+    pub backend_to_client: u64,
+}
+
+/// Running totals of the bytes forwarded in each direction by a group of
+/// sessions, such as those of one frontend or one backend. Sessions add to
+/// them as data flows, not only when they end.
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    client_to_backend: AtomicU64,
+    backend_to_client: AtomicU64,
+}
+
+impl TrafficCounters {
+    pub fn get(&self) -> Transferred {
+        Transferred {
+            client_to_backend: self.client_to_backend.load(Ordering::Relaxed),
+            backend_to_client: self.backend_to_client.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add(&self, source: Side, n: usize) {
+        let counter = match source {
+            Side::Client => &self.client_to_backend,
+            Side::Backend => &self.backend_to_client,
+        };
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// What a session is known by in logs, the traffic counters it adds its
+/// bytes to, which side finished first and, in HTTP mode, its last
+/// request.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionContext {
+    /// The connection id of the session.
+    pub(crate) id: u64,
+    counters: Vec<Arc<TrafficCounters>>,
+    // The side that finished sending first, as `Side as u8 + 1`, or 0.
+    first_finished: Arc<AtomicU8>,
+    last_request: Arc<Mutex<Option<Exchange>>>,
+}
+
+impl SessionContext {
+    pub(crate) fn new(id: u64, counters: Vec<Arc<TrafficCounters>>) -> Self {
+        SessionContext {
+            id,
+            counters,
+            ..SessionContext::default()
+        }
+    }
+
+    pub(crate) fn record_request(&self, exchange: Exchange) {
+        *self.last_request.lock().unwrap() = Some(exchange);
+    }
+
+    /// Notes the status the last request was answered with.
+    pub(crate) fn record_status(&self, status: u16) {
+        if let Some(exchange) = self.last_request.lock().unwrap().as_mut() {
+            exchange.status = Some(status);
+        }
+    }
+
+    pub(crate) fn last_request(&self) -> Option<Exchange> {
+        self.last_request.lock().unwrap().clone()
+    }
+
+    /// Notes that `source` has reached end of stream.
+    pub(crate) fn record_finished(&self, source: Side) {
+        let _ = self.first_finished.compare_exchange(
+            0,
+            source as u8 + 1,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The side that reached end of stream first, if either has.
+    pub(crate) fn first_finished(&self) -> Option<Side> {
+        match self.first_finished.load(Ordering::Relaxed) {
+            1 => Some(Side::Client),
+            2 => Some(Side::Backend),
+            _ => None,
+        }
+    }
+
+    /// Counts `n` bytes forwarded from `source` to its peer.
+    pub(crate) fn record(&self, source: Side, n: usize) {
+        for counters in &self.counters {
+            counters.add(source, n);
+        }
+    }
+}
+
+pub(crate) type SessionResult = Result<Transferred, (Side, io::Error)>;
+
+/// The limit that ended a session or a connect attempt. Carried inside the
+/// [`io::ErrorKind::TimedOut`] errors it causes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Connect,
+    /// Nothing was sent either way for `client_idle` while the client had
+    /// not finished sending.
+    ClientIdle,
+    /// Nothing was sent either way for `backend_idle` while the backend had
+    /// not finished sending.
+    BackendIdle,
+    MaxSession,
+}
+
+impl TimeoutKind {
+    /// Which timeout, if any, caused `error`.
+    pub fn of(error: &io::Error) -> Option<TimeoutKind> {
+        error.get_ref()?.downcast_ref().copied()
+    }
+
+    /// The snake_case name used in logs and metrics labels.
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            TimeoutKind::Connect => "connect_timeout",
+            TimeoutKind::ClientIdle => "client_idle_timeout",
+            TimeoutKind::BackendIdle => "backend_idle_timeout",
+            TimeoutKind::MaxSession => "max_session",
+        }
+    }
+
+    /// The side a session that hit this timeout failed on: the one whose
+    /// idle timeout ran out, the backend for connecting, and the client for
+    /// reaching the session lifetime.
+    pub fn side(self) -> Side {
+        match self {
+            TimeoutKind::Connect | TimeoutKind::BackendIdle => Side::Backend,
+            TimeoutKind::ClientIdle | TimeoutKind::MaxSession => Side::Client,
+        }
+    }
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TimeoutKind::Connect => "connect timeout",
+            TimeoutKind::ClientIdle => "client idle timeout",
+            TimeoutKind::BackendIdle => "backend idle timeout",
+            TimeoutKind::MaxSession => "maximum session lifetime reached",
+        })
+    }
+}
+
+impl Error for TimeoutKind {}
+
+impl From<TimeoutKind> for io::Error {
+    fn from(kind: TimeoutKind) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, kind)
+    }
+}
+
+/// When a session last saw traffic, and which sides have finished sending.
+struct Activity {
+    started: Instant,
+    // Milliseconds since `started`.
+    last: AtomicU64,
+    client_finished: AtomicBool,
+    backend_finished: AtomicBool,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            started: Instant::now(),
+            last: AtomicU64::new(0),
+            client_finished: AtomicBool::new(false),
+            backend_finished: AtomicBool::new(false),
+        }
+    }
+
+    fn record(&self) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn record_finished(&self, source: Side) {
+        self.record();
+        match source {
+            Side::Client => self.client_finished.store(true, Ordering::Relaxed),
+            Side::Backend => self.backend_finished.store(true, Ordering::Relaxed),
+        }
+    }
+
+    fn expired(&self, timeouts: &Timeouts) -> Option<TimeoutKind> {
+        let age = self.started.elapsed();
+        let quiet = age.saturating_sub(Duration::from_millis(self.last.load(Ordering::Relaxed)));
+        timeouts.expired(
+            age,
+            quiet,
+            !self.client_finished.load(Ordering::Relaxed),
+            !self.backend_finished.load(Ordering::Relaxed),
+        )
+    }
+}
+
+pub(crate) fn proxy_session(
+    client: TcpStream,
+    server: TcpStream,
+    zero_copy: bool,
+    timeouts: &Timeouts,
+    context: &SessionContext,
+) -> SessionResult {
+    client
+        .set_read_timeout(Some(TIMEOUT_CHECK))
+        .map_err(|e| (Side::Client, e))?;
+    server
+        .set_read_timeout(Some(TIMEOUT_CHECK))
+        .map_err(|e| (Side::Backend, e))?;
+    let client_reader = client.try_clone().map_err(|e| (Side::Client, e))?;
+    let server_writer = server.try_clone().map_err(|e| (Side::Backend, e))?;
+
+    let activity = Arc::new(Activity::new());
+
+    let upstream = {
+        let activity = Arc::clone(&activity);
+        let timeouts = timeouts.clone();
+        let context = context.clone();
+        thread::spawn(move || {
+            pump(
+                client_reader,
+                server_writer,
+                Side::Client,
+                zero_copy,
+                &activity,
+                &timeouts,
+                &context,
+            )
+        })
+    };
+    let downstream = pump(
+        server,
+        client,
+        Side::Backend,
+        zero_copy,
+        &activity,
+        timeouts,
+        context,
+    );
+    let upstream = upstream
+        .join()
+        .unwrap_or_else(|_| Err((Side::Client, io::Error::other("upstream copy panicked"))));
+
+    let transferred = Transferred {
+        client_to_backend: *upstream.as_ref().unwrap_or(&0),
+        backend_to_client: *downstream.as_ref().unwrap_or(&0),
+    };
+    upstream?;
+    downstream?;
+    Ok(transferred)
+}
+
+/// Copies from `from` to `to` until `from` reaches end of stream, then
+/// shuts down the write half of `to`. On error or timeout both sockets are
+/// shut down completely so that the opposite direction stops as well.
+fn pump(
+    from: TcpStream,
+    to: TcpStream,
+    source: Side,
+    zero_copy: bool,
+    activity: &Activity,
+    timeouts: &Timeouts,
+    context: &SessionContext,
+) -> Result<u64, (Side, io::Error)> {
+    let destination = source.peer();
+    let (from_name, to_name) = match source {
+        Side::Client => ("client", "backend server"),
+        Side::Backend => ("backend", "client"),
+    };
+
+    let mut chunk = Chunk::new(zero_copy);
+    let mut total = 0;
+    let result = loop {
+        match chunk.read_from(&from) {
+            Ok(0) => {
+                debug!("{} finished sending", capitalize(from_name); conn = context.id);
+                context.record_finished(source);
+                activity.record_finished(source);
+                let _ = to.shutdown(Shutdown::Write);
+                break Ok(total);
+            }
+            Ok(n) => {
+                trace!("Read {} bytes from {}", n, from_name; conn = context.id);
+                activity.record();
+                if let Err(e) = chunk.write_to(&to, n) {
+                    debug!(
+                        "Error writing to {}", to_name;
+                        conn = context.id,
+                        error_kind = logging::error_kind(&e),
+                        error = e,
+                    );
+                    break Err((destination, e));
+                }
+                total += n as u64;
+                context.record(source, n);
+                trace!("Wrote {} bytes to {}", n, to_name; conn = context.id);
+            }
+            Err(e) if is_timeout(&e) => {
+                if let Some(kind) = activity.expired(timeouts) {
+                    debug!("Closing session: {}", kind; conn = context.id);
+                    break Err((kind.side(), kind.into()));
+                }
+            }
+            Err(e) => {
+                debug!(
+                    "Error reading from {}", from_name;
+                    conn = context.id,
+                    error_kind = logging::error_kind(&e),
+                    error = e,
+                );
+                break Err((source, e));
+            }
+        }
+    };
+
+    if result.is_err() {
+        let _ = from.shutdown(Shutdown::Both);
+        let _ = to.shutdown(Shutdown::Both);
+    }
+    result
+}
+
+/// Holds one chunk in flight between reading it from the source and
+/// writing it to the destination.
+enum Chunk {
+    Buffer(Box<[u8]>),
+    #[cfg(target_os = "linux")]
+    Pipe(crate::sys::Pipe),
+}
+
+impl Chunk {
+    fn new(zero_copy: bool) -> Self {
+        #[cfg(target_os = "linux")]
+        if zero_copy {
+            match crate::sys::Pipe::new(false) {
+                Ok(pipe) => return Chunk::Pipe(pipe),
+                Err(e) => warn!("Falling back to buffered copy, cannot create pipe"; error = e),
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = zero_copy;
+        Chunk::Buffer(vec![0; BUFFER_SIZE].into_boxed_slice())
+    }
+
+    /// Reads the next chunk, returning its length or 0 at end of stream.
+    fn read_from(&mut self, mut from: &TcpStream) -> io::Result<usize> {
+        match self {
+            Chunk::Buffer(buffer) => from.read(buffer),
+            // The pipe is always empty here, so only the socket can block.
+            #[cfg(target_os = "linux")]
+            Chunk::Pipe(pipe) => pipe.fill_from(from, crate::sys::PIPE_CAPACITY),
+        }
+    }
+
+    /// Writes out the `len` bytes of the chunk just read.
+    fn write_to(&mut self, mut to: &TcpStream, len: usize) -> io::Result<()> {
+        match self {
+            Chunk::Buffer(buffer) => {
+                to.write_all(&buffer[..len])?;
+                record_copied(len);
+                Ok(())
+            }
+            #[cfg(target_os = "linux")]
+            Chunk::Pipe(pipe) => {
+                let mut remaining = len;
+                while remaining > 0 {
+                    match pipe.drain_to(to, remaining) {
+                        Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                        Ok(n) => {
+                            remaining -= n;
+                            record_spliced(n);
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Starts a single-use proxy in front of `backend` and returns the
+    /// client end of a connection through it.
+    fn connect_through_proxy(
+        backend: &TcpListener,
+        zero_copy: bool,
+        timeouts: Timeouts,
+    ) -> (TcpStream, thread::JoinHandle<SessionResult>) {
+        let backend_addr = backend.local_addr().unwrap();
+        let front = TcpListener::bind("127.0.0.1:0").unwrap();
+        let front_addr = front.local_addr().unwrap();
+        let session = thread::spawn(move || {
+            let (client, _) = front.accept().unwrap();
+            let server = TcpStream::connect(backend_addr).unwrap();
+            proxy_session(
+                client,
+                server,
+                zero_copy,
+                &timeouts,
+                &SessionContext::default(),
+            )
+        });
+        (TcpStream::connect(front_addr).unwrap(), session)
+    }
+
+    #[test]
+    fn test_server_speaks_first() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, session) = connect_through_proxy(&backend, true, Timeouts::default());
+
+        let (mut conn, _) = backend.accept().unwrap();
+        conn.write_all(b"220 ready\r\n").unwrap();
+        let mut banner = [0; 11];
+        client.read_exact(&mut banner).unwrap();
+        assert_eq!(&banner, b"220 ready\r\n");
+
+        client.write_all(b"QUIT\r\n").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut request = String::new();
+        conn.read_to_string(&mut request).unwrap();
+        assert_eq!(request, "QUIT\r\n");
+        drop(conn);
+
+        let transferred = session.join().unwrap().unwrap();
+        assert_eq!(
+            transferred,
+            Transferred {
+                client_to_backend: 6,
+                backend_to_client: 11
+            }
+        );
+    }
+
+    fn echo_large_transfer(zero_copy: bool) {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (client, session) = connect_through_proxy(&backend, zero_copy, Timeouts::default());
+        let echo = thread::spawn(move || {
+            let (mut conn, _) = backend.accept().unwrap();
+            let mut reader = conn.try_clone().unwrap();
+            io::copy(&mut reader, &mut conn).unwrap();
+        });
+
+        let payload: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+        let mut writer = client.try_clone().unwrap();
+        let sent = payload.clone();
+        let send = thread::spawn(move || {
+            writer.write_all(&sent).unwrap();
+            writer.shutdown(Shutdown::Write).unwrap();
+        });
+
+        let mut received = Vec::new();
+        (&client).read_to_end(&mut received).unwrap();
+        send.join().unwrap();
+        echo.join().unwrap();
+        assert_eq!(received.len(), payload.len());
+        assert!(received == payload);
+        assert_eq!(
+            session.join().unwrap().unwrap().backend_to_client,
+            payload.len() as u64
+        );
+    }
+
+    #[test]
+    fn test_large_pipelined_transfer_is_echoed() {
+        let before = byte_counters();
+        echo_large_transfer(false);
+        // Other tests share the counters, so only check they moved.
+        assert!(byte_counters().copied >= before.copied + 2 * 512 * 1024);
+    }
+
+    #[test]
+    fn test_large_pipelined_transfer_is_echoed_zero_copy() {
+        let before = byte_counters();
+        echo_large_transfer(true);
+        if cfg!(target_os = "linux") {
+            assert!(byte_counters().spliced >= before.spliced + 2 * 512 * 1024);
+        }
+    }
+
+    #[test]
+    fn test_half_close_reaches_backend() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, session) = connect_through_proxy(&backend, true, Timeouts::default());
+
+        client.write_all(b"request body").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+
+        // The backend only answers once it has seen the end of the request.
+        let (mut conn, _) = backend.accept().unwrap();
+        let mut request = String::new();
+        conn.read_to_string(&mut request).unwrap();
+        assert_eq!(request, "request body");
+        conn.write_all(b"response").unwrap();
+        drop(conn);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "response");
+        assert!(session.join().unwrap().is_ok());
+    }
+
+    fn short_timeouts(max_session: Option<Duration>) -> Timeouts {
+        Timeouts {
+            client_idle: Duration::from_millis(300),
+            backend_idle: Duration::from_millis(300),
+            max_session,
+            ..Timeouts::default()
+        }
+    }
+
+    #[test]
+    fn test_idle_side_is_reported() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let long = Duration::from_secs(60);
+        for (timeouts, side, kind) in [
+            (
+                Timeouts {
+                    backend_idle: long,
+                    ..short_timeouts(None)
+                },
+                Side::Client,
+                TimeoutKind::ClientIdle,
+            ),
+            (
+                Timeouts {
+                    client_idle: long,
+                    ..short_timeouts(None)
+                },
+                Side::Backend,
+                TimeoutKind::BackendIdle,
+            ),
+        ] {
+            let (_client, session) = connect_through_proxy(&backend, true, timeouts);
+            let _conn = backend.accept().unwrap();
+
+            let (failed, e) = session.join().unwrap().unwrap_err();
+            assert_eq!(failed, side);
+            assert_eq!(TimeoutKind::of(&e), Some(kind));
+        }
+    }
+
+    #[test]
+    fn test_slow_stream_outlives_idle_timeout_until_max_session() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, session) = connect_through_proxy(
+            &backend,
+            false,
+            short_timeouts(Some(Duration::from_millis(1500))),
+        );
+        let (mut conn, _) = backend.accept().unwrap();
+
+        // Only the backend talks, but often enough never to be idle.
+        let started = Instant::now();
+        let streamer = thread::spawn(move || {
+            while conn.write_all(b".").is_ok() {
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let mut received = Vec::new();
+        let _ = client.read_to_end(&mut received);
+        assert!(started.elapsed() >= Duration::from_millis(1500));
+        assert!(received.len() >= 10);
+
+        let (_, e) = session.join().unwrap().unwrap_err();
+        assert_eq!(TimeoutKind::of(&e), Some(TimeoutKind::MaxSession));
+        streamer.join().unwrap();
+    }
+}
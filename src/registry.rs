@@ -0,0 +1,115 @@
+//! Bookkeeping of open sessions so they can be closed from outside.
+//!
+//! Sessions are driven by worker threads or the event loop, neither of
+//! which can be reached from the code that decides a session has to go.
+//! Instead, duplicates of a session's sockets are kept in a registry, and
+//! shutting those down makes the session end the same way it would if
+//! both peers had closed their connections.
+
+use std::{
+    collections::BTreeMap,
+    net::{Shutdown, TcpStream},
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Default)]
+pub(crate) struct SessionRegistry {
+    state: Mutex<RegistryState>,
+}
+
+#[derive(Debug, Default)]
+struct RegistryState {
+    // Client and backend sockets of each session.
+    sessions: BTreeMap<u64, [TcpStream; 2]>,
+    next_id: u64,
+    closed: bool,
+}
+
+impl SessionRegistry {
+    /// Registers the sockets of a session until the returned handle is
+    /// dropped. Returns `None` once the registry has been closed, or if
+    /// the sockets cannot be duplicated.
+    pub(crate) fn track(
+        self: &Arc<Self>,
+        client: &TcpStream,
+        server: &TcpStream,
+    ) -> Option<TrackedSession> {
+        let (client, server) = (client.try_clone().ok()?, server.try_clone().ok()?);
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return None;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.sessions.insert(id, [client, server]);
+        Some(TrackedSession {
+            registry: Arc::clone(self),
+            id,
+        })
+    }
+
+    /// Closes every registered session, returning how many there were.
+    pub(crate) fn close_all(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        for stream in state.sessions.values().flatten() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        state.sessions.len()
+    }
+
+    /// Closes every registered session and refuses to track new ones.
+    pub(crate) fn close(&self) -> usize {
+        self.state.lock().unwrap().closed = true;
+        self.close_all()
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+}
+
+/// Keeps a session in its registry until dropped.
+#[derive(Debug)]
+pub(crate) struct TrackedSession {
+    registry: Arc<SessionRegistry>,
+    id: u64,
+}
+
+impl Drop for TrackedSession {
+    fn drop(&mut self) {
+        self.registry
+            .state
+            .lock()
+            .unwrap()
+            .sessions
+            .remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, net::TcpListener};
+
+    fn connected_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_close_shuts_down_tracked_sessions() {
+        let registry = Arc::new(SessionRegistry::default());
+        let (mut client, server) = connected_pair();
+        let tracked = registry.track(&client, &server).unwrap();
+        let (other_client, other_server) = connected_pair();
+        drop(registry.track(&other_client, &other_server).unwrap());
+
+        assert_eq!(registry.close(), 1);
+        assert_eq!(client.read(&mut [0; 16]).unwrap(), 0);
+        assert!(registry.track(&other_client, &other_server).is_none());
+        drop(tracked);
+        assert_eq!(registry.close_all(), 0);
+    }
+}
@@ -0,0 +1,88 @@
+//! Stopping running frontends.
+//!
+//! A [`ShutdownHandle`] is passed to frontends through
+//! [`ProxyConfig::shutdown`](crate::ProxyConfig::shutdown). Triggering it
+//! makes each of them stop accepting, give in-flight sessions up to
+//! [`Timeouts::shutdown_grace`](crate::Timeouts::shutdown_grace) to finish,
+//! close whatever is left and return.
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// Requests a graceful shutdown of the frontends it was given to. Clones
+/// share the same request.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requested: AtomicBool,
+    // Listeners whose accept loops have to be woken up.
+    listeners: Mutex<Vec<SocketAddr>>,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the frontends to shut down. Returns straight away; each
+    /// frontend returns from its run function once it is done.
+    pub fn trigger(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        for address in self.inner.listeners.lock().unwrap().iter() {
+            // Wake the accept loop so it notices the request.
+            let _ = TcpStream::connect(address);
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Makes [`trigger`](Self::trigger) wake the accept loop of the
+    /// listener bound to `address`.
+    pub(crate) fn register(&self, address: SocketAddr) {
+        self.inner
+            .listeners
+            .lock()
+            .unwrap()
+            .push(connectable(address));
+    }
+
+    pub(crate) fn unregister(&self, address: SocketAddr) {
+        let address = connectable(address);
+        self.inner
+            .listeners
+            .lock()
+            .unwrap()
+            .retain(|a| *a != address);
+    }
+}
+
+/// The address to connect to for reaching a listener bound to `address`,
+/// which may be a wildcard.
+fn connectable(mut address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => address.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => address.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => {}
+    }
+    address
+}
+
+impl fmt::Debug for ShutdownHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShutdownHandle")
+            .field("triggered", &self.is_triggered())
+            .finish()
+    }
+}
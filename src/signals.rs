@@ -0,0 +1,112 @@
+//! Minimal Unix signal handling.
+//!
+//! Handlers only set a flag, which the application polls with [`take`] from
+//! an ordinary thread where it is safe to do real work.
+
+use std::{
+    io,
+    os::raw::c_int,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// `SIGHUP`, conventionally a request to reload configuration.
+    Hangup,
+    /// `SIGINT`, sent by Ctrl-C.
+    Interrupt,
+    /// `SIGTERM`, the polite request to shut down.
+    Terminate,
+    /// `SIGUSR1`, conventionally a request to reopen log files after they
+    /// have been rotated.
+    User1,
+}
+
+#[cfg(target_os = "linux")]
+const SIGUSR1: c_int = 10;
+#[cfg(not(target_os = "linux"))]
+const SIGUSR1: c_int = 30;
+
+impl Signal {
+    fn number(self) -> c_int {
+        match self {
+            Signal::Hangup => 1,
+            Signal::Interrupt => 2,
+            Signal::Terminate => 15,
+            Signal::User1 => SIGUSR1,
+        }
+    }
+
+    fn pending(self) -> &'static AtomicBool {
+        static HANGUP: AtomicBool = AtomicBool::new(false);
+        static INTERRUPT: AtomicBool = AtomicBool::new(false);
+        static TERMINATE: AtomicBool = AtomicBool::new(false);
+        static USER1: AtomicBool = AtomicBool::new(false);
+        match self {
+            Signal::Hangup => &HANGUP,
+            Signal::Interrupt => &INTERRUPT,
+            Signal::Terminate => &TERMINATE,
+            Signal::User1 => &USER1,
+        }
+    }
+
+    fn from_number(number: c_int) -> Option<Signal> {
+        match number {
+            1 => Some(Signal::Hangup),
+            2 => Some(Signal::Interrupt),
+            15 => Some(Signal::Terminate),
+            SIGUSR1 => Some(Signal::User1),
+            _ => None,
+        }
+    }
+}
+
+/// Starts catching `signal` instead of letting it take its default action.
+pub fn listen(signal: Signal) -> io::Result<()> {
+    // SAFETY: `handle` only touches atomics, which is async-signal-safe.
+    let previous = unsafe { ffi::signal(signal.number(), handle as ffi::Handler) };
+    if previous == ffi::SIG_ERR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether `signal` has arrived since the last call.
+pub fn take(signal: Signal) -> bool {
+    signal.pending().swap(false, Ordering::SeqCst)
+}
+
+extern "C" fn handle(number: c_int) {
+    if let Some(signal) = Signal::from_number(number) {
+        signal.pending().store(true, Ordering::SeqCst);
+    }
+}
+
+mod ffi {
+    use std::os::raw::c_int;
+
+    pub type Handler = extern "C" fn(c_int);
+
+    pub const SIG_ERR: usize = usize::MAX;
+
+    extern "C" {
+        pub fn signal(signum: c_int, handler: Handler) -> usize;
+        #[cfg(test)]
+        pub fn raise(sig: c_int) -> c_int;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_caught_signal_is_taken_once() {
+        listen(Signal::Hangup).unwrap();
+        assert!(!take(Signal::Hangup));
+        // SAFETY: a handler for the signal is installed above.
+        assert_eq!(unsafe { ffi::raise(Signal::Hangup.number()) }, 0);
+        assert!(take(Signal::Hangup));
+        assert!(!take(Signal::Hangup));
+    }
+}
@@ -0,0 +1,31 @@
+//! Stable 64-bit hashing for the hash-based strategies.
+//!
+//! `std`'s `DefaultHasher` is not guaranteed to be stable across releases,
+//! and hash-based placement has to agree between restarts, so the
+//! strategies use FNV-1a followed by the murmur3 finaliser to spread the
+//! bits.
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+pub(crate) fn hash64(bytes: &[u8]) -> u64 {
+    hash64_seeded(bytes, 0)
+}
+
+pub(crate) fn hash64_seeded(bytes: &[u8], seed: u64) -> u64 {
+    let mut hash = FNV_OFFSET ^ seed;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    fmix64(hash)
+}
+
+fn fmix64(mut k: u64) -> u64 {
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    k ^= k >> 33;
+    k = k.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    k ^= k >> 33;
+    k
+}
@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use super::{BalancingStrategy, SelectContext};
+use crate::Backend;
+
+/// Picks the available backend with the fewest active connections relative
+/// to its weight. Ties are broken by rotating through the tied backends so
+/// that an idle pool is still spread evenly.
+#[derive(Debug, Default)]
+pub struct LeastConnections {
+    offset: usize,
+}
+
+impl LeastConnections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BalancingStrategy for LeastConnections {
+    fn name(&self) -> &'static str {
+        "least_connections"
+    }
+
+    fn select(&mut self, backends: &[Arc<Backend>], ctx: &SelectContext) -> Option<usize> {
+        let len = backends.len();
+        let mut best: Option<(usize, u64, u64)> = None;
+        for i in 0..len {
+            let index = (self.offset + i) % len;
+            let backend = &backends[index];
+            let weight = u64::from(backend.weight());
+            if weight == 0 || !ctx.is_eligible(backend) {
+                continue;
+            }
+            let active = backend.active_connections() as u64;
+            // Compare active / weight without dividing: a/w < b/v <=> a*v < b*w.
+            let better = match best {
+                None => true,
+                Some((_, best_active, best_weight)) => active * best_weight < best_active * weight,
+            };
+            if better {
+                best = Some((index, active, weight));
+            }
+        }
+
+        let (index, _, _) = best?;
+        self.offset = (index + 1) % len;
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_least_connections_prefers_idle_backend() {
+        let backends: Vec<Arc<Backend>> = (0..3)
+            .map(|i| Arc::new(Backend::new(format!("backend-{}", i))))
+            .collect();
+        let mut lc = LeastConnections::new();
+        let ctx = SelectContext::default();
+
+        let _a = backends[0].acquire();
+        let _b = backends[0].acquire();
+        let _c = backends[1].acquire();
+        assert_eq!(lc.select(&backends, &ctx), Some(2));
+
+        let _d = backends[2].acquire();
+        let _e = backends[2].acquire();
+        assert_eq!(lc.select(&backends, &ctx), Some(1));
+
+        backends[1].set_healthy(false);
+        assert!(matches!(lc.select(&backends, &ctx), Some(0) | Some(2)));
+    }
+
+    #[test]
+    fn test_least_connections_respects_weight() {
+        let backends = vec![
+            Arc::new(Backend::with_weight("big", 4)),
+            Arc::new(Backend::with_weight("small", 1)),
+        ];
+        let mut lc = LeastConnections::new();
+        let ctx = SelectContext::default();
+
+        let mut guards = Vec::new();
+        for _ in 0..10 {
+            let index = lc.select(&backends, &ctx).unwrap();
+            guards.push(backends[index].acquire());
+        }
+        assert_eq!(backends[0].active_connections(), 8);
+        assert_eq!(backends[1].active_connections(), 2);
+    }
+}
@@ -0,0 +1,325 @@
+use std::sync::Arc;
+
+use super::{
+    hash::{hash64, hash64_seeded},
+    BalancingStrategy, HashKey, SelectContext,
+};
+use crate::Backend;
+
+/// Default lookup table size. Must be prime and should be well above
+/// 100 times the number of backends for an even spread.
+pub const DEFAULT_TABLE_SIZE: usize = 65_537;
+
+const OFFSET_SEED: u64 = 0x6d61_676c_6576_0001;
+const SKIP_SEED: u64 = 0x6d61_676c_6576_0002;
+
+/// Maglev lookup-table hashing (Eisenbud et al., NSDI 2016).
+///
+/// Each backend derives a permutation of table slots from its address and
+/// the backends take turns claiming their next preferred free slot until the
+/// table is full. A key is served by `table[hash(key) % size]`, so lookups
+/// are O(1) and every backend owns almost exactly its share of the table.
+/// Backends get extra turns in proportion to their weight.
+///
+/// When a looked-up backend is unavailable the table is rebuilt without it;
+/// the excluded backends are then checked on each pick so the original table
+/// comes back as soon as they recover. Backends excluded by the
+/// [`SelectContext`] are skipped by moving on to the following slots.
+#[derive(Debug)]
+pub struct Maglev {
+    key: HashKey,
+    table_size: usize,
+    table: Vec<usize>,
+    // Backends left out of `table` because they were unavailable.
+    excluded: Vec<usize>,
+    built_for: usize,
+}
+
+impl Maglev {
+    pub fn new(key: HashKey) -> Self {
+        Self::with_table_size(key, DEFAULT_TABLE_SIZE)
+    }
+
+    /// Uses a table of the given size, rounded up to the next prime.
+    pub fn with_table_size(key: HashKey, table_size: usize) -> Self {
+        Maglev {
+            key,
+            table_size: next_prime(table_size.max(2)),
+            table: Vec::new(),
+            excluded: Vec::new(),
+            built_for: 0,
+        }
+    }
+
+    pub fn table_size(&self) -> usize {
+        self.table_size
+    }
+
+    fn build(&mut self, backends: &[Arc<Backend>]) {
+        self.built_for = backends.len();
+        self.excluded = (0..backends.len())
+            .filter(|&i| !backends[i].is_available() || backends[i].weight() == 0)
+            .collect();
+        let included: Vec<usize> = (0..backends.len())
+            .filter(|i| !self.excluded.contains(i))
+            .collect();
+        self.table = populate(backends, &included, self.table_size);
+    }
+}
+
+impl BalancingStrategy for Maglev {
+    fn name(&self) -> &'static str {
+        "maglev"
+    }
+
+    fn select(&mut self, backends: &[Arc<Backend>], ctx: &SelectContext) -> Option<usize> {
+        if self.built_for != backends.len()
+            || self
+                .excluded
+                .iter()
+                .any(|&i| backends[i].is_available() && backends[i].weight() > 0)
+        {
+            self.build(backends);
+        }
+
+        let slot = (hash64(&self.key.extract(ctx)) % self.table_size as u64) as usize;
+        let index = *self.table.get(slot)?;
+        if ctx.is_eligible(&backends[index]) {
+            return Some(index);
+        }
+
+        if !backends[index].is_available() {
+            self.build(backends);
+        }
+        // The owner of the slot is excluded for this connection only, so
+        // fall through to the owners of the following slots instead.
+        (0..self.table.len())
+            .map(|i| self.table[(slot + i) % self.table.len()])
+            .find(|&index| ctx.is_eligible(&backends[index]))
+    }
+
+    fn backends_changed(&mut self, backends: &[Arc<Backend>]) {
+        self.build(backends);
+    }
+}
+
+fn populate(backends: &[Arc<Backend>], included: &[usize], size: usize) -> Vec<usize> {
+    if included.is_empty() {
+        return Vec::new();
+    }
+
+    let m = size as u64;
+    let permutations: Vec<(u64, u64)> = included
+        .iter()
+        .map(|&i| {
+            let name = backends[i].address().as_bytes();
+            let offset = hash64_seeded(name, OFFSET_SEED) % m;
+            let skip = hash64_seeded(name, SKIP_SEED) % (m - 1) + 1;
+            (offset, skip)
+        })
+        .collect();
+    let weights: Vec<u64> = included
+        .iter()
+        .map(|&i| u64::from(backends[i].weight()))
+        .collect();
+    let max_weight = weights.iter().copied().max().unwrap_or(1);
+
+    let mut next = vec![0u64; included.len()];
+    let mut table = vec![usize::MAX; size];
+    let mut filled = 0;
+    // Weighted turns: in every round a backend gets a turn while its
+    // accumulated credit reaches the heaviest backend's weight.
+    // Credit stays below twice the largest u32, so u64 cannot overflow.
+    let mut credit = vec![0u64; included.len()];
+    while filled < size {
+        for (n, &backend) in included.iter().enumerate() {
+            credit[n] += weights[n];
+            if credit[n] < max_weight {
+                continue;
+            }
+            credit[n] -= max_weight;
+
+            let (offset, skip) = permutations[n];
+            let mut slot = ((offset + next[n] * skip) % m) as usize;
+            while table[slot] != usize::MAX {
+                next[n] += 1;
+                slot = ((offset + next[n] * skip) % m) as usize;
+            }
+            table[slot] = backend;
+            next[n] += 1;
+            filled += 1;
+            if filled == size {
+                break;
+            }
+        }
+    }
+    table
+}
+
+fn next_prime(mut n: usize) -> usize {
+    while !is_prime(n) {
+        n += 1;
+    }
+    n
+}
+
+fn is_prime(n: usize) -> bool {
+    if n < 2 {
+        return false;
+    }
+    (2..)
+        .take_while(|d| d * d <= n)
+        .all(|d| !n.is_multiple_of(d))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: u32 = 20_000;
+
+    fn pool(n: usize) -> Vec<Arc<Backend>> {
+        (0..n)
+            .map(|i| Arc::new(Backend::new(format!("10.1.{}.{}:8080", i / 256, i % 256))))
+            .collect()
+    }
+
+    fn key(i: u32) -> SelectContext {
+        let mut ctx = SelectContext::default();
+        ctx.headers.push(("x-key".to_string(), i.to_string()));
+        ctx
+    }
+
+    fn owners(maglev: &mut Maglev, backends: &[Arc<Backend>]) -> Vec<String> {
+        (0..KEYS)
+            .map(|i| {
+                let index = maglev.select(backends, &key(i)).unwrap();
+                backends[index].address().to_string()
+            })
+            .collect()
+    }
+
+    fn disruption(before: &[String], after: &[String]) -> f64 {
+        let moved = before.iter().zip(after).filter(|(b, a)| b != a).count();
+        moved as f64 / before.len() as f64
+    }
+
+    fn header_maglev(table_size: usize) -> Maglev {
+        Maglev::with_table_size(HashKey::Header("x-key".to_string()), table_size)
+    }
+
+    #[test]
+    fn test_maglev_table_size_is_prime() {
+        assert_eq!(
+            Maglev::with_table_size(HashKey::ClientIp, 1000).table_size(),
+            1009
+        );
+        assert_eq!(Maglev::new(HashKey::ClientIp).table_size(), 65_537);
+    }
+
+    #[test]
+    fn test_maglev_spreads_evenly() {
+        let backends = pool(100);
+        let mut maglev = header_maglev(65_537);
+        maglev.backends_changed(&backends);
+
+        let mut counts = vec![0usize; backends.len()];
+        for &index in &maglev.table {
+            counts[index] += 1;
+        }
+        let min = *counts.iter().min().unwrap();
+        let max = *counts.iter().max().unwrap();
+        // Maglev guarantees slot counts differ by at most a handful.
+        assert!(max - min <= 2, "min {} max {}", min, max);
+    }
+
+    #[test]
+    fn test_maglev_respects_weights() {
+        let backends = vec![
+            Arc::new(Backend::with_weight("heavy", 3)),
+            Arc::new(Backend::with_weight("light", 1)),
+        ];
+        let mut maglev = header_maglev(10_007);
+        maglev.backends_changed(&backends);
+
+        let heavy = maglev.table.iter().filter(|&&i| i == 0).count();
+        let share = heavy as f64 / maglev.table.len() as f64;
+        assert!((share - 0.75).abs() < 0.01, "heavy share {}", share);
+    }
+
+    #[test]
+    fn test_maglev_handles_largest_weights() {
+        let backends = vec![
+            Arc::new(Backend::with_weight("a", u32::MAX)),
+            Arc::new(Backend::with_weight("b", u32::MAX - 1)),
+        ];
+        let mut maglev = header_maglev(101);
+        maglev.backends_changed(&backends);
+        assert!(maglev.table.iter().all(|&i| i < backends.len()));
+    }
+
+    #[test]
+    fn test_maglev_disruption_on_add() {
+        let mut backends = pool(50);
+        let mut maglev = header_maglev(65_537);
+        maglev.backends_changed(&backends);
+        let before = owners(&mut maglev, &backends);
+
+        backends.push(Arc::new(Backend::new("10.9.9.9:8080")));
+        maglev.backends_changed(&backends);
+        let after = owners(&mut maglev, &backends);
+
+        // Ideal disruption is 1/51 (~2%); Maglev trades a little extra
+        // movement for its even spread.
+        let moved = disruption(&before, &after);
+        assert!(moved < 0.04, "disruption {:.4}", moved);
+        let to_new = after
+            .iter()
+            .filter(|a| a.as_str() == "10.9.9.9:8080")
+            .count();
+        assert!(to_new as f64 / KEYS as f64 > 0.015);
+    }
+
+    #[test]
+    fn test_maglev_disruption_on_remove() {
+        let mut backends = pool(50);
+        let mut maglev = header_maglev(65_537);
+        maglev.backends_changed(&backends);
+        let before = owners(&mut maglev, &backends);
+
+        let removed = backends.remove(17);
+        maglev.backends_changed(&backends);
+        let after = owners(&mut maglev, &backends);
+
+        let moved = disruption(&before, &after);
+        let owned_by_removed = before.iter().filter(|b| *b == removed.address()).count();
+        let unnecessary = before
+            .iter()
+            .zip(&after)
+            .filter(|(b, a)| *b != removed.address() && b != a)
+            .count();
+        assert!(moved < 0.04, "disruption {:.4}", moved);
+        assert!(owned_by_removed > 0);
+        assert!(
+            (unnecessary as f64) < KEYS as f64 * 0.02,
+            "{} keys moved off healthy backends",
+            unnecessary
+        );
+    }
+
+    #[test]
+    fn test_maglev_unavailable_backend_excluded_then_restored() {
+        let backends = pool(10);
+        let mut maglev = header_maglev(5_003);
+        maglev.backends_changed(&backends);
+        let before = owners(&mut maglev, &backends);
+
+        backends[3].set_healthy(false);
+        let during = owners(&mut maglev, &backends);
+        assert!(during.iter().all(|a| a != backends[3].address()));
+        assert!(disruption(&before, &during) < 0.2);
+
+        backends[3].set_healthy(true);
+        assert_eq!(owners(&mut maglev, &backends), before);
+    }
+}
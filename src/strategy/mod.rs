@@ -0,0 +1,117 @@
+//! Backend selection strategies for [`LoadBalancer`](crate::LoadBalancer).
+//!
+//! A strategy is handed the full backend list on every call, including
+//! backends that are currently unavailable, so that it can keep its own
+//! bookkeeping stable and decide for itself how to skip them. Strategies
+//! only return backends for which [`SelectContext::is_eligible`] holds.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use crate::Backend;
+
+mod hash;
+mod least_connections;
+mod maglev;
+mod p2c;
+mod ring_hash;
+mod round_robin;
+mod weighted;
+
+pub use least_connections::LeastConnections;
+pub use maglev::{Maglev, DEFAULT_TABLE_SIZE};
+pub use p2c::PowerOfTwoChoices;
+pub use ring_hash::{ConsistentHash, DEFAULT_POINTS_PER_WEIGHT, MAX_RING_POINTS};
+pub use round_robin::RoundRobin;
+pub use weighted::WeightedRoundRobin;
+
+/// Information about the connection a backend is being chosen for.
+#[derive(Debug, Clone, Default)]
+pub struct SelectContext {
+    pub client_addr: Option<SocketAddr>,
+    /// Request headers, when the proxy understands the protocol. Empty for
+    /// plain TCP connections.
+    pub headers: Vec<(String, String)>,
+    /// Addresses of backends that must not be chosen, such as ones that
+    /// already failed to accept this connection.
+    pub excluded: Vec<String>,
+}
+
+impl SelectContext {
+    pub fn for_client(client_addr: SocketAddr) -> Self {
+        SelectContext {
+            client_addr: Some(client_addr),
+            ..SelectContext::default()
+        }
+    }
+
+    /// Whether `backend` may be chosen for this connection: it has to be
+    /// available and not excluded.
+    pub fn is_eligible(&self, backend: &Backend) -> bool {
+        backend.is_available() && !self.excluded.iter().any(|a| a == backend.address())
+    }
+
+    /// Looks up a header by case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Looks up a cookie by name in the `Cookie` header.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("cookie"))
+            .flat_map(|(_, v)| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| v)
+    }
+}
+
+/// What the hash-based strategies hash to pick a backend.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum HashKey {
+    /// The client's IP address, ignoring the port.
+    #[default]
+    ClientIp,
+    /// The value of a request header. Falls back to the client IP when the
+    /// header is missing or the connection is not parsed as HTTP.
+    Header(String),
+    /// The value of a request cookie, with the same fallback as `Header`.
+    Cookie(String),
+}
+
+impl HashKey {
+    pub(crate) fn extract(&self, ctx: &SelectContext) -> Vec<u8> {
+        let value = match self {
+            HashKey::ClientIp => None,
+            HashKey::Header(name) => ctx.header(name),
+            HashKey::Cookie(name) => ctx.cookie(name),
+        };
+        if let Some(value) = value {
+            return value.as_bytes().to_vec();
+        }
+        match ctx.client_addr.map(|addr| addr.ip()) {
+            Some(IpAddr::V4(ip)) => ip.octets().to_vec(),
+            Some(IpAddr::V6(ip)) => ip.octets().to_vec(),
+            None => Vec::new(),
+        }
+    }
+}
+
+pub trait BalancingStrategy: Send {
+    /// Short identifier used in logs and configuration.
+    fn name(&self) -> &'static str;
+
+    /// Returns the index into `backends` of the backend that should receive
+    /// the next connection, or `None` if no backend is available.
+    fn select(&mut self, backends: &[Arc<Backend>], ctx: &SelectContext) -> Option<usize>;
+
+    /// Called whenever the backend list held by the load balancer changes.
+    fn backends_changed(&mut self, _backends: &[Arc<Backend>]) {}
+}
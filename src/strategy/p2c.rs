@@ -0,0 +1,176 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::Arc,
+};
+
+use super::{BalancingStrategy, SelectContext};
+use crate::Backend;
+
+/// How many random probes are spent looking for an available backend before
+/// falling back to a scan of the whole pool.
+const MAX_PROBES: usize = 8;
+
+/// Power-of-two-choices: samples two distinct available backends at random
+/// and keeps the one with fewer in-flight connections relative to its
+/// weight. This gets close to least-connections without looking at every
+/// backend, which matters for large pools.
+#[derive(Debug)]
+pub struct PowerOfTwoChoices {
+    rng: XorShift64,
+}
+
+impl PowerOfTwoChoices {
+    pub fn new() -> Self {
+        let seed = RandomState::new().build_hasher().finish();
+        Self::with_seed(seed)
+    }
+
+    /// Creates the strategy with a fixed random seed, for reproducible runs.
+    pub fn with_seed(seed: u64) -> Self {
+        PowerOfTwoChoices {
+            rng: XorShift64::new(seed),
+        }
+    }
+
+    fn random_available(
+        &mut self,
+        backends: &[Arc<Backend>],
+        ctx: &SelectContext,
+        skip: Option<usize>,
+    ) -> Option<usize> {
+        let len = backends.len();
+        for _ in 0..MAX_PROBES {
+            let index = self.rng.below(len);
+            if Some(index) != skip && is_candidate(&backends[index], ctx) {
+                return Some(index);
+            }
+        }
+
+        // Most of the pool is unavailable; scan from a random starting point.
+        let start = self.rng.below(len);
+        (0..len)
+            .map(|i| (start + i) % len)
+            .find(|&index| Some(index) != skip && is_candidate(&backends[index], ctx))
+    }
+}
+
+impl Default for PowerOfTwoChoices {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BalancingStrategy for PowerOfTwoChoices {
+    fn name(&self) -> &'static str {
+        "power_of_two_choices"
+    }
+
+    fn select(&mut self, backends: &[Arc<Backend>], ctx: &SelectContext) -> Option<usize> {
+        if backends.is_empty() {
+            return None;
+        }
+        let first = self.random_available(backends, ctx, None)?;
+        let second = match self.random_available(backends, ctx, Some(first)) {
+            Some(second) => second,
+            None => return Some(first),
+        };
+
+        let (a, b) = (&backends[first], &backends[second]);
+        let load_a = a.active_connections() as u64 * u64::from(b.weight());
+        let load_b = b.active_connections() as u64 * u64::from(a.weight());
+        if load_b < load_a {
+            Some(second)
+        } else {
+            Some(first)
+        }
+    }
+}
+
+fn is_candidate(backend: &Backend, ctx: &SelectContext) -> bool {
+    backend.weight() > 0 && ctx.is_eligible(backend)
+}
+
+#[derive(Debug)]
+struct XorShift64 {
+    state: u64,
+}
+
+impl XorShift64 {
+    fn new(seed: u64) -> Self {
+        // A zero state would make xorshift return zero forever.
+        XorShift64 { state: seed | 1 }
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(n: usize) -> Vec<Arc<Backend>> {
+        (0..n)
+            .map(|i| Arc::new(Backend::new(format!("backend-{}", i))))
+            .collect()
+    }
+
+    #[test]
+    fn test_p2c_prefers_less_loaded_of_two() {
+        let backends = pool(2);
+        let _busy: Vec<_> = (0..5).map(|_| backends[0].acquire()).collect();
+        let mut p2c = PowerOfTwoChoices::with_seed(42);
+        let ctx = SelectContext::default();
+
+        for _ in 0..20 {
+            assert_eq!(p2c.select(&backends, &ctx), Some(1));
+        }
+    }
+
+    #[test]
+    fn test_p2c_balances_large_pool() {
+        let backends = pool(200);
+        backends[7].set_healthy(false);
+        let mut p2c = PowerOfTwoChoices::with_seed(7);
+        let ctx = SelectContext::default();
+
+        let mut guards = Vec::new();
+        for _ in 0..2000 {
+            let index = p2c.select(&backends, &ctx).unwrap();
+            assert_ne!(index, 7);
+            guards.push(backends[index].acquire());
+        }
+        let max = backends
+            .iter()
+            .map(|b| b.active_connections())
+            .max()
+            .unwrap();
+        // Pure random placement of 2000 connections over 199 backends
+        // routinely reaches 20+; two choices keeps the maximum tight.
+        assert!(max <= 14, "max load {}", max);
+    }
+
+    #[test]
+    fn test_p2c_single_available_backend() {
+        let backends = pool(50);
+        for backend in backends.iter().skip(1) {
+            backend.set_healthy(false);
+        }
+        let mut p2c = PowerOfTwoChoices::with_seed(3);
+        assert_eq!(p2c.select(&backends, &SelectContext::default()), Some(0));
+
+        backends[0].set_healthy(false);
+        assert_eq!(p2c.select(&backends, &SelectContext::default()), None);
+    }
+}
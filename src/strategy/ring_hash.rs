@@ -0,0 +1,216 @@
+use std::sync::Arc;
+
+use super::{hash::hash64, BalancingStrategy, HashKey, SelectContext};
+use crate::Backend;
+
+/// Points placed on the ring for each unit of backend weight, as in ketama.
+pub const DEFAULT_POINTS_PER_WEIGHT: u32 = 160;
+
+/// Most points on the ring. Heavier pools get proportionally fewer points
+/// per unit of weight so that building the ring stays cheap.
+pub const MAX_RING_POINTS: u64 = 1 << 20;
+
+/// Ketama-style consistent hashing.
+///
+/// Every backend is placed on a 64-bit ring at `weight * points_per_weight`
+/// pseudo-random points, scaled down to fit [`MAX_RING_POINTS`]. A
+/// connection is hashed onto the ring and served by the first backend found
+/// clockwise from it, so adding or removing a backend only moves the keys
+/// that land next to its points. Unavailable
+/// backends are stepped over without rebuilding the ring, so their keys move
+/// to the neighbour and come back once they recover.
+#[derive(Debug)]
+pub struct ConsistentHash {
+    key: HashKey,
+    points_per_weight: u32,
+    ring: Vec<(u64, usize)>,
+    // Weight of each backend when the ring was built.
+    built_weights: Vec<u32>,
+}
+
+impl ConsistentHash {
+    pub fn new(key: HashKey) -> Self {
+        Self::with_points_per_weight(key, DEFAULT_POINTS_PER_WEIGHT)
+    }
+
+    pub fn with_points_per_weight(key: HashKey, points_per_weight: u32) -> Self {
+        ConsistentHash {
+            key,
+            points_per_weight,
+            ring: Vec::new(),
+            built_weights: Vec::new(),
+        }
+    }
+
+    fn build(&mut self, backends: &[Arc<Backend>]) {
+        self.ring.clear();
+        self.built_weights = backends.iter().map(|b| b.weight()).collect();
+        let wanted = |backend: &Arc<Backend>| {
+            u64::from(backend.weight()) * u64::from(self.points_per_weight)
+        };
+        let total: u128 = backends.iter().map(|b| u128::from(wanted(b))).sum();
+        for (index, backend) in backends.iter().enumerate() {
+            let mut points = wanted(backend);
+            if total > u128::from(MAX_RING_POINTS) && points > 0 {
+                let scaled = u128::from(points) * u128::from(MAX_RING_POINTS) / total;
+                points = (scaled as u64).max(1);
+            }
+            for point in 0..points {
+                let label = format!("{}-{}", backend.address(), point);
+                self.ring.push((hash64(label.as_bytes()), index));
+            }
+        }
+        // Sorting by address as well keeps placement independent of the
+        // order backends were listed in if two points ever collide.
+        self.ring.sort_by(|(ha, ia), (hb, ib)| {
+            ha.cmp(hb)
+                .then_with(|| backends[*ia].address().cmp(backends[*ib].address()))
+        });
+    }
+}
+
+impl BalancingStrategy for ConsistentHash {
+    fn name(&self) -> &'static str {
+        "consistent_hash"
+    }
+
+    fn select(&mut self, backends: &[Arc<Backend>], ctx: &SelectContext) -> Option<usize> {
+        if self.built_weights.len() != backends.len() {
+            self.build(backends);
+        }
+        if self.ring.is_empty() {
+            return None;
+        }
+
+        let hash = hash64(&self.key.extract(ctx));
+        let start = self.ring.partition_point(|(point, _)| *point < hash);
+        (0..self.ring.len())
+            .map(|i| self.ring[(start + i) % self.ring.len()].1)
+            .find(|&index| ctx.is_eligible(&backends[index]))
+    }
+
+    fn backends_changed(&mut self, backends: &[Arc<Backend>]) {
+        self.build(backends);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn pool(n: usize) -> Vec<Arc<Backend>> {
+        (0..n)
+            .map(|i| Arc::new(Backend::new(format!("10.0.0.{}:80", i))))
+            .collect()
+    }
+
+    fn client(i: u32) -> SelectContext {
+        let ip = std::net::Ipv4Addr::from(0xc0a8_0000 + i);
+        SelectContext::for_client(SocketAddr::from((ip, 40000)))
+    }
+
+    fn assignments(strategy: &mut ConsistentHash, backends: &[Arc<Backend>]) -> Vec<String> {
+        (0..2000)
+            .map(|i| {
+                let index = strategy.select(backends, &client(i)).unwrap();
+                backends[index].address().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_consistent_hash_is_sticky_per_client_ip() {
+        let backends = pool(5);
+        let mut ring = ConsistentHash::new(HashKey::ClientIp);
+        ring.backends_changed(&backends);
+
+        let ip = std::net::Ipv4Addr::new(192, 168, 1, 20);
+        let first = ring.select(
+            &backends,
+            &SelectContext::for_client(SocketAddr::from((ip, 1000))),
+        );
+        let second = ring.select(
+            &backends,
+            &SelectContext::for_client(SocketAddr::from((ip, 2000))),
+        );
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn test_consistent_hash_ring_size_is_capped() {
+        let backends = vec![
+            Arc::new(Backend::with_weight("heavy", u32::MAX)),
+            Arc::new(Backend::with_weight("light", 1)),
+            Arc::new(Backend::new("plain")),
+        ];
+        let mut ring = ConsistentHash::new(HashKey::ClientIp);
+        ring.backends_changed(&backends);
+
+        assert!(ring.ring.len() as u64 <= MAX_RING_POINTS + backends.len() as u64);
+        for index in 0..backends.len() {
+            assert!(ring.ring.iter().any(|&(_, i)| i == index));
+        }
+    }
+
+    #[test]
+    fn test_consistent_hash_minimal_remapping() {
+        let mut backends = pool(5);
+        let mut ring = ConsistentHash::new(HashKey::ClientIp);
+        ring.backends_changed(&backends);
+        let before = assignments(&mut ring, &backends);
+
+        backends.push(Arc::new(Backend::new("10.0.0.99:80")));
+        ring.backends_changed(&backends);
+        let after = assignments(&mut ring, &backends);
+
+        // Only keys that moved to the new backend may change owner.
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(b, a)| b != a).collect();
+        assert!(moved.iter().all(|(_, a)| a.as_str() == "10.0.0.99:80"));
+        // Ideal is 1/6 of the keys.
+        assert!(moved.len() < 2000 * 25 / 100, "{} keys moved", moved.len());
+    }
+
+    #[test]
+    fn test_consistent_hash_skips_unavailable_and_returns() {
+        let backends = pool(4);
+        let mut ring = ConsistentHash::new(HashKey::ClientIp);
+        ring.backends_changed(&backends);
+        let before = assignments(&mut ring, &backends);
+
+        backends[2].set_healthy(false);
+        let during = assignments(&mut ring, &backends);
+        for (b, d) in before.iter().zip(&during) {
+            assert_ne!(d, backends[2].address());
+            if b != backends[2].address() {
+                assert_eq!(b, d);
+            }
+        }
+
+        backends[2].set_healthy(true);
+        assert_eq!(assignments(&mut ring, &backends), before);
+    }
+
+    #[test]
+    fn test_consistent_hash_header_key_falls_back_to_client_ip() {
+        let backends = pool(8);
+        let mut by_header = ConsistentHash::new(HashKey::Header("X-User".to_string()));
+        let mut by_ip = ConsistentHash::new(HashKey::ClientIp);
+
+        let ctx = client(42);
+        assert_eq!(
+            by_header.select(&backends, &ctx),
+            by_ip.select(&backends, &ctx)
+        );
+
+        let mut ctx = client(42);
+        ctx.headers
+            .push(("x-user".to_string(), "alice".to_string()));
+        let alice = by_header.select(&backends, &ctx);
+        let mut other = client(7);
+        other
+            .headers
+            .push(("X-User".to_string(), "alice".to_string()));
+        assert_eq!(by_header.select(&backends, &other), alice);
+    }
+}
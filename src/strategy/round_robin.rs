@@ -0,0 +1,65 @@
+use std::sync::Arc;
+
+use super::{BalancingStrategy, SelectContext};
+use crate::Backend;
+
+/// Hands out backends in order, skipping any that are unavailable.
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    current: usize,
+}
+
+impl RoundRobin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BalancingStrategy for RoundRobin {
+    fn name(&self) -> &'static str {
+        "round_robin"
+    }
+
+    fn select(&mut self, backends: &[Arc<Backend>], ctx: &SelectContext) -> Option<usize> {
+        let len = backends.len();
+        for offset in 0..len {
+            let index = (self.current + offset) % len;
+            if ctx.is_eligible(&backends[index]) {
+                self.current = (index + 1) % len;
+                return Some(index);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_robin_skips_unavailable() {
+        let backends: Vec<Arc<Backend>> = ["a", "b", "c"]
+            .iter()
+            .map(|a| Arc::new(Backend::new(*a)))
+            .collect();
+        backends[1].set_healthy(false);
+
+        let mut rr = RoundRobin::new();
+        let ctx = SelectContext::default();
+        assert_eq!(rr.select(&backends, &ctx), Some(0));
+        assert_eq!(rr.select(&backends, &ctx), Some(2));
+        assert_eq!(rr.select(&backends, &ctx), Some(0));
+
+        let ctx = SelectContext {
+            excluded: vec!["a".to_string()],
+            ..SelectContext::default()
+        };
+        assert_eq!(rr.select(&backends, &ctx), Some(2));
+        assert_eq!(rr.select(&backends, &ctx), Some(2));
+
+        backends[0].set_healthy(false);
+        backends[2].set_healthy(false);
+        assert_eq!(rr.select(&backends, &ctx), None);
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::Arc;
+
+use super::{BalancingStrategy, SelectContext};
+use crate::Backend;
+
+/// Smooth weighted round-robin, as used by nginx.
+///
+/// Every pick adds each backend's weight to its running score, chooses the
+/// backend with the highest score and subtracts the total weight from it.
+/// A 5:1:1 pool therefore yields `a a b a c a a` rather than five `a`s in a
+/// row. Weights are read on every pick, so runtime changes apply at once.
+/// Backends with a weight of zero are never chosen.
+#[derive(Debug, Default)]
+pub struct WeightedRoundRobin {
+    current_weights: Vec<i64>,
+}
+
+impl WeightedRoundRobin {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl BalancingStrategy for WeightedRoundRobin {
+    fn name(&self) -> &'static str {
+        "weighted_round_robin"
+    }
+
+    fn select(&mut self, backends: &[Arc<Backend>], ctx: &SelectContext) -> Option<usize> {
+        if self.current_weights.len() != backends.len() {
+            self.backends_changed(backends);
+        }
+
+        let mut total = 0i64;
+        let mut best: Option<usize> = None;
+        for (index, backend) in backends.iter().enumerate() {
+            let weight = i64::from(backend.weight());
+            if weight == 0 || !ctx.is_eligible(backend) {
+                continue;
+            }
+            self.current_weights[index] += weight;
+            total += weight;
+            if best.is_none_or(|b| self.current_weights[index] > self.current_weights[b]) {
+                best = Some(index);
+            }
+        }
+
+        let best = best?;
+        self.current_weights[best] -= total;
+        Some(best)
+    }
+
+    fn backends_changed(&mut self, backends: &[Arc<Backend>]) {
+        self.current_weights = vec![0; backends.len()];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(weights: &[u32]) -> Vec<Arc<Backend>> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, w)| Arc::new(Backend::with_weight(format!("backend-{}", i), *w)))
+            .collect()
+    }
+
+    fn picks(wrr: &mut WeightedRoundRobin, backends: &[Arc<Backend>], n: usize) -> Vec<usize> {
+        let ctx = SelectContext::default();
+        (0..n)
+            .map(|_| wrr.select(backends, &ctx).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_weighted_round_robin_interleaves() {
+        let backends = pool(&[5, 1, 1]);
+        let mut wrr = WeightedRoundRobin::new();
+
+        assert_eq!(picks(&mut wrr, &backends, 7), vec![0, 0, 1, 0, 2, 0, 0]);
+        assert_eq!(picks(&mut wrr, &backends, 7), vec![0, 0, 1, 0, 2, 0, 0]);
+    }
+
+    #[test]
+    fn test_weighted_round_robin_runtime_weight_change() {
+        let backends = pool(&[1, 1]);
+        let mut wrr = WeightedRoundRobin::new();
+        assert_eq!(picks(&mut wrr, &backends, 4), vec![0, 1, 0, 1]);
+
+        backends[1].set_weight(0);
+        assert_eq!(picks(&mut wrr, &backends, 3), vec![0, 0, 0]);
+
+        backends[1].set_weight(3);
+        let counts = picks(&mut wrr, &backends, 400)
+            .into_iter()
+            .fold([0; 2], |mut acc, i| {
+                acc[i] += 1;
+                acc
+            });
+        assert!((295..=305).contains(&counts[1]), "{:?}", counts);
+    }
+}
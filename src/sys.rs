@@ -0,0 +1,191 @@
+//! Thin safe wrappers over the Linux system calls used by the event loop
+//! and the zero-copy data path.
+//!
+//! The crate has no dependencies, so the handful of libc bindings needed
+//! are declared by hand below. Constants are from the Linux UAPI headers.
+
+use std::{
+    fs::File,
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    ptr,
+    time::Duration,
+};
+
+/// Bytes a pipe holds by default (16 pages).
+pub(crate) const PIPE_CAPACITY: usize = 64 * 1024;
+
+pub(crate) use ffi::{epoll_event, EPOLLERR, EPOLLET, EPOLLHUP, EPOLLIN, EPOLLOUT, EPOLLRDHUP};
+
+pub(crate) struct Poller {
+    fd: OwnedFd,
+}
+
+impl Poller {
+    pub(crate) fn new() -> io::Result<Self> {
+        // SAFETY: epoll_create1 has no memory-safety preconditions.
+        let fd = cvt(unsafe { ffi::epoll_create1(ffi::EPOLL_CLOEXEC) })?;
+        // SAFETY: `fd` was just returned by the kernel and is owned by us.
+        Ok(Poller {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    pub(crate) fn add(&self, fd: RawFd, token: u64, events: u32) -> io::Result<()> {
+        let mut event = epoll_event { events, u64: token };
+        // SAFETY: `event` is a valid epoll_event for the duration of the call.
+        cvt(unsafe { ffi::epoll_ctl(self.fd.as_raw_fd(), ffi::EPOLL_CTL_ADD, fd, &mut event) })
+            .map(drop)
+    }
+
+    pub(crate) fn delete(&self, fd: RawFd) -> io::Result<()> {
+        let mut event = epoll_event { events: 0, u64: 0 };
+        // SAFETY: as in `add`; the event is ignored for deletions.
+        cvt(unsafe { ffi::epoll_ctl(self.fd.as_raw_fd(), ffi::EPOLL_CTL_DEL, fd, &mut event) })
+            .map(drop)
+    }
+
+    pub(crate) fn wait(&self, events: &mut [epoll_event], timeout: Duration) -> io::Result<usize> {
+        // SAFETY: `events` is valid for writes of `events.len()` entries.
+        let ready = unsafe {
+            ffi::epoll_wait(
+                self.fd.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.len() as i32,
+                timeout.as_millis() as i32,
+            )
+        };
+        match cvt(ready) {
+            Ok(n) => Ok(n as usize),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+pub(crate) fn eventfd() -> io::Result<File> {
+    // SAFETY: eventfd has no memory-safety preconditions.
+    let fd = cvt(unsafe { ffi::eventfd(0, ffi::EFD_CLOEXEC | ffi::EFD_NONBLOCK) })?;
+    // SAFETY: `fd` was just returned by the kernel and is owned by us.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// A kernel pipe used as the intermediate buffer for `splice(2)`.
+pub(crate) struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+    nonblocking: bool,
+}
+
+impl Pipe {
+    pub(crate) fn new(nonblocking: bool) -> io::Result<Self> {
+        let mut fds = [0; 2];
+        let mut flags = ffi::O_CLOEXEC;
+        if nonblocking {
+            flags |= ffi::O_NONBLOCK;
+        }
+        // SAFETY: `fds` has room for the two descriptors pipe2 writes.
+        cvt(unsafe { ffi::pipe2(fds.as_mut_ptr(), flags) })?;
+        // SAFETY: both descriptors were just returned by the kernel.
+        Ok(unsafe {
+            Pipe {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+                nonblocking,
+            }
+        })
+    }
+
+    /// Moves up to `len` bytes from `from` into the pipe. Returns 0 when
+    /// `from` has reached end of stream.
+    pub(crate) fn fill_from(&self, from: &impl AsRawFd, len: usize) -> io::Result<usize> {
+        splice(from.as_raw_fd(), self.write.as_raw_fd(), len, self.flags())
+    }
+
+    /// Moves up to `len` bytes from the pipe into `to`.
+    pub(crate) fn drain_to(&self, to: &impl AsRawFd, len: usize) -> io::Result<usize> {
+        splice(self.read.as_raw_fd(), to.as_raw_fd(), len, self.flags())
+    }
+
+    fn flags(&self) -> u32 {
+        let mut flags = ffi::SPLICE_F_MOVE;
+        if self.nonblocking {
+            flags |= ffi::SPLICE_F_NONBLOCK;
+        }
+        flags
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize, flags: u32) -> io::Result<usize> {
+    // SAFETY: null offsets are allowed for pipes and sockets, and the
+    // kernel only touches the two descriptors.
+    let n = unsafe { ffi::splice(from, ptr::null_mut(), to, ptr::null_mut(), len, flags) };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+fn cvt(result: i32) -> io::Result<i32> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_int, c_uint};
+
+    pub const EPOLL_CLOEXEC: c_int = 0o2_000_000;
+    pub const EPOLL_CTL_ADD: c_int = 1;
+    pub const EPOLL_CTL_DEL: c_int = 2;
+
+    pub const EPOLLIN: u32 = 0x001;
+    pub const EPOLLOUT: u32 = 0x004;
+    pub const EPOLLERR: u32 = 0x008;
+    pub const EPOLLHUP: u32 = 0x010;
+    pub const EPOLLRDHUP: u32 = 0x2000;
+    pub const EPOLLET: u32 = 1 << 31;
+
+    pub const EFD_CLOEXEC: c_int = 0o2_000_000;
+    pub const EFD_NONBLOCK: c_int = 0o4_000;
+
+    pub const O_CLOEXEC: c_int = 0o2_000_000;
+    pub const O_NONBLOCK: c_int = 0o4_000;
+
+    pub const SPLICE_F_MOVE: c_uint = 1;
+    pub const SPLICE_F_NONBLOCK: c_uint = 2;
+
+    // The kernel packs this struct on x86-64 only.
+    #[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+    #[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+    #[derive(Clone, Copy)]
+    pub struct epoll_event {
+        pub events: u32,
+        pub u64: u64,
+    }
+
+    extern "C" {
+        pub fn epoll_create1(flags: c_int) -> c_int;
+        pub fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut epoll_event) -> c_int;
+        pub fn epoll_wait(
+            epfd: c_int,
+            events: *mut epoll_event,
+            maxevents: c_int,
+            timeout: c_int,
+        ) -> c_int;
+        pub fn eventfd(initval: c_uint, flags: c_int) -> c_int;
+        pub fn pipe2(fds: *mut c_int, flags: c_int) -> c_int;
+        pub fn splice(
+            fd_in: c_int,
+            off_in: *mut i64,
+            fd_out: c_int,
+            off_out: *mut i64,
+            len: usize,
+            flags: c_uint,
+        ) -> isize;
+    }
+}